@@ -1,22 +1,26 @@
 use common_macros::hash_map;
-use handlebars::Handlebars;
+use handlebars::{to_json, Handlebars, JsonValue};
+use is_terminal::IsTerminal;
 use nonempty::NonEmpty;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    env::current_dir,
+    collections::{BTreeMap, HashMap, HashSet},
+    env::{self, current_dir},
     fmt::Display,
     fs::{self, create_dir_all, remove_dir_all},
-    io,
-    path::PathBuf,
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+    process::{Command, ExitCode, Stdio},
 };
 
 use anyhow::{anyhow, bail, Context};
-use clap::{Parser, ValueEnum};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use indoc::{formatdoc, writedoc};
 use itertools::Itertools;
-use rustyline::{error::ReadlineError, Editor};
+use rustyline::{error::ReadlineError, Behavior, Config, Editor};
 
-#[derive(ValueEnum, Clone, Copy, Debug)]
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
 enum ConflictBehavior {
     Overwrite,
     MergeKeep,
@@ -30,21 +34,152 @@ impl Default for ConflictBehavior {
     }
 }
 
-#[derive(Parser)]
-#[command(author, version, about)]
+/// Whether templates in your config directory are looked up before or
+/// after the builtin templates, i.e. which of them wins when they
+/// share a name.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum TemplateDirPrecedence {
+    #[default]
+    Before,
+    After,
+}
+
+/// How `--dry-run` prints its plan.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Whether to use colors in what's printed for people.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum ColorChoice {
+    /// Only when printing to a terminal.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// How the top-level `shell.nix` combines the templates' shells.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum CombineStrategy {
+    #[default]
+    Import,
+    Merge,
+}
+
+#[derive(Clone, Parser)]
+#[command(author, version, about, disable_help_subcommand = true)]
 struct Cli {
+    /// Do something other than setting up a directory.
+    #[command(subcommand)]
+    action: Option<Action>,
+
     /// The name of the template to use.
     ///
-    /// Inix uses a blank template if you don't specify one.
+    /// Inix uses a blank template if you don't specify one. To use a
+    /// template with the same name as a command (like "list"), pass it
+    /// with `--templates`.
+    ///
+    /// A template can also come straight from a git repository, as
+    /// `<url>#<name>`, e.g.
+    /// `https://github.com/org/templates.git#rust` for the "rust"
+    /// directory in that repository (see `--template-repo`).
     templates: Vec<String>,
 
+    /// A list of templates to use, separated by the template
+    /// separator (a comma by default), e.g. "rust,node".
+    ///
+    /// Useful when passing multiple arguments is awkward. These
+    /// templates are used in addition to any other templates you
+    /// provide.
+    #[arg(long = "templates", value_name = "LIST")]
+    template_list: Option<String>,
+
+    /// The character used to separate templates in the `--templates`
+    /// list.
+    #[arg(long, default_value_t = ',')]
+    template_separator: char,
+
+    /// Whether the templates in your config directory take precedence
+    /// over the builtin templates when they share a name.
+    ///
+    /// before: Use the template from your config directory if there is
+    /// one, and fall back to the builtin template.
+    ///
+    /// after: Use the builtin template if there is one, and fall back
+    /// to the template from your config directory.
+    ///
+    /// This applies to the base template too.
+    #[arg(long, value_enum, default_value_t)]
+    template_dir_precedence: TemplateDirPrecedence,
+
+    /// Another directory to look for templates in, before the ones in
+    /// the `INIX_TEMPLATE_PATH` environment variable (a list of
+    /// directories, separated like `PATH`) and your config directory.
+    /// Can be used more than once; directories listed earlier win
+    /// when they have templates with the same name.
+    ///
+    /// If the directory has a template's files (like `shell.nix`)
+    /// directly in it, it's a template itself, named after the
+    /// directory. Such templates are used if you don't ask for any
+    /// other templates.
+    ///
+    /// A `.inix/templates` directory in the target directory, or in
+    /// the closest of its parents that has one, is looked in before
+    /// all of these, so that a repository can bring its own templates.
+    #[arg(long = "template-dir", value_name = "DIR")]
+    template_dirs: Vec<PathBuf>,
+
+    /// A git repository to look for templates in, like a
+    /// `--template-dir`: every directory at the top of the repository
+    /// is a template. Can be used more than once.
+    ///
+    /// The repository is cloned into your cache directory the first
+    /// time it's used, and the clone is reused after that (see
+    /// `--refresh-templates`). Listing templates and dry runs only use
+    /// a clone that's already there. Repositories come after the
+    /// `--template-dir` directories, but before everything else.
+    #[arg(long = "template-repo", value_name = "URL")]
+    template_repos: Vec<String>,
+
+    /// Update the clones of the `--template-repo` repositories before
+    /// using them.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    refresh_templates: bool,
+
     /// The directory to initialize.
     ///
     /// If the directory does not already exist, then inix will try to create it.
     /// Defaults to your current directory if not provided.
-    #[arg(short, long)]
+    ///
+    /// `-C` works too, for those used to git. Unlike with git, it only
+    /// says which directory to initialize: relative paths given to
+    /// other options, like `--template-dir`, are still relative to
+    /// your current directory. There's no separate `--root` option
+    /// either: this directory is also where inix starts looking for a
+    /// project's `.inix/templates` directory.
+    #[arg(short, long, visible_alias = "dir", visible_short_alias = 'C')]
     directory: Option<PathBuf>,
 
+    /// Set up every directory in the target directory instead of the
+    /// target directory itself, one after the other, with the same
+    /// templates and options. Hidden directories are skipped.
+    ///
+    /// Useful for giving every project in a monorepo the same setup.
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with_all = ["list", "save_as", "print_template_path"])]
+    each_subdir: bool,
+
+    /// Refuse to use the target directory if it already contains
+    /// anything (other than a `.git` directory).
+    ///
+    /// By default, inix creates the directory if it doesn't exist and
+    /// reuses it if it does.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    require_empty_dir: bool,
+
     /// Print a summary of what would be done, but don't do anything.
     #[arg(short = 'n', long, action = clap::ArgAction::SetTrue)]
     dry_run: bool,
@@ -57,6 +192,12 @@ struct Cli {
     #[arg(short, long, action = clap::ArgAction::SetTrue)]
     auto_allow: bool,
 
+    /// If `direnv allow` fails with `--auto-allow`, undo everything
+    /// that this run did: remove what it created and put back what it
+    /// replaced. Nothing that was there before is removed.
+    #[arg(long, action = clap::ArgAction::SetTrue, requires = "auto_allow")]
+    rollback_on_allow_failure: bool,
+
     /// What to do in case of a pre-existing inix directory where you
     /// are trying to create one. If no value is provided, inix will
     /// prompt you if there is a conflict.
@@ -72,27 +213,561 @@ struct Cli {
     /// remove the old templates and add the new ones.
     ///
     /// cancel: Stop the process without writing any files.
+    ///
+    /// The top-level `shell.nix`, `.envrc`, and `flake.nix` files are
+    /// handled similarly: they are replaced with overwrite and
+    /// merge-replace, and left alone if they already exist with
+    /// cancel. With merge-keep, existing files are moved to the next
+    /// free backup generation (`shell.nix.1`, `shell.nix.2`, ...)
+    /// before the new ones are written.
     #[arg(long, value_enum)]
     on_conflict: Option<ConflictBehavior>,
+
+    /// Never prompt for anything. If there's a conflict with an
+    /// existing inix directory and you haven't said what to do about
+    /// it with `--on-conflict`, or if a template needs a variable that
+    /// you haven't set, inix fails instead of asking you.
+    ///
+    /// Use this in CI and other scripts.
+    #[arg(long, visible_alias = "no-input", action = clap::ArgAction::SetTrue)]
+    non_interactive: bool,
+
+    /// Don't take the lock (an `.inix.lock` file in the target
+    /// directory) that stops other inix runs from writing to the same
+    /// directory at the same time.
+    ///
+    /// If a run was killed before it could clean up after itself, the
+    /// lock file is left behind: delete it, or use this.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    no_lock: bool,
+
+    /// Also generate a `flake.nix` whose default dev shell combines
+    /// the shells of all the templates you've chosen.
+    ///
+    /// The generated `.envrc` will `use flake` instead of `use nix`.
+    ///
+    /// This happens automatically if any of the templates you've
+    /// chosen has a `flake.nix`.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    emit_flake: bool,
+
+    /// Set the project up with just a flake: a `flake.nix` whose
+    /// default dev shell combines the templates' shells, and an
+    /// `.envrc` that does `use flake`, but no top-level `shell.nix`.
+    ///
+    /// Unlike `--emit-flake`, which adds the `flake.nix` next to the
+    /// `shell.nix`.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    flake: bool,
+
+    /// Don't make the top-level `shell.nix` (or `flake.nix`) import
+    /// the templates' shells.
+    ///
+    /// The templates are still written to the inix directory, and the
+    /// top-level files are still created, but they only contain an
+    /// empty shell for you to fill in. Use this if you want to pick
+    /// which of the templates' shells to use yourself.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    no_top_level_import: bool,
+
+    /// How the top-level `shell.nix` combines the templates' shells.
+    ///
+    /// import: Import each template's `shell.nix` and combine their
+    /// inputs and packages.
+    ///
+    /// merge: Copy the packages listed in each template's `shell.nix`
+    /// into a single shell. This only works for templates that list
+    /// their packages directly (e.g. `buildInputs = [ pkgs.nodejs ];`)
+    /// without relying on anything else they define.
+    #[arg(long, value_enum, default_value_t)]
+    combine_strategy: CombineStrategy,
+
+    /// Create the inix directory even if you haven't chosen any
+    /// templates.
+    ///
+    /// The directory will only contain an empty `.gitkeep` file. An
+    /// existing inix directory is left as it is.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    allow_empty: bool,
+
+    /// After creating the files, check that the environment works by
+    /// running `nix-shell --run true` (or `nix develop --command
+    /// true` with `--emit-flake`).
+    ///
+    /// The generated files are left in place even if the check fails.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    smoke_test: bool,
+
+    /// After creating the files, run `git init` in the directory,
+    /// unless it already has a `.git` directory.
+    ///
+    /// If that fails (e.g. because git isn't installed), inix warns
+    /// about it, but keeps the files it has written.
+    #[arg(long, visible_alias = "git", action = clap::ArgAction::SetTrue)]
+    git_init: bool,
+
+    /// Make the generated `.envrc` check that direnv is at least this
+    /// version (e.g. "2.21.0") before doing anything else.
+    ///
+    /// Useful if your setup relies on features that older versions of
+    /// direnv don't have, such as `use flake`.
+    #[arg(long, value_name = "VERSION", value_parser = parse_version)]
+    envrc_min_direnv: Option<String>,
+
+    /// Make the generated `.envrc` load a shared direnv library first
+    /// (right after the direnv version check, if any): `source_env`
+    /// for a path, or `source_url` for an http(s) URL.
+    ///
+    /// URLs need their integrity hash too (see
+    /// `--envrc-prelude-hash`).
+    #[arg(long, value_name = "PATH_OR_URL")]
+    envrc_prelude: Option<String>,
+
+    /// The integrity hash of the `--envrc-prelude` URL, as printed by
+    /// `direnv fetchurl <url>` (e.g. "sha256-...").
+    #[arg(long, value_name = "HASH", requires = "envrc_prelude")]
+    envrc_prelude_hash: Option<String>,
+
+    /// Don't use a pager for long output.
+    ///
+    /// By default, output that doesn't fit in your terminal is shown
+    /// with your `$PAGER` (or `less -FRX` if that isn't set).
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    no_pager: bool,
+
+    /// Print the directory that the template with this name would be
+    /// read from and exit without doing anything else.
+    ///
+    /// Builtin templates don't have a directory, so "<builtin>" is
+    /// printed for them instead.
+    #[arg(long, value_name = "NAME")]
+    print_template_path: Option<String>,
+
+    /// Make a variable available when rendering custom templates and
+    /// the top-level `shell.nix`, `.envrc`, and `flake.nix`, e.g.
+    /// `--set project_name=inix`. Can be used more than once.
+    ///
+    /// The names that inix uses itself (such as `templates`) can't be
+    /// set.
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_variable)]
+    variables: Vec<(String, String)>,
+
+    /// Like `--set`, but the value is the output of a shell command,
+    /// e.g. `--var-from-command branch='git branch --show-current'`.
+    /// Can be used more than once.
+    ///
+    /// The command is run in the directory you're initializing (if it
+    /// exists), and leading and trailing whitespace is removed from
+    /// its output.
+    #[arg(
+        long = "var-from-command",
+        value_name = "KEY=COMMAND",
+        value_parser = parse_variable
+    )]
+    variable_commands: Vec<(String, String)>,
+
+    /// Don't render any templates with Handlebars: copy all files
+    /// exactly as they are, including the top-level `shell.nix`,
+    /// `.envrc`, and `flake.nix` templates.
+    ///
+    /// Use this if your templates contain `{{` for other reasons.
+    /// Variables (from `--set` and `--var-from-command`) and the list
+    /// of templates are not filled in.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    no_render: bool,
+
+    /// Fail if a template uses a variable that isn't set, instead of
+    /// leaving it empty. Use this to catch typos like
+    /// `{{projeect_name}}`.
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with = "no_render")]
+    strict_vars: bool,
+
+    /// Only print the main message when something goes wrong, without
+    /// the underlying causes.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    quiet_errors: bool,
+
+    /// Only print errors and warnings: no summary of the files that
+    /// were written and no progress messages. As there's nobody
+    /// listening, I won't ask what to do about conflicts either: use
+    /// `--on-conflict` for that.
+    #[arg(short, long, action = clap::ArgAction::SetTrue)]
+    quiet: bool,
+
+    /// Print every file that is written, with its full path, and what
+    /// happened to it (created, overwritten, updated, or backed up).
+    #[arg(short, long, action = clap::ArgAction::SetTrue, conflicts_with_all = ["quiet", "print_created"])]
+    verbose: bool,
+
+    /// Instead of the summary, print the path of every file that was
+    /// created or updated, one per line, and nothing else. Useful for
+    /// passing them on to other programs.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    print_created: bool,
+
+    /// With `--print-created`: end each path with a NUL character
+    /// instead of a newline, like `find -print0` (for `xargs -0`).
+    #[arg(short = '0', long, action = clap::ArgAction::SetTrue, requires = "print_created")]
+    null: bool,
+
+    /// How to print the plan for `--dry-run`.
+    ///
+    /// text: A description of each step, for people.
+    ///
+    /// json: The target directory, the state of the inix directory,
+    /// the conflict behavior, the templates, and the files that would
+    /// be written, for tools.
+    #[arg(long, value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// When to highlight template names, conflict behaviors, and
+    /// warnings in what I print. `auto` only does it when printing to
+    /// a terminal. Setting `NO_COLOR` turns colors off regardless.
+    #[arg(long, value_enum, default_value_t)]
+    color: ColorChoice,
+
+    /// Print what I decided for each template as a JSON array (to
+    /// stderr), once they've been looked up: its name, whether it was
+    /// resolved, shadowed (by a template with the same name that's
+    /// used instead), missing, or skipped (because it couldn't be
+    /// read), where it comes from, and why.
+    ///
+    /// This is also done when using `--format json`.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    log_decisions: bool,
+
+    /// With `--dry-run`: instead of printing the plan, show how the
+    /// plans for two conflict behaviors differ, e.g.
+    /// `--compare-behaviors merge-keep merge-replace`.
+    #[arg(
+        long,
+        value_enum,
+        num_args = 2,
+        value_names = ["A", "B"],
+        requires = "dry_run"
+    )]
+    compare_behaviors: Vec<ConflictBehavior>,
+
+    /// List all the templates you can use and exit without doing
+    /// anything else.
+    ///
+    /// Templates from your config directory are listed along with the
+    /// builtin ones, with the files they provide. If both have a
+    /// template with the same name, the one that isn't used is marked
+    /// as shadowed. `inix list` does the same.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    list: bool,
+
+    /// Save the `shell.nix` and `.envrc` in the directory as a custom
+    /// template with this name (in your config directory) and exit
+    /// without doing anything else.
+    ///
+    /// If there already is a custom template with that name, inix
+    /// won't change it unless you say how with `--on-conflict`:
+    /// overwrite replaces the whole template, merge-keep only adds
+    /// the files it doesn't have, and merge-replace replaces the
+    /// files it has.
+    #[arg(long, value_name = "NAME")]
+    save_as: Option<String>,
+
+    /// Print a completion script for the given shell and exit without
+    /// doing anything else.
+    #[arg(long, value_name = "SHELL", hide = true)]
+    completions: Option<clap_complete::Shell>,
+
+    /// Install a completion script for the given shell (bash, zsh, or
+    /// fish) that also completes the names of your templates, whatever
+    /// they are at the time, and exit without doing anything else.
+    ///
+    /// The script is written to your user completion directory for the
+    /// shell, or to `--completions-dir`.
+    #[arg(long, value_name = "SHELL")]
+    completions_dynamic: Option<clap_complete::Shell>,
+
+    /// With `--completions-dynamic`: the directory to write the
+    /// completion script to.
+    #[arg(long, value_name = "DIR", requires = "completions_dynamic")]
+    completions_dir: Option<PathBuf>,
+
+    /// Print the names of all the templates, one per line, and exit.
+    /// Used by the scripts from `--completions-dynamic`.
+    #[arg(long, action = clap::ArgAction::SetTrue, hide = true)]
+    list_template_names: bool,
+
+    /// With `inix init-config`: write the sample files even if the
+    /// template directory already exists. Files with the same names
+    /// as the sample files are replaced; nothing else is touched.
+    ///
+    /// With `--on-conflict overwrite`: remove the inix directory even
+    /// if it has files in it that don't belong to any template.
+    ///
+    /// Otherwise: write templates into an existing inix directory even
+    /// if it doesn't look like inix has ever used it.
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    force: bool,
+}
+
+/// The commands that inix has besides setting up a directory. Any
+/// options for setting up a directory that they don't need are
+/// ignored.
+#[derive(Clone, Debug, PartialEq, Eq, Subcommand)]
+enum Action {
+    /// List all the templates you can use, with the files they provide
+    /// and where they come from (like `--list`).
+    List,
+    /// Set up the directory that your own templates go in, with a
+    /// sample template to start from.
+    ///
+    /// If the directory already exists, inix won't touch it unless you
+    /// also pass `--force`.
+    InitConfig,
+    /// Print the files of a template (before any variables are filled
+    /// in) and where it comes from, without setting anything up.
+    Show {
+        /// The name of the template to show.
+        template: String,
+    },
+    /// Remove templates from the inix directory of a project that's
+    /// already set up, and update the top-level `shell.nix` and
+    /// `.envrc` (and `flake.nix`, if there is one) to match.
+    Remove {
+        /// The names of the templates to remove.
+        #[arg(required = true)]
+        templates: Vec<String>,
+    },
+    /// Copy the templates in the inix directory of a project that's
+    /// already set up again from where they come from, and render the
+    /// top-level files again, to pick up changes to the templates.
+    ///
+    /// Files in the templates are replaced unless you say otherwise
+    /// with `--on-conflict`.
+    Update {
+        /// The names of the templates to update. All of them, if you
+        /// don't give any.
+        templates: Vec<String>,
+    },
+    /// Look for templates in the registry (set with `registry` in
+    /// `<your user configuration directory>/inix/config.toml`) whose
+    /// name or description contains a keyword. The templates it lists
+    /// can be used by name, as long as there isn't another template
+    /// with the same name.
+    Search {
+        /// What to look for. Case doesn't matter.
+        keyword: String,
+    },
 }
 
 impl Default for Cli {
     fn default() -> Self {
         Self {
+            action: Default::default(),
             templates: Default::default(),
+            template_list: Default::default(),
+            template_separator: ',',
+            template_dir_precedence: Default::default(),
+            template_dirs: Default::default(),
+            template_repos: Default::default(),
+            refresh_templates: Default::default(),
             directory: Default::default(),
+            each_subdir: Default::default(),
+            require_empty_dir: Default::default(),
             dry_run: Default::default(),
             auto_allow: Default::default(),
+            rollback_on_allow_failure: Default::default(),
             on_conflict: Default::default(),
+            non_interactive: Default::default(),
+            no_lock: Default::default(),
+            emit_flake: Default::default(),
+            flake: Default::default(),
+            no_top_level_import: Default::default(),
+            combine_strategy: Default::default(),
+            allow_empty: Default::default(),
+            smoke_test: Default::default(),
+            git_init: Default::default(),
+            envrc_min_direnv: Default::default(),
+            envrc_prelude: Default::default(),
+            envrc_prelude_hash: Default::default(),
+            no_pager: Default::default(),
+            print_template_path: Default::default(),
+            variables: Default::default(),
+            variable_commands: Default::default(),
+            no_render: Default::default(),
+            strict_vars: Default::default(),
+            quiet_errors: Default::default(),
+            quiet: Default::default(),
+            verbose: Default::default(),
+            print_created: Default::default(),
+            null: Default::default(),
+            format: Default::default(),
+            color: Default::default(),
+            log_decisions: Default::default(),
+            compare_behaviors: Default::default(),
+            list: Default::default(),
+            save_as: Default::default(),
+            completions: Default::default(),
+            completions_dynamic: Default::default(),
+            completions_dir: Default::default(),
+            list_template_names: Default::default(),
+            force: Default::default(),
+        }
+    }
+}
+
+/// Split a `--set` argument into its key and value.
+fn parse_variable(input: &str) -> Result<(String, String), String> {
+    match input.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        Some(_) => Err(format!(
+            r#""{input}" doesn't have a name before the "=". Please use KEY=VALUE."#
+        )),
+        None => Err(format!(
+            r#""{input}" doesn't have an "=" in it. Please use KEY=VALUE."#
+        )),
+    }
+}
+
+/// Run the commands from `--var-from-command` and turn their output
+/// into variables. Commands are run with `sh -c`, in `dir` if it
+/// exists.
+fn variables_from_commands(
+    commands: &[(String, String)],
+    dir: &Path,
+) -> anyhow::Result<Vec<(String, String)>> {
+    commands
+        .iter()
+        .map(|(key, command)| {
+            let mut sh = Command::new("sh");
+            sh.arg("-c").arg(command).stderr(Stdio::inherit());
+            if dir.is_dir() {
+                sh.current_dir(dir);
+            }
+
+            let output = sh.output().with_context(|| {
+                format!(r#"I was unable to run the command for "{key}" ("{command}")."#)
+            })?;
+
+            if !output.status.success() {
+                bail!(
+                    r#"The command for "{key}" ("{command}") failed ({}), so I don't have a value for it."#,
+                    output.status
+                )
+            }
+
+            let value = String::from_utf8(output.stdout).with_context(|| {
+                format!(r#"The output of the command for "{key}" ("{command}") isn't valid UTF-8."#)
+            })?;
+
+            Ok((key.clone(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Check that `input` looks like a version number, e.g. "2.21.0".
+fn parse_version(input: &str) -> Result<String, String> {
+    let valid = !input.is_empty()
+        && input
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+
+    if valid {
+        Ok(input.to_string())
+    } else {
+        Err(format!(
+            r#""{input}" is not a version number. Please use only numbers separated by dots, such as "2.21.0"."#
+        ))
+    }
+}
+
+/// Whether `version` is at least `minimum`, where both are numbers
+/// separated by dots (see `parse_version`). Missing parts count as 0,
+/// so "1.2" is the same as "1.2.0".
+fn version_at_least(version: &str, minimum: &str) -> bool {
+    let parts = |version: &str| -> Vec<u64> {
+        version
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    };
+    let (mut version, mut minimum) = (parts(version), parts(minimum));
+    let len = version.len().max(minimum.len());
+    version.resize(len, 0);
+    minimum.resize(len, 0);
+    version >= minimum
+}
+
+/// The line that loads the `--envrc-prelude` in the `.envrc`, if
+/// there is one: `source_url` (with its integrity hash) for http(s)
+/// URLs, and `source_env` for paths.
+fn envrc_prelude(prelude: Option<&str>, hash: Option<&str>) -> anyhow::Result<Option<String>> {
+    let quote = |value: &str| format!("'{}'", value.replace('\'', r"'\''"));
+
+    let Some(prelude) = prelude else {
+        return Ok(None);
+    };
+
+    if prelude.starts_with("http://") || prelude.starts_with("https://") {
+        match hash {
+            Some(hash) if hash.starts_with("sha256-") && hash.len() > "sha256-".len() => Ok(Some(
+                format!("source_url {} {}", quote(prelude), quote(hash)),
+            )),
+            Some(hash) => bail!(
+                r#""{hash}" doesn't look like an integrity hash. You can get the right one by running `direnv fetchurl {prelude}`."#
+            ),
+            None => bail!(
+                r#"direnv needs the integrity hash of "{prelude}" to load it. Please pass it with `--envrc-prelude-hash` (you can get it by running `direnv fetchurl {prelude}`)."#
+            ),
+        }
+    } else if prelude.contains("://") {
+        bail!(r#"I can only load a prelude from an http(s) URL or a path, not from "{prelude}"."#)
+    } else if hash.is_some() {
+        bail!(
+            r#""{prelude}" is a path, so it doesn't need an integrity hash. Please remove `--envrc-prelude-hash`."#
+        )
+    } else {
+        Ok(Some(format!("source_env {}", quote(prelude))))
+    }
+}
+
+/// Remove `.` and `..` components and trailing slashes from `path`
+/// without touching the file system, so that it works for paths that
+/// don't exist yet. `..` at the start of a relative path is kept.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                // you can't go further up than the root
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component),
         }
     }
+
+    if normalized.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        normalized
+    }
 }
 
 fn try_get_target_dir(input: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    // only look up the current directory if we have to: it can fail
+    // (e.g. if the directory has been deleted), and we don't need it
+    // if we've been told where to go.
     match input {
-        None => current_dir().context("Failed to read the current working directory."),
+        None => current_dir().context(
+            "Failed to read the current working directory. You can tell me which directory to use with the `--directory` option instead.",
+        ),
 
         Some(dir) => {
+            let dir = normalize_path(&dir);
             if dir.is_dir() || !dir.exists() {
                 Ok(dir)
             } else {
@@ -107,6 +782,186 @@ fn try_get_target_dir(input: Option<PathBuf>) -> anyhow::Result<PathBuf> {
     }
 }
 
+/// The directories directly in `dir` that aren't hidden, sorted by
+/// name: what `--each-subdir` sets up.
+fn visible_subdirs(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!(r#"I was unable to read "{}"."#, dir.display()))?;
+    let mut subdirs = vec![];
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!(r#"I was unable to read "{}"."#, dir.display()))?
+            .path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if path.is_dir() && !hidden {
+            subdirs.push(path);
+        }
+    }
+    subdirs.sort();
+    Ok(subdirs)
+}
+
+/// The `.inix/templates` directory in `dir` or in the closest of its
+/// parents that has one (like git looks for `.git`), if there is one.
+fn find_local_template_dir(dir: &Path) -> Option<PathBuf> {
+    let dir = match dir.is_absolute() {
+        true => dir.to_path_buf(),
+        false => current_dir().ok()?.join(dir),
+    };
+    dir.ancestors()
+        .map(|ancestor| ancestor.join(".inix").join("templates"))
+        .find(|templates| templates.is_dir())
+}
+
+/// Combine the templates passed as arguments with the ones passed in
+/// the `--templates` list, keeping the order they were provided in
+/// and skipping duplicates.
+fn requested_templates(cli: &Cli) -> anyhow::Result<Vec<String>> {
+    let listed = match &cli.template_list {
+        None => vec![],
+        Some(list) => list
+            .split(cli.template_separator)
+            .map(str::trim)
+            .map(|name| match name {
+                "" => Err(anyhow!(
+                    r#"The template list "{}" contains an empty template name. Please make sure that there is a template name between each separator ("{}")."#,
+                    list,
+                    cli.template_separator
+                )),
+                name => Ok(name.to_string()),
+            })
+            .collect::<anyhow::Result<_>>()?,
+    };
+
+    Ok(cli
+        .templates
+        .iter()
+        .cloned()
+        .chain(listed)
+        .unique()
+        .collect())
+}
+
+/// Make sure that `dir` can be created, along with any missing parent
+/// directories, by checking that the closest ancestor that already
+/// exists is writable. This gives a better error than whatever
+/// `create_dir_all` fails with further down the chain. The permission
+/// bits don't tell the whole story (who owns the directory, ACLs,
+/// read-only mounts), so this creates (and removes) a file there.
+fn ensure_dir_can_be_created(dir: &Path) -> anyhow::Result<()> {
+    let ancestor = dir
+        .ancestors()
+        .skip(1)
+        .map(|ancestor| {
+            if ancestor.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                ancestor
+            }
+        })
+        .find(|ancestor| ancestor.exists());
+
+    if let Some(ancestor) = ancestor.filter(|ancestor| ancestor.is_dir()) {
+        let probe = tempfile::Builder::new()
+            .prefix(".inix-")
+            .tempfile_in(ancestor);
+        if let Err(e) = probe {
+            return Err(e).with_context(|| {
+                format!(
+                    r#"I can't create "{}" because "{}" isn't writable."#,
+                    dir.display(),
+                    ancestor.display()
+                )
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Make sure that `dir` isn't in the nix store (`store_dir`), which is
+/// read-only: writing there would only fail with an unhelpful error.
+/// Symlinks are resolved first, so links into the store are caught
+/// too.
+fn ensure_not_in_nix_store(dir: &Path, store_dir: &Path) -> anyhow::Result<()> {
+    let absolute = absolute_path(dir);
+    // the directory might not exist yet, so resolve the closest
+    // ancestor that does and add the rest back on
+    let resolved = absolute
+        .ancestors()
+        .find_map(|ancestor| {
+            let canonical = ancestor.canonicalize().ok()?;
+            let rest = absolute.strip_prefix(ancestor).ok()?;
+            Some(canonical.join(rest))
+        })
+        .unwrap_or(absolute);
+    let store_dir = store_dir
+        .canonicalize()
+        .unwrap_or_else(|_| store_dir.to_path_buf());
+
+    if resolved.starts_with(&store_dir) {
+        bail!(
+            r#""{}" is in the nix store ("{}"), which is read-only, so I can't put any files there. Please pick a directory outside of the store with `--directory` (or run me from one)."#,
+            dir.display(),
+            store_dir.display()
+        )
+    }
+
+    Ok(())
+}
+
+/// Where the nix store is: `$NIX_STORE_DIR` if it's set, like nix
+/// itself does, and `/nix/store` otherwise.
+fn nix_store_dir() -> PathBuf {
+    env::var_os("NIX_STORE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/nix/store"))
+}
+
+/// Make sure that `dir` is empty (or doesn't exist yet). A `.git`
+/// directory is allowed, so that you can scaffold freshly initialized
+/// repositories.
+fn ensure_dir_is_empty(dir: &Path) -> anyhow::Result<()> {
+    const MAX_LISTED_ENTRIES: usize = 5;
+
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    let entries: Vec<_> = fs::read_dir(dir)
+        .with_context(|| {
+            format!(
+                r#"I was unable to read the contents of "{}"."#,
+                dir.display()
+            )
+        })?
+        .filter_map(|entry| entry.ok().map(|e| e.file_name()))
+        .filter(|name| name != ".git" && name != RunLock::FILE_NAME)
+        .sorted()
+        .collect();
+
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let listed = entries
+        .iter()
+        .take(MAX_LISTED_ENTRIES)
+        .map(|name| format!("- {}", name.to_string_lossy()))
+        .join("\n");
+    let more = match entries.len().saturating_sub(MAX_LISTED_ENTRIES) {
+        0 => String::new(),
+        n => format!("\n(and {n} more)"),
+    };
+
+    bail!(
+        "You asked me to only use an empty directory, but \"{}\" already contains these entries:\n{listed}{more}",
+        dir.display()
+    )
+}
+
 #[derive(Clone, Copy, Debug)]
 enum TemplateFiles {
     Nix(&'static str),
@@ -142,10 +997,22 @@ struct CustomTemplate<'a> {
 enum TemplateFiles2 {
     Nix(String),
     Envrc(String),
-    Both { nix: String, envrc: String },
+    Both {
+        nix: String,
+        envrc: String,
+    },
+    /// A template whose environment is defined by a `flake.nix`. It
+    /// may also come with a lock file and any of the other files.
+    Flake {
+        flake: String,
+        lock: Option<String>,
+        nix: Option<String>,
+        envrc: Option<String>,
+    },
 }
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 enum TemplateType {
     Custom,
     Builtin,
@@ -155,10 +1022,54 @@ enum TemplateType {
 struct Template2 {
     name: String,
     files: TemplateFiles2,
+    /// Any other files in a custom template's directory, as (relative
+    /// path, contents) pairs.
+    extra_files: ExtraFiles,
+    /// Empty directories in a custom template's directory, relative to
+    /// it. They'd be lost if only files were copied.
+    empty_dirs: Vec<String>,
+    /// What the template's `inix.toml` says about it, if it has one.
+    metadata: Option<TemplateMetadata>,
     source_dir: PathBuf,
     template_type: TemplateType,
 }
 
+/// The contents of a custom template's (optional) `inix.toml`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplateMetadata {
+    /// What the template is for, shown in listings and plans.
+    description: String,
+    /// The files (other than `shell.nix`, `.envrc`, `flake.nix`, and
+    /// `flake.lock`) that the template provides. If it's left out,
+    /// every other file in the template's directory is copied.
+    files: Option<Vec<String>>,
+    /// A nixpkgs overlay that the template provides (see
+    /// `Template2::overlay`). It's copied even if it's not in `files`.
+    overlay: Option<String>,
+    /// The variables that the template's files use. inix asks for the
+    /// ones that aren't set with `--set` or `--var-from-command`.
+    #[serde(default)]
+    variables: Vec<String>,
+    /// The oldest version of inix that the template works with.
+    min_inix_version: Option<String>,
+    /// The template's files that are copied as they are instead of
+    /// being rendered with Handlebars, like a `shell.nix` full of
+    /// `{{`. Files other than `shell.nix`, `.envrc`, `flake.nix`, and
+    /// `flake.lock` are never rendered.
+    #[serde(default)]
+    verbatim: Vec<String>,
+    /// Shell code to run when entering the environment. It goes in
+    /// the `shellHook` of the top-level `shell.nix` (or `flake.nix`),
+    /// after the ones of the templates before it. That's a Nix string
+    /// (`'' ... ''`), so `${...}` is Nix, not shell, interpolation.
+    shell_hook: Option<String>,
+    /// The templates that this one can't be used together with, like
+    /// two different setups for the same language.
+    #[serde(default)]
+    conflicts_with: Vec<String>,
+}
+
 impl Template2 {
     fn name(&self) -> &str {
         &self.name
@@ -168,829 +1079,9255 @@ impl Template2 {
         self.source_dir.join(self.name.to_string())
     }
 
-    fn files(&self) -> Vec<(&'static str, &str)> {
+    /// The files that make up this template, as (file name, contents)
+    /// pairs. File names are relative to the template's directory. The
+    /// files that inix knows (see `known_files`) come first.
+    fn files(&self) -> Vec<(String, &[u8])> {
+        let mut files: Vec<_> = self
+            .known_files()
+            .into_iter()
+            .map(|(file_name, contents)| (file_name, contents.as_bytes()))
+            .collect();
+        files.extend(
+            self.extra_files
+                .iter()
+                .map(|(file_name, contents)| (file_name.clone(), contents.as_slice())),
+        );
+        files
+    }
+
+    fn known_files(&self) -> Vec<(String, &str)> {
         match &self.files {
-            TemplateFiles2::Nix(content) => vec![("shell.nix", &content)],
-            TemplateFiles2::Envrc(content) => vec![(".envrc", &content)],
+            TemplateFiles2::Nix(content) => vec![("shell.nix".into(), content)],
+            TemplateFiles2::Envrc(content) => vec![(".envrc".into(), content)],
             TemplateFiles2::Both { nix, envrc } => {
-                vec![(".envrc", &envrc), ("shell.nix", &nix)]
+                vec![(".envrc".into(), envrc), ("shell.nix".into(), nix)]
             }
+            TemplateFiles2::Flake {
+                flake,
+                lock,
+                nix,
+                envrc,
+            } => [
+                (".envrc", envrc.as_deref()),
+                ("flake.lock", lock.as_deref()),
+                ("flake.nix", Some(flake.as_str())),
+                ("shell.nix", nix.as_deref()),
+            ]
+            .into_iter()
+            .filter_map(|(file_name, contents)| Some((file_name.into(), contents?)))
+            .collect(),
         }
     }
-}
 
-fn included_templates() -> HashMap<&'static str, Template2> {
-    hash_map! {
-        "rust" => Template2 {name:"rust".into(),files:TemplateFiles2::Nix(include_str!("templates/rust/shell.nix").into()),source_dir:PathBuf::from("inix/templates"), template_type: TemplateType::Builtin},
-        "node" => Template2 {
-            name: "node".into(),
-            files: TemplateFiles2::Both {
-                nix: include_str!("templates/node/shell.nix").into(),
-                envrc: include_str!("templates/node/.envrc").into(),
-            },
-            source_dir: PathBuf::from("inix/templates")
-                , template_type: TemplateType::Builtin
-        },
-        "base" =>  Template2 {
-            name: "base".into(),
-            files: TemplateFiles2::Both {
-                nix: include_str!("templates/base/shell.nix.template").into(),
-              envrc: include_str!("templates/base/.envrc.template").into(),
-            },
-            source_dir: PathBuf::from("inix/templates"), template_type: TemplateType::Builtin
-        },
+    /// The description from the template's `inix.toml`, if any.
+    fn description(&self) -> Option<&str> {
+        self.metadata
+            .as_ref()
+            .map(|metadata| metadata.description.as_str())
     }
-}
 
-fn try_get_templates(input_templates: &[String]) -> anyhow::Result<Vec<Template2>> {
-    #[derive(Clone, Copy, Debug)]
-    enum DirErrorReason {
-        NotADir,
-        NoConfigDir,
-        NotFound,
+    /// The variables that the template's `inix.toml` says have to be
+    /// set to use it.
+    fn required_variables(&self) -> &[String] {
+        self.metadata
+            .as_ref()
+            .map(|metadata| metadata.variables.as_slice())
+            .unwrap_or_default()
     }
 
-    #[derive(Clone, Debug)]
-    struct DirError {
-        path: PathBuf,
-        reason: DirErrorReason,
+    /// The oldest version of inix that the template's `inix.toml` says
+    /// it works with, if any.
+    fn min_inix_version(&self) -> Option<&str> {
+        self.metadata
+            .as_ref()
+            .and_then(|metadata| metadata.min_inix_version.as_deref())
     }
 
-    impl Display for DirError {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(f, "{} ({})", self.path.display(), match self.reason {
-                                DirErrorReason::NotADir =>
-                                    "which exists, but is not a directory (it's probably a file!)",
-                                DirErrorReason::NoConfigDir =>
-                                    "but I don't know where your user configuration directory is (this probably means that you're not on Linux, macOS, or Windows)",
-                                DirErrorReason::NotFound => "but it doesn't exist",
-                            }
-)
+    /// The templates that the template's `inix.toml` says it can't be
+    /// used together with.
+    fn conflicts_with(&self) -> &[String] {
+        self.metadata
+            .as_ref()
+            .map(|metadata| metadata.conflicts_with.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// The shell code that the template's `inix.toml` says to run when
+    /// entering the environment, if any.
+    fn shell_hook(&self) -> Option<&str> {
+        self.metadata
+            .as_ref()
+            .and_then(|metadata| metadata.shell_hook.as_deref())
+    }
+
+    /// Make sure that this version of inix is new enough to use the
+    /// template. This is only checked when the template is used, so
+    /// that it can still be listed and shown.
+    fn ensure_supported(&self) -> anyhow::Result<()> {
+        let Some(minimum) = self.min_inix_version() else {
+            return Ok(());
+        };
+        let current = env!("CARGO_PKG_VERSION");
+        parse_version(minimum).map_err(|e| {
+            anyhow!(
+                r#"The inix.toml of the "{}" template ("{}") has a `min_inix_version` that I don't understand: {e}"#,
+                self.name(),
+                self.source_dir.display()
+            )
+        })?;
+        if !version_at_least(current, minimum) {
+            bail!(
+                r#"The "{}" template ("{}") needs inix {minimum} or later, but this is inix {current}. Please upgrade inix to use it."#,
+                self.name(),
+                self.source_dir.display()
+            )
         }
+        Ok(())
     }
 
-    // a prioritized list over where to find templates. Items listed earlier take precedence
-    let template_locations: Vec<_> = [
-        dirs::config_dir()
-            .map(|dir| dir.join("inix"))
-            .ok_or(DirError {
-                path: PathBuf::from("<your user configuration directory>/inix"),
-                reason: DirErrorReason::NoConfigDir,
-            }),
-        // Ok(PathBuf::from("../templates")),
-    ]
-    .into_iter()
-    .map(|result| {
-        result.and_then(|dir| {
-            if dir.is_dir() {
-                Ok(dir)
-            } else {
-                let reason = match dir.exists() {
-                    true => DirErrorReason::NotADir,
-                    false => DirErrorReason::NotFound,
-                };
-                Err(DirError {
-                    path: dir.clone(),
-                    reason,
-                })
-            }
-        })
-    })
-    .collect();
+    /// The template's nixpkgs overlay, relative to its directory: the
+    /// one its `inix.toml` points to, or else its `overlay.nix`, if it
+    /// has one. The top-level files apply every template's overlay to
+    /// the packages that all the templates use.
+    fn overlay(&self) -> Option<&str> {
+        match self.metadata.as_ref().and_then(|m| m.overlay.as_deref()) {
+            Some(overlay) => Some(overlay),
+            None => self
+                .extra_files
+                .iter()
+                .map(|(file_name, _)| file_name.as_str())
+                .find(|file_name| *file_name == "overlay.nix"),
+        }
+    }
 
-    let found_template_dirs: Vec<_> = template_locations
+    /// Whether this template's environment is defined by a flake.
+    fn is_flake(&self) -> bool {
+        matches!(self.files, TemplateFiles2::Flake { .. })
+    }
+
+    /// Whether the template's `shell.nix` can be given the packages
+    /// that the top-level files use (with all the templates' overlays
+    /// applied) instead of its own. That's the case unless it sets up
+    /// its own nixpkgs with overlays that aren't among them, like the
+    /// builtin rust template does.
+    fn accepts_shared_pkgs(&self) -> bool {
+        let nix = match &self.files {
+            TemplateFiles2::Nix(nix) | TemplateFiles2::Both { nix, .. } => Some(nix),
+            TemplateFiles2::Flake { nix, .. } => nix.as_ref(),
+            TemplateFiles2::Envrc(_) => None,
+        };
+        self.overlay().is_some() || !nix.is_some_and(|nix| uses_nix_word(nix, "overlays"))
+    }
+}
+
+/// Where `template` was read from: its directory for custom
+/// templates, and "<builtin>" for the builtin ones.
+fn template_location(template: &Template2) -> String {
+    match template.template_type {
+        TemplateType::Custom => template.source_dir.display().to_string(),
+        TemplateType::Builtin => "<builtin>".to_string(),
+    }
+}
+
+/// Remove the templates called `names` from the inix directory in
+/// `target_dir` and render the top-level files again from the ones
+/// that are left. Like a run, everything is staged first, so either
+/// all of it happens or none of it does. Returns the top-level files
+/// that were written, and any warnings about the templates that are
+/// left (see `final_templates`).
+fn remove_templates(
+    names: &[String],
+    target_dir: &Path,
+    cli: &Cli,
+    dirs: &Dirs,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<String>)> {
+    let inix_dir = target_dir.join("inix");
+    let missing: Vec<_> = names
         .iter()
-        .filter_map(|x| x.as_deref().map(|y| y.clone()).ok())
+        .filter(|name| !is_plain_name(name) || inix_dir.join(name).symlink_metadata().is_err())
         .collect();
+    if !missing.is_empty() {
+        bail!(
+            r#"There's nothing to remove for {} in "{}", because there are no templates with those names there."#,
+            combine_strings(missing.into_iter()),
+            inix_dir.display()
+        )
+    }
 
-    let (oks, errs): (Vec<_>, Vec<_>) = input_templates
-        .iter()
-        .map(|template_name| {
-            found_template_dirs
-                .iter()
-                .find_map(|location| {
-                    let dir = location.join(template_name);
-                    match (
-                        fs::read_to_string(dir.join("shell.nix")),
-                        fs::read_to_string(dir.join(".envrc")),
-                    ) {
-                        (Err(_), Err(_)) => None,
-                        (Ok(nix), Err(_)) => Some(TemplateFiles2::Nix(nix)),
-                        (Err(_), Ok(envrc)) => Some(TemplateFiles2::Envrc(envrc)),
-                        (Ok(nix), Ok(envrc)) => Some(TemplateFiles2::Both { nix, envrc }),
-                    }
-                    .map(|files| Template2 {
-                        name: template_name.to_owned(),
-                        source_dir: dir,
-                        files,
-                        template_type: TemplateType::Custom,
-                    })
-                })
-                .or_else(|| {
-                    included_templates()
-                        .get(&template_name as &str)
-                        .map(|t| t.clone())
-                })
-                .ok_or_else(|| anyhow!(template_name.clone()))
-        })
-        .partition_result();
+    let (remaining, warnings) = final_templates(&inix_dir, &[], ConflictBehavior::MergeKeep)?;
+    let remaining: Vec<_> = remaining
+        .into_iter()
+        .filter(|template| !names.iter().any(|name| name == template.name()))
+        .collect();
+    let flake_nix_path = target_dir.join("flake.nix");
+    let cli = Cli {
+        emit_flake: cli.emit_flake
+            || flake_nix_path.exists()
+            || remaining.iter().any(Template2::is_flake),
+        ..cli.clone()
+    };
+    let handlebars = base_handlebars(cli.strict_vars);
+    let args = base_template_args(&remaining, &cli)?;
+    let render = |template: &str, path: &Path| {
+        handlebars
+            .render_template(template, &args)
+            .with_context(|| format!(r#"I was unable to render "{}"."#, path.display()))
+    };
+    let (nix_template, envrc_template) = base_template_files(cli.template_dir_precedence, dirs)?;
 
-    if errs.is_empty() {
-        Ok(oks)
-    } else {
-        Err(anyhow!(formatdoc!(
-            "
-            I couldn't find these templates:
-            {}
+    let mut staging = Staging::default();
+    let staged = staging.stage_inix_dir(&inix_dir, true)?;
+    for name in names {
+        remove_entry(&staged.join(name))?;
+    }
+    let mut sources = read_template_sources(&staged)?;
+    sources.retain(|name, _| !names.contains(name));
+    write_template_sources(&staged, &sources)?;
+
+    // a project that's set up with just a flake stays that way
+    let shell_nix_path = target_dir.join("shell.nix");
+    if shell_nix_path.exists() || !flake_nix_path.exists() {
+        staging.stage_file(
+            &shell_nix_path,
+            &render(&nix_template, &shell_nix_path)?,
+            false,
+        )?;
+    }
 
-            I looked (or tried to look) in these places:
-            {}",
-            errs.iter().map(|name| format!("- {}", name)).join("\n"),
-            template_locations
-                .iter()
-                .map(|location| format!(
-                    "- {}",
-                    match location {
-                        Ok(l) => l.display().to_string(),
-                        Err(l) => l.to_string(),
-                    }
-                ))
-                .join("\n"),
-        )))
+    let envrc_path = target_dir.join(".envrc");
+    let envrc = render(&envrc_template, &envrc_path)?;
+    let envrc = match fs::read_to_string(&envrc_path) {
+        Ok(existing) => update_managed_block(&existing, &envrc),
+        Err(_) => envrc,
+    };
+    staging.stage_file(&envrc_path, &envrc, false)?;
+
+    if cli.emit_flake {
+        let flake = builtin_file(
+            dirs.builtin.as_deref(),
+            "base/flake.nix.template",
+            include_str!("templates/base/flake.nix.template"),
+        );
+        staging.stage_file(&flake_nix_path, &render(&flake, &flake_nix_path)?, false)?;
     }
+
+    Ok((staging.commit()?.written_files(), warnings))
 }
 
-#[derive(Clone, Debug)]
-enum TemplateCollisions<'a> {
-    None,
-    All(NonEmpty<&'a str>),
-    Some(NonEmpty<&'a str>),
+/// The templates that `inix update` sets up again: the ones called
+/// `names`, or every template in `inix_dir` if there are none. When
+/// updating everything, templates that can't be found anymore are
+/// skipped with a warning (which is returned).
+fn templates_to_update(
+    names: &[String],
+    inix_dir: &Path,
+    precedence: TemplateDirPrecedence,
+    dirs: &Dirs,
+) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    if !inix_dir.is_dir() {
+        bail!(
+            r#"There's no inix directory ("{}"), so there's nothing to update. Run me with the templates you want to set it up."#,
+            inix_dir.display()
+        )
+    }
+    let installed: Vec<_> = visible_subdirs(inix_dir)?
+        .iter()
+        .filter_map(|dir| Some(dir.file_name()?.to_string_lossy().into_owned()))
+        .collect();
+
+    if !names.is_empty() {
+        let missing: Vec<_> = names
+            .iter()
+            .filter(|name| !installed.contains(name))
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                r#"I can only update templates that are set up already, and {} isn't in "{}". Run me with the templates (without `update`) to add them."#,
+                combine_strings(missing.into_iter()),
+                inix_dir.display()
+            )
+        }
+        return Ok((names.to_vec(), vec![]));
+    }
+
+    let existing_dirs = dirs.existing_template_dirs();
+    let custom_dirs: Vec<_> = existing_dirs.iter().map(PathBuf::as_path).collect();
+    let mut found = vec![];
+    let mut warnings = vec![];
+    for name in installed {
+        if dirs.remote_template(&name).is_some() {
+            found.push(name);
+            continue;
+        }
+        match find_template(
+            &name,
+            &custom_dirs,
+            &dirs.single_template_dirs,
+            precedence,
+            dirs.builtin.as_deref(),
+        ) {
+            Ok(Some(_)) => found.push(name),
+            Ok(None) => warnings.push(format!(
+                r#"Warning: I couldn't find where the "{name}" template comes from anymore, so I've left it as it is."#
+            )),
+            Err(error) => warnings.push(format!(
+                r#"Warning: I was unable to read the "{name}" template, so I've left it as it is: {error:#}"#
+            )),
+        }
+    }
+    Ok((found, warnings))
 }
 
-#[derive(Debug, Clone)]
-enum InixDirState<'a> {
-    DoesNotExist,
-    AlreadyExists {
-        template_collisions: TemplateCollisions<'a>,
-    },
+/// What `inix remove --dry-run` prints: what would be removed and
+/// which files would be updated.
+fn removal_steps(names: &[String], target_dir: &Path) -> Vec<String> {
+    let inix_dir = target_dir.join("inix");
+    names
+        .iter()
+        .map(|name| format!("Remove {}", inix_dir.join(name).display()))
+        .chain(
+            ["shell.nix", ".envrc", "flake.nix"]
+                .into_iter()
+                .map(|file_name| target_dir.join(file_name))
+                // there's only a flake.nix to update if there is one
+                .filter(|path| !path.ends_with("flake.nix") || path.exists())
+                .map(|path| format!("Update {}", path.display())),
+        )
+        .collect()
 }
 
-#[derive(Debug, Clone)]
-struct InixDir<'a> {
-    path: &'a PathBuf,
-    state: InixDirState<'a>,
+/// What `inix show` prints for `template`: where it comes from and the
+/// contents of each of its files, with a header like `head` uses.
+fn show_template(template: &Template2) -> String {
+    let mut shown = format!(
+        "The \"{}\" template, from {}\n",
+        template.name(),
+        template_location(template)
+    );
+    if let Some(description) = template.description() {
+        shown.push_str(&format!("{description}\n"));
+    }
+    if !template.required_variables().is_empty() {
+        shown.push_str(&format!(
+            "Variables: {}\n",
+            template.required_variables().join(", ")
+        ));
+    }
+    if let Some(minimum) = template.min_inix_version() {
+        shown.push_str(&format!("Needs inix {minimum} or later\n"));
+    }
+    for (file_name, contents) in template.files() {
+        let contents = std::str::from_utf8(contents).unwrap_or("(not a text file)\n");
+        shown.push_str(&format!("\n==> {file_name} <==\n{contents}"));
+        if !contents.ends_with('\n') {
+            shown.push('\n');
+        }
+    }
+    shown
 }
 
-impl<'a> InixDir<'a> {
-    fn conflict_description(&self) -> String {
-        match &self.state {
-            InixDirState::DoesNotExist => format!(
-                r#"The inix directory ({}) does not exist."#,
-                self.path.display()
-            ),
-            InixDirState::AlreadyExists {
-                template_collisions,
-            } => match template_collisions {
-                TemplateCollisions::None => format!(
-                    r#"
-            The inix directory ("{}") already exists, but none of the new templates conflict with existing subdirectories."#,
-                    self.path.display()
-                ),
-                TemplateCollisions::All(conflicts) => format!(
-                    r#"The inix directory ("{}") already exists, and it contains all of the templates that you're trying to add ({})."#,
-                    self.path.display(),
-                    combine_strings(conflicts.into_iter())
-                ),
-                TemplateCollisions::Some(conflicts) => format!(
-                    r#"The inix directory ("{}") already exists, and the following templates you're trying to add already exist in the inix directory: {}."#,
-                    self.path.display(),
-                    combine_strings(conflicts.into_iter())
+/// The names of all the builtin templates and of all the
+/// directories in the existing custom template directories, sorted
+/// and without duplicates, leaving out the base template, which isn't
+/// one that you'd ask for. Not every directory is necessarily a
+/// template: use `find_template` to check. The custom directories
+/// that are also in `single_template_dirs` can be a template
+/// themselves (see `single_template_name`).
+fn template_names(
+    custom_dirs: &[&Path],
+    single_template_dirs: &[PathBuf],
+) -> anyhow::Result<Vec<String>> {
+    let mut custom_names = vec![];
+    for dir in custom_dirs {
+        if let Some(name) = single_template_dirs
+            .iter()
+            .any(|single| single == dir)
+            .then(|| single_template_name(dir))
+            .flatten()
+        {
+            custom_names.push(name);
+            continue;
+        }
+        custom_names.extend(
+            fs::read_dir(dir)
+                .with_context(|| {
+                    format!(
+                        r#"I was unable to list the templates in "{}"."#,
+                        dir.display()
+                    )
+                })?
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_dir())
+                .map(|entry| entry.file_name().to_string_lossy().to_string()),
+        );
+    }
+
+    Ok(included_templates(None)
+        .into_keys()
+        .map(String::from)
+        .chain(custom_names)
+        .filter(|name| name != "base")
+        .sorted()
+        .dedup()
+        .collect())
+}
+
+/// If there's no template called `name`, but `name` is the start of
+/// the name of exactly one template, the full name of that template.
+/// It's an error if more than one template name starts with `name`.
+fn complete_template_name(
+    name: &str,
+    precedence: TemplateDirPrecedence,
+    dirs: &Dirs,
+) -> anyhow::Result<Option<String>> {
+    let existing_dirs = dirs.existing_template_dirs();
+    let custom_dirs: Vec<_> = existing_dirs.iter().map(PathBuf::as_path).collect();
+
+    if !is_plain_name(name)
+        || dirs.remote_template(name).is_some()
+        || find_template(
+            name,
+            &custom_dirs,
+            &dirs.single_template_dirs,
+            precedence,
+            dirs.builtin.as_deref(),
+        )?
+        .is_some()
+    {
+        return Ok(None);
+    }
+
+    // a broken template is still a candidate: if it's the one that's
+    // meant, using it says what's wrong with it
+    let mut candidates = vec![];
+    for candidate in template_names(&custom_dirs, &dirs.single_template_dirs)? {
+        if candidate.starts_with(name)
+            && !matches!(
+                find_template(
+                    &candidate,
+                    &custom_dirs,
+                    &dirs.single_template_dirs,
+                    precedence,
+                    dirs.builtin.as_deref(),
                 ),
-            },
+                Ok(None)
+            )
+        {
+            candidates.push(candidate);
         }
     }
+
+    match candidates.as_slice() {
+        [] => Ok(None),
+        [candidate] => Ok(Some(candidate.clone())),
+        _ => bail!(
+            r#"There's no template called "{name}", and I can't tell which of these templates you meant: {}. Please use the full name of the template."#,
+            combine_strings(candidates.iter().map(String::as_str))
+        ),
+    }
 }
 
-fn run(cli: Cli) -> anyhow::Result<()> {
-    // PREPARE //
+/// A line for every available template, sorted by name, saying where
+/// it comes from and which files it provides. If a custom template
+/// and a builtin template share a name, the one that's used comes
+/// first and the other one is listed as shadowed.
+fn template_listing(precedence: TemplateDirPrecedence, dirs: &Dirs) -> anyhow::Result<Vec<String>> {
+    let existing_dirs = dirs.existing_template_dirs();
+    let custom_dirs: Vec<_> = existing_dirs.iter().map(PathBuf::as_path).collect();
 
-    // check to see whether we can find all the templates
-    let templates = try_get_templates(&cli.templates)?;
+    let names = template_names(&custom_dirs, &dirs.single_template_dirs)?;
 
-    // check to see if the target directory exists
-    let target_dir = try_get_target_dir(cli.directory)?;
+    let describe = |template: &Template2| match template.template_type {
+        TemplateType::Custom => format!("custom, from {}", template.source_dir.display()),
+        TemplateType::Builtin => "builtin".to_string(),
+    };
 
-    // check to see whether we have write permissions in the target
-    // directory
+    let mut lines = vec![];
+    for name in names {
+        let other_precedence = match precedence {
+            TemplateDirPrecedence::Before => TemplateDirPrecedence::After,
+            TemplateDirPrecedence::After => TemplateDirPrecedence::Before,
+        };
 
-    // does the inix subdirectory already exist?
-    let inix_dir_path = target_dir.join("inix");
+        // a directory in the config dir without any template files in it
+        // isn't a template, and a broken template is listed as such
+        // instead of hiding all the others
+        let used = match find_template(
+            &name,
+            &custom_dirs,
+            &dirs.single_template_dirs,
+            precedence,
+            dirs.builtin.as_deref(),
+        ) {
+            Ok(Some(used)) => used,
+            Ok(None) => continue,
+            Err(error) => {
+                lines.push(format!("{name} (broken: {error:#})"));
+                continue;
+            }
+        };
+        let mut files = used
+            .known_files()
+            .into_iter()
+            .map(|(file_name, _)| file_name)
+            .join(", ");
+        if !used.required_variables().is_empty() {
+            files.push_str(&format!(
+                "; variables: {}",
+                used.required_variables().join(", ")
+            ));
+        }
+        if let Some(minimum) = used.min_inix_version() {
+            files.push_str(&format!("; needs inix {minimum}"));
+        }
+        lines.push(match used.description() {
+            Some(description) => {
+                format!("{name} ({}; {files}): {description}", describe(&used))
+            }
+            None => format!("{name} ({}; {files})", describe(&used)),
+        });
 
-    // gather all data about the inix dir
+        let winner = match used.template_type {
+            TemplateType::Custom => "custom",
+            TemplateType::Builtin => "builtin",
+        };
+        let shadowed = match find_template(
+            &name,
+            &custom_dirs,
+            &dirs.single_template_dirs,
+            other_precedence,
+            dirs.builtin.as_deref(),
+        ) {
+            Ok(shadowed) => shadowed,
+            Err(error) => {
+                lines.push(format!(
+                    "{name} (broken, shadowed by the {winner} template: {error:#})"
+                ));
+                None
+            }
+        };
+        if let Some(shadowed) =
+            shadowed.filter(|template| template.template_type != used.template_type)
+        {
+            lines.push(format!(
+                "{name} ({}, shadowed by the {winner} template)",
+                describe(&shadowed),
+            ));
+        }
+    }
 
-    let inix_dir = {
-        let state = if inix_dir_path.is_dir() {
-            let conflicting_templates: Vec<&str> = templates
-                .iter()
-                .filter_map(|template| {
-                    if inix_dir_path.join(template.name()).is_dir() {
-                        Some(template.name())
-                    } else {
-                        None
-                    }
-                })
-                .collect();
+    Ok(lines)
+}
 
-            let template_collisions = match conflicting_templates.as_slice() {
-                [] => TemplateCollisions::None,
-                [head, tail @ ..] if conflicting_templates.len() == templates.len() => {
-                    TemplateCollisions::All(NonEmpty::from((*head, tail.iter().copied().collect())))
-                }
-                [head, tail @ ..] => TemplateCollisions::Some(NonEmpty::from((
-                    *head,
-                    tail.iter().copied().collect(),
-                ))),
-            };
+/// What was decided for a template when looking it up, for
+/// `--log-decisions`.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+struct TemplateDecision {
+    name: String,
+    decision: Decision,
+    /// Where the template comes from (see `template_location`), if it
+    /// was found.
+    source: Option<String>,
+    reason: String,
+}
 
-            InixDirState::AlreadyExists {
-                template_collisions,
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Decision {
+    Resolved,
+    Shadowed,
+    Missing,
+    Skipped,
+}
+
+/// A decision for each of the `input_templates`, in order, plus one
+/// for every template that's shadowed by one of them.
+fn template_decisions(
+    input_templates: &[String],
+    precedence: TemplateDirPrecedence,
+    dirs: &Dirs,
+) -> Vec<TemplateDecision> {
+    let existing_dirs = dirs.existing_template_dirs();
+    let custom_dirs: Vec<_> = existing_dirs.iter().map(PathBuf::as_path).collect();
+    let other_precedence = match precedence {
+        TemplateDirPrecedence::Before => TemplateDirPrecedence::After,
+        TemplateDirPrecedence::After => TemplateDirPrecedence::Before,
+    };
+    let kind = |template: &Template2| match template.template_type {
+        TemplateType::Custom => "custom",
+        TemplateType::Builtin => "builtin",
+    };
+
+    let mut decisions = vec![];
+    for name in input_templates {
+        if let Some(remote) = dirs.remote_template(name) {
+            decisions.push(TemplateDecision {
+                name: name.clone(),
+                decision: Decision::Resolved,
+                source: Some(remote.dir.display().to_string()),
+                reason: format!(r#"It was asked for from "{}"."#, remote.source),
+            });
+            continue;
+        }
+        let used = match find_template(
+            name,
+            &custom_dirs,
+            &dirs.single_template_dirs,
+            precedence,
+            dirs.builtin.as_deref(),
+        ) {
+            Ok(Some(used)) => used,
+            Ok(None) => {
+                decisions.push(TemplateDecision {
+                    name: name.clone(),
+                    decision: Decision::Missing,
+                    source: None,
+                    reason: "There's no builtin template with this name, and it's not in any of the template directories.".into(),
+                });
+                continue;
+            }
+            Err(error) => {
+                decisions.push(TemplateDecision {
+                    name: name.clone(),
+                    decision: Decision::Skipped,
+                    source: None,
+                    reason: format!("{error:#}"),
+                });
+                continue;
             }
-        } else {
-            InixDirState::DoesNotExist
         };
 
-        InixDir {
-            state,
-            path: &inix_dir_path,
+        let shadowed = find_template(
+            name,
+            &custom_dirs,
+            &dirs.single_template_dirs,
+            other_precedence,
+            dirs.builtin.as_deref(),
+        )
+        .ok()
+        .flatten()
+        .filter(|template| template.template_type != used.template_type);
+
+        decisions.push(TemplateDecision {
+            name: name.clone(),
+            decision: Decision::Resolved,
+            source: Some(template_location(&used)),
+            reason: match &shadowed {
+                Some(shadowed) => format!(
+                    "It's a {} template, and those are used before {} templates.",
+                    kind(&used),
+                    kind(shadowed)
+                ),
+                None => format!("It's a {} template.", kind(&used)),
+            },
+        });
+        if let Some(shadowed) = shadowed {
+            decisions.push(TemplateDecision {
+                name: name.clone(),
+                decision: Decision::Shadowed,
+                source: Some(template_location(&shadowed)),
+                reason: format!(
+                    "The {} template with the same name is used instead.",
+                    kind(&used)
+                ),
+            });
         }
-    };
+    }
 
-    let on_conflict = match (&inix_dir.state, cli.on_conflict) {
-        (_, Some(behavior)) => behavior,
-        (InixDirState::DoesNotExist, None) => ConflictBehavior::Cancel,
-        (InixDirState::AlreadyExists { .. }, None) => prompt_for_conflict_behavior(&inix_dir)?,
-    };
+    decisions
+}
 
-    // EXECUTE //
-    if cli.dry_run {
-        println!("So here's the plan:");
-        match inix_dir.state {
-            InixDirState::DoesNotExist => {
-                println!(
-                    r#"I will create the "{}" directory."#,
-                    inix_dir.path.display()
-                );
-                println!(
-                    r#"I will then add the {} template(s) to that directory."#,
-                    combine_strings(templates.iter().map(|t| t.name()))
-                );
-                let conflict_behavior = match on_conflict {
-                    ConflictBehavior::Overwrite => "completely overwrite the existing directory",
-                    ConflictBehavior::MergeKeep => {
-                        "merge the two directories, keeping existing files on collisions"
-                    }
-                    ConflictBehavior::MergeReplace => {
-                        "merge the two directories, replacing existing files on collisions"
-                    }
-                    ConflictBehavior::Cancel => "cancel the operation and exit",
-                };
-                println!(
-                    r#"If the directory were to be created in the meantime, I would "{}"."#,
-                    conflict_behavior
-                );
+/// The templates that come with inix. Their files are compiled in,
+/// but any of them can be replaced by a file at the same path in
+/// `builtin_dir` (e.g. `rust/shell.nix`), so that packagers can patch
+/// them without rebuilding inix.
+fn included_templates(builtin_dir: Option<&Path>) -> HashMap<&'static str, Template2> {
+    let file = |path, embedded| builtin_file(builtin_dir, path, embedded);
+    hash_map! {
+        "rust" => Template2 {
+            name: "rust".into(),
+            files: TemplateFiles2::Both {
+                nix: file("rust/shell.nix", include_str!("templates/rust/shell.nix")),
+                envrc: file("rust/.envrc", include_str!("templates/rust/.envrc")),
+            },
+            extra_files: vec![],
+            empty_dirs: vec![],
+            metadata: None,
+            source_dir: PathBuf::from("inix/templates"),
+            template_type: TemplateType::Builtin,
+        },
+        "node" => Template2 {
+            name: "node".into(),
+            files: TemplateFiles2::Both {
+                nix: file("node/shell.nix", include_str!("templates/node/shell.nix")),
+                envrc: file("node/.envrc", include_str!("templates/node/.envrc")),
+            },
+            extra_files: vec![],
+            empty_dirs: vec![],
+            metadata: None,
+            source_dir: PathBuf::from("inix/templates")
+                , template_type: TemplateType::Builtin
+        },
+        "flake" => Template2 {
+            name: "flake".into(),
+            files: TemplateFiles2::Flake {
+                flake: file("flake/flake.nix", include_str!("templates/flake/flake.nix")),
+                lock: None,
+                nix: None,
+                envrc: None,
+            },
+            extra_files: vec![],
+            empty_dirs: vec![],
+            metadata: None,
+            source_dir: PathBuf::from("inix/templates"),
+            template_type: TemplateType::Builtin,
+        },
+        "base" =>  Template2 {
+            name: "base".into(),
+            files: TemplateFiles2::Both {
+                nix: file("base/shell.nix.template", include_str!("templates/base/shell.nix.template")),
+              envrc: file("base/.envrc.template", include_str!("templates/base/.envrc.template")),
+            },
+            extra_files: vec![],
+            empty_dirs: vec![],
+            metadata: None,
+            source_dir: PathBuf::from("inix/templates"), template_type: TemplateType::Builtin
+        },
+    }
+}
+
+/// The contents of the builtin template file at `path` (relative to
+/// the `templates` directory): the file in `builtin_dir` if there is
+/// one and it can be read, and the compiled-in `embedded` contents
+/// otherwise.
+fn builtin_file(builtin_dir: Option<&Path>, path: &str, embedded: &str) -> String {
+    builtin_dir
+        .and_then(|dir| fs::read_to_string(dir.join(path)).ok())
+        .unwrap_or_else(|| embedded.to_string())
+}
+
+/// The directories that inix looks in that depend on the user's
+/// environment. They're passed around instead of being looked up
+/// where they're used, so that tests can point them somewhere else.
+#[derive(Clone, Debug)]
+struct Dirs {
+    /// The user's configuration directory. Custom templates live in
+    /// its `inix` subdirectory.
+    config: Option<PathBuf>,
+    /// Other directories with custom templates, which are looked in
+    /// (in order) before the config directory. Read from the
+    /// `INIX_TEMPLATE_PATH` environment variable.
+    templates: Vec<PathBuf>,
+    /// The user's cache directory. Clones of template repositories
+    /// live in its `inix` subdirectory.
+    cache: Option<PathBuf>,
+    /// The user's home directory. `--completions-dynamic` installs
+    /// completion scripts under it.
+    home: Option<PathBuf>,
+    /// A directory laid out like inix's own `templates` directory,
+    /// whose files are used instead of the builtin ones. Read from the
+    /// `INIX_BUILTIN_DIR` environment variable.
+    builtin: Option<PathBuf>,
+    /// Templates that were asked for along with the git repository
+    /// they're in, which are used instead of any other template with
+    /// the same name.
+    remote: Vec<RemoteTemplate>,
+    /// The template directories that were given with `--template-dir`.
+    /// Unlike the others, they can be a single template themselves
+    /// (see `single_template_name`).
+    single_template_dirs: Vec<PathBuf>,
+}
+
+/// A template that's taken from a directory in a git repository.
+#[derive(Clone, Debug)]
+struct RemoteTemplate {
+    /// The name that it's set up under.
+    name: String,
+    /// Where it comes from, as `<url>#<name>` (see
+    /// `remote_template_spec`).
+    source: String,
+    /// Its directory in the clone of the repository.
+    dir: PathBuf,
+}
+
+impl Default for Dirs {
+    fn default() -> Self {
+        Self {
+            config: dirs::config_dir(),
+            cache: dirs::cache_dir(),
+            home: dirs::home_dir(),
+            templates: std::env::var_os("INIX_TEMPLATE_PATH")
+                .map(|paths| {
+                    std::env::split_paths(&paths)
+                        .filter(|path| !path.as_os_str().is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            builtin: std::env::var_os("INIX_BUILTIN_DIR")
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from),
+            remote: vec![],
+            single_template_dirs: vec![],
+        }
+    }
+}
+
+impl Dirs {
+    /// The template called `name` that was asked for along with its
+    /// repository, if there is one.
+    fn remote_template(&self, name: &str) -> Option<&RemoteTemplate> {
+        self.remote.iter().find(|template| template.name == name)
+    }
+
+    /// All the directories that custom templates are looked for in,
+    /// in order of precedence, whether they exist or not.
+    fn template_dirs(&self) -> Vec<PathBuf> {
+        self.templates
+            .iter()
+            .cloned()
+            .chain(self.config.as_ref().map(|dir| dir.join("inix")))
+            .collect()
+    }
+
+    /// The `template_dirs` that exist.
+    fn existing_template_dirs(&self) -> Vec<PathBuf> {
+        self.template_dirs()
+            .into_iter()
+            .filter(|dir| dir.is_dir())
+            .collect()
+    }
+}
+
+/// The files that `inix init-config` puts in a new template directory,
+/// relative to that directory.
+const SAMPLE_CONFIG_FILES: [(&str, &str); 2] = [
+    (
+        "example/shell.nix",
+        include_str!("templates/example/shell.nix"),
+    ),
+    ("example/.envrc", include_str!("templates/example/.envrc")),
+];
+
+/// Create the directory for custom templates in the user's config
+/// directory, along with a sample template. Returns what was (or, if
+/// `dry_run`, would be) done, followed by some pointers on what to do
+/// next.
+fn init_config(dirs: &Dirs, force: bool, dry_run: bool) -> anyhow::Result<Vec<String>> {
+    let template_dir = dirs
+        .config
+        .as_ref()
+        .map(|dir| dir.join("inix"))
+        .ok_or_else(|| {
+            anyhow!("I was unable to find your user configuration directory, so I don't know where to put your templates.")
+        })?;
+
+    if template_dir.exists() && !force {
+        bail!(
+            r#"Your template directory ("{}") already exists, so I will leave it alone. If you want me to add the sample files to it anyway, please run me again with `--force`."#,
+            template_dir.display()
+        );
+    }
+
+    let mut lines = vec![];
+    for (file_name, contents) in SAMPLE_CONFIG_FILES {
+        let path = template_dir.join(file_name);
+        if dry_run {
+            lines.push(format!(r#"I would create "{}"."#, path.display()));
+            continue;
+        }
+
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).with_context(|| {
+                format!(
+                    r#"I was unable to create the directory "{}"."#,
+                    parent.display()
+                )
+            })?;
+        }
+        fs::write(&path, contents)
+            .with_context(|| format!(r#"I was unable to write "{}"."#, path.display()))?;
+        lines.push(format!(r#"I created "{}"."#, path.display()));
+    }
+
+    lines.push(formatdoc! {r#"
+
+        Every directory in "{}" is a template, named after the directory.
+        A template needs a `shell.nix`, an `.envrc`, or a `flake.nix`, and
+        any other files in it are copied along with them.
+
+        Next steps:
+        - Try the sample template with `inix example`.
+        - Copy or rename it to make your own templates.
+        - Run `inix --list` to see all the templates you can use."#,
+        template_dir.display()
+    });
+
+    Ok(lines)
+}
+
+/// Save the `shell.nix` and `.envrc` in `target_dir` as the custom
+/// template `name`. What happens to an existing template with the
+/// same name depends on `on_conflict`; without it, that's an error.
+/// Returns what was (or, if `dry_run`, would be) done.
+fn save_as_template(
+    name: &str,
+    target_dir: &Path,
+    dirs: &Dirs,
+    on_conflict: Option<ConflictBehavior>,
+    dry_run: bool,
+) -> anyhow::Result<Vec<String>> {
+    if !is_plain_name(name) {
+        bail!(
+            r#"I can't save a template called "{name}", because template names can't contain path separators or be "." or ".."."#
+        )
+    }
+
+    let template_dir = dirs
+        .config
+        .as_ref()
+        .map(|dir| dir.join("inix").join(name))
+        .ok_or_else(|| {
+            anyhow!("I was unable to find your user configuration directory, so I don't know where to save the template.")
+        })?;
+
+    let mut files = vec![];
+    for file_name in ["shell.nix", ".envrc"] {
+        let path = target_dir.join(file_name);
+        match fs::read_to_string(&path) {
+            Ok(contents) => files.push((file_name, contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!(r#"I was unable to read "{}"."#, path.display()))
             }
-            InixDirState::AlreadyExists {
-                ref template_collisions,
-            } => {
-                println!("{}", inix_dir.conflict_description());
+        }
+    }
+    if files.is_empty() {
+        bail!(
+            r#"There's no "shell.nix" or ".envrc" in "{}", so there's nothing to save as a template."#,
+            target_dir.display()
+        )
+    }
+
+    if template_dir.exists() {
+        match on_conflict {
+            None | Some(ConflictBehavior::Cancel) => bail!(
+                r#"There already is a template called "{name}" ("{}"), so I will leave it alone. If you want me to change it, please run me again with `--on-conflict <overwrite|merge-keep|merge-replace>`."#,
+                template_dir.display()
+            ),
+            Some(ConflictBehavior::MergeKeep) => {
+                files.retain(|(file_name, _)| !template_dir.join(file_name).exists())
+            }
+            Some(ConflictBehavior::MergeReplace) => {}
+            Some(ConflictBehavior::Overwrite) => {
+                if !dry_run {
+                    remove_dir_all(&template_dir).with_context(|| {
+                        format!(
+                            r#"I was unable to remove the existing template ("{}")."#,
+                            template_dir.display()
+                        )
+                    })?;
+                }
+            }
+        }
+    }
+
+    if !dry_run {
+        create_dir_all(&template_dir).with_context(|| {
+            format!(
+                r#"I was unable to create the template directory "{}"."#,
+                template_dir.display()
+            )
+        })?;
+    }
+
+    let mut lines = vec![];
+    for (file_name, contents) in files {
+        let source = target_dir.join(file_name);
+        let destination = template_dir.join(file_name);
+        if dry_run {
+            lines.push(format!(
+                r#"I would save "{}" as "{}"."#,
+                source.display(),
+                destination.display()
+            ));
+            continue;
+        }
+        fs::write(&destination, contents)
+            .with_context(|| format!(r#"I was unable to write "{}"."#, destination.display()))?;
+        lines.push(format!(
+            r#"I saved "{}" as "{}"."#,
+            source.display(),
+            destination.display()
+        ));
+    }
+
+    if !dry_run {
+        lines.push(format!(r#"You can now use it with `inix {name}`."#));
+    }
+
+    Ok(lines)
+}
+
+/// Make sure that none of the `templates` says (with `conflicts_with`
+/// in its inix.toml) that it can't be used with another one of them.
+fn ensure_no_conflicting_templates(templates: &[Template2]) -> anyhow::Result<()> {
+    let pairs: Vec<_> = templates
+        .iter()
+        .flat_map(|template| {
+            template
+                .conflicts_with()
+                .iter()
+                .filter(|other| {
+                    *other != template.name() && templates.iter().any(|t| t.name() == *other)
+                })
+                .map(|other| (template.name(), other.as_str()))
+        })
+        .collect();
+    // a pair that both templates mention is only reported once
+    let pairs: Vec<_> = pairs
+        .iter()
+        .filter(|(a, b)| a < b || !pairs.contains(&(b, a)))
+        .collect();
+    if pairs.is_empty() {
+        return Ok(());
+    }
+
+    bail!(
+        "These templates can't be used together:\n{}\n\nPlease choose one template from each pair.",
+        pairs
+            .iter()
+            .map(|(a, b)| format!(r#"- "{a}" and "{b}" ("{a}" says that it conflicts with "{b}")"#))
+            .join("\n")
+    )
+}
+
+fn try_get_templates(
+    input_templates: &[String],
+    precedence: TemplateDirPrecedence,
+    dirs: &Dirs,
+) -> anyhow::Result<Vec<Template2>> {
+    // template names are used as directory names, both when looking
+    // them up and when writing them, so they can't be allowed to point
+    // anywhere else.
+    let invalid_names: Vec<_> = input_templates
+        .iter()
+        .filter(|name| !is_plain_name(name))
+        .collect();
+    if !invalid_names.is_empty() {
+        bail!(
+            "These template names aren't allowed, because template names can't contain path separators or be \".\" or \"..\":\n{}",
+            invalid_names
+                .iter()
+                .map(|name| format!("- {name}"))
+                .join("\n")
+        )
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    enum DirErrorReason {
+        NotADir,
+        NoConfigDir,
+        NotFound,
+    }
+
+    #[derive(Clone, Debug)]
+    struct DirError {
+        path: PathBuf,
+        reason: DirErrorReason,
+    }
+
+    impl Display for DirError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{} ({})", self.path.display(), match self.reason {
+                                DirErrorReason::NotADir =>
+                                    "which exists, but is not a directory (it's probably a file!)",
+                                DirErrorReason::NoConfigDir =>
+                                    "but I don't know where your user configuration directory is (this probably means that you're not on Linux, macOS, or Windows)",
+                                DirErrorReason::NotFound => "but it doesn't exist",
+                            }
+)
+        }
+    }
+
+    // a prioritized list over where to find templates. Items listed earlier take precedence
+    let template_locations: Vec<_> = dirs
+        .templates
+        .iter()
+        .cloned()
+        .map(Ok)
+        .chain([dirs
+            .config
+            .as_ref()
+            .map(|dir| dir.join("inix"))
+            .ok_or(DirError {
+                path: PathBuf::from("<your user configuration directory>/inix"),
+                reason: DirErrorReason::NoConfigDir,
+            })])
+        .map(|result| {
+            result.and_then(|dir| {
+                if dir.is_dir() {
+                    Ok(dir)
+                } else {
+                    let reason = match dir.exists() {
+                        true => DirErrorReason::NotADir,
+                        false => DirErrorReason::NotFound,
+                    };
+                    Err(DirError {
+                        path: dir.clone(),
+                        reason,
+                    })
+                }
+            })
+        })
+        .collect();
+
+    let found_template_dirs: Vec<_> = template_locations
+        .iter()
+        .filter_map(|x| x.as_deref().map(|y| y.clone()).ok())
+        .collect();
+
+    #[derive(Debug)]
+    enum TemplateError {
+        NotFound(String),
+        Unreadable(anyhow::Error),
+    }
+
+    let (oks, errs): (Vec<_>, Vec<_>) = input_templates
+        .iter()
+        .map(|template_name| {
+            match dirs.remote_template(template_name) {
+                Some(remote) => read_custom_template(template_name, &remote.dir),
+                None => find_template(
+                    template_name,
+                    &found_template_dirs,
+                    &dirs.single_template_dirs,
+                    precedence,
+                    dirs.builtin.as_deref(),
+                ),
+            }
+            .map_err(TemplateError::Unreadable)
+            .and_then(|template| {
+                template.ok_or_else(|| TemplateError::NotFound(template_name.clone()))
+            })
+        })
+        .partition_result();
+
+    if errs.is_empty() {
+        ensure_no_conflicting_templates(&oks)?;
+        return Ok(oks);
+    }
+
+    let (not_found, unreadable): (Vec<_>, Vec<_>) = errs.into_iter().partition_map(|e| match e {
+        TemplateError::NotFound(name) => itertools::Either::Left(name),
+        TemplateError::Unreadable(e) => itertools::Either::Right(e),
+    });
+
+    let not_found_message = (!not_found.is_empty()).then(|| {
+        formatdoc!(
+            "
+            I couldn't find these templates:
+            {}
+
+            I looked (or tried to look) in these places:
+            {}",
+            not_found
+                .iter()
+                .map(|name| format!("- {}", name))
+                .join("\n"),
+            template_locations
+                .iter()
+                .map(|location| format!(
+                    "- {}",
+                    match location {
+                        Ok(l) => l.display().to_string(),
+                        Err(l) => l.to_string(),
+                    }
+                ))
+                .join("\n"),
+        )
+    });
+
+    let unreadable_message = (!unreadable.is_empty()).then(|| {
+        formatdoc!(
+            "
+            I found these templates, but I couldn't read them:
+            {}",
+            unreadable.iter().map(|e| format!("- {:#}", e)).join("\n"),
+        )
+    });
+
+    Err(anyhow!([unreadable_message, not_found_message]
+        .into_iter()
+        .flatten()
+        .join("\n\n")))
+}
+
+/// Whether `name` can be used as a single file or directory name:
+/// it can't be empty, contain path separators, or be "." or "..".
+fn is_plain_name(name: &str) -> bool {
+    !name.chars().any(std::path::is_separator)
+        && matches!(
+            Path::new(name).components().collect::<Vec<_>>().as_slice(),
+            [Component::Normal(_)]
+        )
+}
+
+/// Look for the template called `name` in the `custom_dirs` (in
+/// order) and among the builtin templates (see `included_templates`
+/// for `builtin_dir`). `precedence` decides which of the two is
+/// checked first. The custom directories that are also in
+/// `single_template_dirs` can be a template themselves (see
+/// `single_template_name`).
+fn find_template(
+    name: &str,
+    custom_dirs: &[&Path],
+    single_template_dirs: &[PathBuf],
+    precedence: TemplateDirPrecedence,
+    builtin_dir: Option<&Path>,
+) -> anyhow::Result<Option<Template2>> {
+    let custom = || {
+        custom_dirs
+            .iter()
+            .find_map(|location| {
+                let dir = if single_template_dirs.iter().any(|dir| dir == location)
+                    && single_template_name(location).as_deref() == Some(name)
+                {
+                    location.to_path_buf()
+                } else {
+                    location.join(name)
+                };
+                read_custom_template(name, &dir).transpose()
+            })
+            .transpose()
+    };
+    let builtin = || included_templates(builtin_dir).remove(name);
+
+    match precedence {
+        TemplateDirPrecedence::Before => Ok(custom()?.or_else(builtin)),
+        TemplateDirPrecedence::After => match builtin() {
+            Some(template) => Ok(Some(template)),
+            None => custom(),
+        },
+    }
+}
+
+/// Write the `files` of `template` into its own directory in
+/// `inix_dir`, creating any subdirectories the files are in. Returns
+/// the files and empty directories that were written.
+fn write_template(
+    template: &Template2,
+    files: &[(String, Vec<u8>)],
+    inix_dir: &Path,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut written = vec![];
+    let target = inix_dir.join(template.name());
+    create_dir_all(&target).with_context(|| {
+        format!(
+            r#"I was unable to create the template directory "{}"."#,
+            target.display()
+        )
+    })?;
+    for (file_name, contents) in files {
+        let file = target.join(file_name);
+        if let Some(parent) = file.parent() {
+            create_dir_all(parent).with_context(|| {
+                format!(
+                    r#"I was unable to create the directory "{}"."#,
+                    parent.display()
+                )
+            })?;
+        }
+        fs::write(&file, contents).with_context(|| {
+            format!(
+                r#"I was unable to write the "{}" template (found at "{}") to "{}"."#,
+                template.name(),
+                template.path().display(),
+                target.display()
+            )
+        })?;
+        written.push(file);
+    }
+    for dir_name in &template.empty_dirs {
+        let dir = target.join(dir_name);
+        create_dir_all(&dir).with_context(|| {
+            format!(
+                r#"I was unable to create the directory "{}"."#,
+                dir.display()
+            )
+        })?;
+        written.push(dir);
+    }
+    Ok(written)
+}
+
+/// If `dir` has a template's files directly in it (instead of having
+/// a subdirectory for each template), the name of that template,
+/// which is the name of the directory.
+fn single_template_name(dir: &Path) -> Option<String> {
+    read_custom_template_files(dir).ok()??;
+    Some(
+        dir.canonicalize()
+            .ok()?
+            .file_name()?
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+/// Read the custom template called `name` from `dir`, if there is
+/// one there.
+fn read_custom_template(name: &str, dir: &Path) -> anyhow::Result<Option<Template2>> {
+    let metadata = read_template_metadata(dir)?;
+    let Some(files) = read_custom_template_files(dir)? else {
+        if metadata.is_some() {
+            bail!(
+                r#"The "{name}" template ("{}") has an inix.toml, but none of the files that make up a template (a shell.nix, an .envrc, or a flake.nix)."#,
+                dir.display()
+            )
+        }
+        return Ok(None);
+    };
+
+    let (mut extra_files, mut empty_dirs) = read_extra_template_files(dir)?;
+    let overlay = metadata.as_ref().and_then(|m| m.overlay.as_ref());
+    if let Some(overlay) = overlay {
+        if !extra_files.iter().any(|(name, _)| name == overlay) {
+            bail!(
+                r#"The inix.toml of the "{name}" template ("{}") points to an overlay that isn't in the template: "{overlay}""#,
+                dir.display(),
+            )
+        }
+    }
+    if let Some(listed) = metadata.as_ref().and_then(|m| m.files.as_ref()) {
+        let missing: Vec<_> = listed
+            .iter()
+            .filter(|file| {
+                !extra_files.iter().any(|(name, _)| name == *file) && !empty_dirs.contains(file)
+            })
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                r#"The inix.toml of the "{name}" template ("{}") lists files that aren't in the template: {}"#,
+                dir.display(),
+                combine_strings(missing.into_iter())
+            )
+        }
+        extra_files
+            .retain(|(file_name, _)| listed.contains(file_name) || overlay == Some(file_name));
+        empty_dirs.retain(|dir_name| listed.contains(dir_name));
+    }
+
+    let template = Template2 {
+        name: name.to_owned(),
+        files,
+        extra_files,
+        empty_dirs,
+        metadata,
+        source_dir: dir.to_path_buf(),
+        template_type: TemplateType::Custom,
+    };
+
+    let file_names: Vec<_> = template
+        .files()
+        .into_iter()
+        .map(|(file_name, _)| file_name)
+        .collect();
+    let not_in_template: Vec<_> = template
+        .metadata
+        .iter()
+        .flat_map(|metadata| &metadata.verbatim)
+        .filter(|file| !file_names.contains(file))
+        .collect();
+    if !not_in_template.is_empty() {
+        bail!(
+            r#"The inix.toml of the "{name}" template ("{}") says to copy files verbatim that aren't in the template: {}"#,
+            dir.display(),
+            combine_strings(not_in_template.into_iter())
+        )
+    }
+
+    Ok(Some(template))
+}
+
+/// Read the `inix.toml` in a custom template's `dir`, if it has one.
+fn read_template_metadata(dir: &Path) -> anyhow::Result<Option<TemplateMetadata>> {
+    let path = dir.join("inix.toml");
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!(r#"I was unable to read "{}"."#, path.display()))
+        }
+    };
+    toml::from_str(&contents)
+        .map(Some)
+        .with_context(|| format!(r#"I was unable to understand "{}"."#, path.display()))
+}
+
+/// (relative path, contents) pairs for the files in a template that
+/// are copied as they are.
+type ExtraFiles = Vec<(String, Vec<u8>)>;
+
+/// Read every file in a custom template's `dir` (recursively) that
+/// isn't one of the files that inix knows what to do with (or its
+/// `inix.toml`), so that they can be copied along with the rest of
+/// the template, along with any empty directories, which would
+/// otherwise be lost. Paths are relative to `dir` and sorted, so the
+/// output is stable. Symlinks to directories aren't followed, so
+/// that a link back up the tree can't send this in circles.
+fn read_extra_template_files(dir: &Path) -> anyhow::Result<(ExtraFiles, Vec<String>)> {
+    fn walk(
+        root: &Path,
+        dir: &Path,
+        files: &mut ExtraFiles,
+        empty_dirs: &mut Vec<String>,
+    ) -> anyhow::Result<()> {
+        let entries = fs::read_dir(dir).with_context(|| {
+            format!(
+                r#"I was unable to read the template directory "{}"."#,
+                dir.display()
+            )
+        })?;
+        let mut entries = entries.peekable();
+        if entries.peek().is_none() && dir != root {
+            empty_dirs.push(dir.strip_prefix(root)?.to_string_lossy().into_owned());
+        }
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            let relative = path.strip_prefix(root)?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                if relative != Path::new(".git") {
+                    walk(root, &path, files, empty_dirs)?;
+                }
+            } else if file_type.is_symlink() && path.is_dir() {
+                continue;
+            } else if !matches!(
+                relative.to_str(),
+                Some("shell.nix" | ".envrc" | "flake.nix" | "flake.lock" | "inix.toml")
+            ) {
+                let contents = fs::read(&path)
+                    .with_context(|| format!(r#"I was unable to read "{}"."#, path.display()))?;
+                files.push((relative.to_string_lossy().into_owned(), contents));
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = vec![];
+    let mut empty_dirs = vec![];
+    walk(dir, dir, &mut files, &mut empty_dirs)?;
+    files.sort();
+    empty_dirs.sort();
+    Ok((files, empty_dirs))
+}
+
+/// Read the files of a custom template in `dir`. Files that don't
+/// exist are skipped, but any other problem reading one (missing
+/// permissions, contents that aren't UTF-8, ...) is reported as an
+/// error instead of being silently ignored.
+fn read_custom_template_files(dir: &Path) -> anyhow::Result<Option<TemplateFiles2>> {
+    let read = |file_name: &str| {
+        let path = dir.join(file_name);
+        match fs::read_to_string(&path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Err(e).with_context(|| {
+                format!(
+                    r#"I found "{}", but I don't have permission to read it"#,
+                    path.display()
+                )
+            }),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => Err(e).with_context(|| {
+                format!(
+                    r#"I found "{}", but it isn't valid UTF-8, so I can't use it as a template file"#,
+                    path.display()
+                )
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!(r#"I was unable to read "{}""#, path.display())),
+        }
+    };
+
+    if let Some(flake) = read("flake.nix")? {
+        return Ok(Some(TemplateFiles2::Flake {
+            flake,
+            lock: read("flake.lock")?,
+            nix: read("shell.nix")?,
+            envrc: read(".envrc")?,
+        }));
+    }
+
+    Ok(match (read("shell.nix")?, read(".envrc")?) {
+        (None, None) => None,
+        (Some(nix), None) => Some(TemplateFiles2::Nix(nix)),
+        (None, Some(envrc)) => Some(TemplateFiles2::Envrc(envrc)),
+        (Some(nix), Some(envrc)) => Some(TemplateFiles2::Both { nix, envrc }),
+    })
+}
+
+#[derive(Clone, Debug)]
+enum TemplateCollisions<'a> {
+    None,
+    All(NonEmpty<&'a str>),
+    Some(NonEmpty<&'a str>),
+}
+
+#[derive(Debug, Clone)]
+enum InixDirState<'a> {
+    DoesNotExist,
+    AlreadyExists {
+        template_collisions: TemplateCollisions<'a>,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct InixDir<'a> {
+    path: &'a PathBuf,
+    state: InixDirState<'a>,
+}
+
+impl<'a> InixDir<'a> {
+    fn conflict_description(&self) -> String {
+        self.describe_conflict(Colors::default())
+    }
+
+    /// The conflict description, with the template names highlighted.
+    fn describe_conflict(&self, colors: Colors) -> String {
+        let names = |conflicts: &NonEmpty<&str>| {
+            let names: Vec<_> = conflicts.iter().map(|name| colors.template(name)).collect();
+            combine_strings(names.into_iter())
+        };
+        match &self.state {
+            InixDirState::DoesNotExist => format!(
+                r#"The inix directory ({}) does not exist."#,
+                self.path.display()
+            ),
+            InixDirState::AlreadyExists {
+                template_collisions,
+            } => match template_collisions {
+                TemplateCollisions::None => format!(
+                    r#"
+            The inix directory ("{}") already exists, but none of the new templates conflict with existing subdirectories."#,
+                    self.path.display()
+                ),
+                TemplateCollisions::All(conflicts) => format!(
+                    r#"The inix directory ("{}") already exists, and it contains all of the templates that you're trying to add ({})."#,
+                    self.path.display(),
+                    names(conflicts)
+                ),
+                TemplateCollisions::Some(conflicts) => format!(
+                    r#"The inix directory ("{}") already exists, and the following templates you're trying to add already exist in the inix directory: {}."#,
+                    self.path.display(),
+                    names(conflicts)
+                ),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ConflictResolution {
+    /// Handle conflicts like this.
+    Use(ConflictBehavior),
+    /// Ask the user what to do.
+    Prompt,
+    /// There's a conflict, but nobody to ask about it, so fall back to
+    /// cancelling. Because that's easy to miss (especially in
+    /// scripts), the user should be told why.
+    CancelWithWarning(String),
+}
+
+/// Work out how to handle a (potential) conflict with an existing
+/// inix directory. `interactive` says whether we're able to prompt
+/// the user.
+fn resolve_conflict_behavior(
+    inix_dir: &InixDir,
+    chosen: Option<ConflictBehavior>,
+    interactive: bool,
+) -> ConflictResolution {
+    match (&inix_dir.state, chosen) {
+        (_, Some(behavior)) => ConflictResolution::Use(behavior),
+        (InixDirState::DoesNotExist, None) => ConflictResolution::Use(ConflictBehavior::Cancel),
+        (InixDirState::AlreadyExists { .. }, None) if interactive => ConflictResolution::Prompt,
+        (InixDirState::AlreadyExists { .. }, None) => {
+            ConflictResolution::CancelWithWarning(formatdoc!(
+                "
+                Warning: {}
+
+                You haven't told me what to do about this with `--on-conflict`, and I can't ask you, because I'm not running in a terminal. I'll fall back to the default (`--on-conflict cancel`), so I won't change anything in the inix directory.
+
+                If you want me to do something else, please run me again with `--on-conflict <overwrite|merge-keep|merge-replace|cancel>`.",
+                inix_dir.conflict_description().trim()
+            ))
+        }
+    }
+}
+
+/// The entries in the inix directory that don't belong to any
+/// template: neither to one of `templates` nor to any other template
+/// that inix knows about. Inside a template's directory, anything that
+/// isn't one of that template's files or directories doesn't belong to
+/// it either. Overwriting the inix directory would delete them.
+fn unexpected_inix_entries(
+    inix_dir: &Path,
+    templates: &[Template2],
+    dirs: &Dirs,
+    precedence: TemplateDirPrecedence,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let existing_dirs = dirs.existing_template_dirs();
+    let custom_dirs: Vec<_> = existing_dirs.iter().map(PathBuf::as_path).collect();
+    let known_names = template_names(&custom_dirs, &dirs.single_template_dirs)?;
+
+    let entries = fs::read_dir(inix_dir).with_context(|| {
+        format!(
+            r#"I was unable to read the inix directory ("{}")."#,
+            inix_dir.display()
+        )
+    })?;
+
+    let mut unexpected = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let is_template = templates.iter().any(|t| t.name() == name) || known_names.contains(&name);
+        // created by --allow-empty, and for templates from git
+        // repositories
+        let is_inix_file = name == ".gitkeep" || name == TEMPLATE_SOURCES_FILE;
+        if !is_template && !is_inix_file {
+            unexpected.push(entry.path());
+            continue;
+        }
+
+        // symlinked template directories are replaced, not followed
+        if !is_template || !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let template = match templates.iter().find(|t| t.name() == name) {
+            Some(template) => Some(template.clone()),
+            // if the template can't be read, there's no telling which
+            // files are its own
+            None => find_template(
+                &name,
+                &custom_dirs,
+                &dirs.single_template_dirs,
+                precedence,
+                dirs.builtin.as_deref(),
+            )
+            .ok()
+            .flatten(),
+        };
+        if let Some(template) = template {
+            unexpected_template_entries(&entry.path(), &template, &mut unexpected)?;
+        }
+    }
+    unexpected.sort();
+    Ok(unexpected)
+}
+
+/// Add the entries in `template_dir` (a copy of `template` in the inix
+/// directory) that aren't among the template's files or directories to
+/// `unexpected`.
+fn unexpected_template_entries(
+    template_dir: &Path,
+    template: &Template2,
+    unexpected: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    let files: HashSet<_> = template
+        .files()
+        .into_iter()
+        .map(|(file_name, _)| PathBuf::from(file_name))
+        .collect();
+    let dirs: HashSet<_> = files
+        .iter()
+        .flat_map(|file| file.ancestors().skip(1))
+        .map(Path::to_path_buf)
+        .chain(template.empty_dirs.iter().map(PathBuf::from))
+        .collect();
+
+    fn walk(
+        root: &Path,
+        dir: &Path,
+        files: &HashSet<PathBuf>,
+        dirs: &HashSet<PathBuf>,
+        unexpected: &mut Vec<PathBuf>,
+    ) -> anyhow::Result<()> {
+        let entries = fs::read_dir(dir).with_context(|| {
+            format!(r#"I was unable to read the directory "{}"."#, dir.display())
+        })?;
+        for entry in entries {
+            let path = entry?.path();
+            let relative = path.strip_prefix(root)?;
+            if path.symlink_metadata()?.is_dir() && dirs.contains(relative) {
+                walk(root, &path, files, dirs, unexpected)?;
+            } else if !files.contains(relative) {
+                unexpected.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    walk(template_dir, template_dir, &files, &dirs, unexpected)
+}
+
+/// Whether the existing directory at `inix_dir` looks like something
+/// inix has written to: it's empty, it has the `.gitkeep` that
+/// `--allow-empty` creates, or one of the directories in it is named
+/// after a template or has template files in it. Anything else is
+/// probably an unrelated directory that just happens to have the same
+/// name.
+fn looks_like_inix_dir(
+    inix_dir: &Path,
+    templates: &[Template2],
+    dirs: &Dirs,
+) -> anyhow::Result<bool> {
+    let existing_dirs = dirs.existing_template_dirs();
+    let custom_dirs: Vec<_> = existing_dirs.iter().map(PathBuf::as_path).collect();
+    let known_names = template_names(&custom_dirs, &dirs.single_template_dirs)?;
+
+    let entries: Vec<_> = fs::read_dir(inix_dir)
+        .and_then(|entries| entries.collect::<Result<_, _>>())
+        .with_context(|| {
+            format!(
+                r#"I was unable to read the inix directory ("{}")."#,
+                inix_dir.display()
+            )
+        })?;
+
+    Ok(entries.is_empty()
+        || entries.iter().any(|entry| {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            let is_template = templates.iter().any(|t| t.name() == name)
+                || known_names.contains(&name)
+                || ["shell.nix", ".envrc", "flake.nix"]
+                    .iter()
+                    .any(|file| path.join(file).is_file());
+            name == ".gitkeep" || (path.is_dir() && is_template)
+        }))
+}
+
+/// When merging into an existing inix directory, each template is
+/// written to a subdirectory named after it. Make sure that none of
+/// those already exist as something other than a directory, which we
+/// wouldn't be able to write into.
+fn ensure_template_dirs_are_not_files(
+    inix_dir: &Path,
+    templates: &[Template2],
+) -> anyhow::Result<()> {
+    let blocked: Vec<_> = templates
+        .iter()
+        .map(|template| inix_dir.join(template.name()))
+        // symlinks are treated like directories: kept when merging and
+        // keeping, and replaced (but not followed) otherwise
+        .filter(|path| path.symlink_metadata().is_ok_and(|m| m.is_file()))
+        .collect();
+
+    if !blocked.is_empty() {
+        bail!(
+            "I can't add these templates to the inix directory, because there are files where their directories should be:\n{}\n\nPlease move or remove them and try again, or use `--on-conflict overwrite` to replace the whole inix directory.",
+            blocked
+                .iter()
+                .map(|path| format!("- {}", path.display()))
+                .join("\n")
+        )
+    }
+
+    Ok(())
+}
+
+/// Whether one of the top-level files (`shell.nix`, `.envrc`, or
+/// `flake.nix`) at `path` should be written, given how conflicts
+/// should be handled. Files that don't exist yet are created unless
+/// we're cancelling because the inix directory already exists. When
+/// merging and keeping, existing files are backed up first (see
+/// `backup_path`).
+fn should_write_base_file(
+    path: &Path,
+    on_conflict: ConflictBehavior,
+    inix_dir_exists: bool,
+) -> bool {
+    match on_conflict {
+        ConflictBehavior::Overwrite
+        | ConflictBehavior::MergeReplace
+        | ConflictBehavior::MergeKeep => true,
+        ConflictBehavior::Cancel => !inix_dir_exists && !path.exists(),
+    }
+}
+
+/// The top-level files that already exist in `target_dir` and that
+/// won't be written, given how conflicts should be handled. Nothing
+/// else says that they were left alone, so inix warns about them.
+fn skipped_base_files(
+    target_dir: &Path,
+    cli: &Cli,
+    on_conflict: ConflictBehavior,
+    inix_dir_exists: bool,
+) -> Vec<PathBuf> {
+    [
+        ("shell.nix", !cli.flake),
+        (".envrc", true),
+        ("flake.nix", cli.emit_flake),
+    ]
+    .into_iter()
+    .map(|(file_name, wanted)| (target_dir.join(file_name), wanted))
+    .filter(|(path, wanted)| {
+        *wanted && path.exists() && !should_write_base_file(path, on_conflict, inix_dir_exists)
+    })
+    .map(|(path, _)| path)
+    .collect()
+}
+
+/// The templates that the inix directory will have once `templates`
+/// have been written to it with `on_conflict`, sorted by name: when
+/// merging, that's the ones that are there already as well as the new
+/// ones. The base files are rendered from these, so that they pull in
+/// everything and not just what was added this time. A template in
+/// the inix directory with a broken inix.toml is used without it, and
+/// a warning about it is returned.
+fn final_templates(
+    inix_dir: &Path,
+    templates: &[Template2],
+    on_conflict: ConflictBehavior,
+) -> anyhow::Result<(Vec<Template2>, Vec<String>)> {
+    let mut existing = vec![];
+    let mut warnings = vec![];
+    if let ConflictBehavior::MergeKeep | ConflictBehavior::MergeReplace = on_conflict {
+        if let Ok(entries) = fs::read_dir(inix_dir) {
+            for entry in entries {
+                let path = entry
+                    .with_context(|| format!(r#"I was unable to read "{}"."#, inix_dir.display()))?
+                    .path();
+                let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                    continue;
+                };
+                if !path.is_dir() {
+                    continue;
+                }
+                match read_custom_template(name, &path) {
+                    Ok(template) => existing.extend(template),
+                    Err(error) => {
+                        let files = read_custom_template_files(&path).ok().flatten();
+                        warnings.push(match files {
+                            Some(_) => format!(
+                                r#"Warning: {error:#}\n\nI'll use the "{name}" template without its inix.toml."#
+                            ),
+                            None => format!(
+                                r#"Warning: {error:#}\n\nI'll leave the "{name}" template out of the top-level files."#
+                            ),
+                        });
+                        existing.extend(files.map(|files| Template2 {
+                            name: name.to_string(),
+                            files,
+                            extra_files: vec![],
+                            empty_dirs: vec![],
+                            metadata: None,
+                            source_dir: path.clone(),
+                            template_type: TemplateType::Custom,
+                        }));
+                    }
+                }
+            }
+        }
+    }
+
+    // when keeping, the templates that are there already win, and when
+    // replacing, the new ones do
+    let (first, second) = match on_conflict {
+        ConflictBehavior::MergeKeep => (existing, templates.to_vec()),
+        _ => (templates.to_vec(), existing),
+    };
+    let mut all: Vec<Template2> = vec![];
+    for template in first.into_iter().chain(second) {
+        if !all.iter().any(|other| other.name() == template.name()) {
+            all.push(template);
+        }
+    }
+    all.sort_by(|a, b| a.name().cmp(b.name()));
+
+    Ok((all, warnings))
+}
+
+/// The next free backup generation for the file at `path`: if
+/// `shell.nix.1`, `shell.nix.2`, and `shell.nix.7` exist next to
+/// `shell.nix`, that's `shell.nix.8`. Gaps are never filled, so the
+/// highest number is always the most recent backup.
+fn backup_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!(r#"I can't back up "{}"."#, path.display()))?;
+    let parent = path.parent().unwrap_or_else(|| Path::new("."));
+
+    let latest = fs::read_dir(parent)
+        .with_context(|| format!(r#"I was unable to read "{}"."#, parent.display()))?
+        .filter_map(|entry| {
+            entry
+                .ok()?
+                .file_name()
+                .to_str()?
+                .strip_prefix(file_name)?
+                .strip_prefix('.')?
+                .parse::<u32>()
+                .ok()
+        })
+        .max()
+        .unwrap_or(0);
+
+    Ok(parent.join(format!("{file_name}.{}", latest + 1)))
+}
+
+/// A lock on a target directory, so that two inix runs don't write to
+/// it at the same time. It's a file that only one run can create, and
+/// it's removed again when the lock is dropped, along with the target
+/// directory if the run created it and left it empty.
+struct RunLock {
+    path: PathBuf,
+    created_dir: Option<PathBuf>,
+}
+
+impl RunLock {
+    const FILE_NAME: &'static str = ".inix.lock";
+
+    fn acquire(target_dir: &Path, created_dir: bool) -> anyhow::Result<Self> {
+        let path = target_dir.join(Self::FILE_NAME);
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => {
+                // for whoever finds it
+                let _ = writeln!(file, "{}", std::process::id());
+                Ok(RunLock {
+                    path,
+                    created_dir: created_dir.then(|| target_dir.to_path_buf()),
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => bail!(
+                "Another inix run is in progress in \"{}\", so I'll stop here instead of getting in its way.\n\nIf there's no other run, an earlier one was probably interrupted: delete \"{}\" and try again, or run me with `--no-lock`.",
+                target_dir.display(),
+                path.display()
+            ),
+            Err(e) => Err(e)
+                .with_context(|| format!(r#"I was unable to create the lock file "{}"."#, path.display())),
+        }
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        if let Some(dir) = &self.created_dir {
+            // only succeeds if nothing was written
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+/// Everything a run writes, put together in temporary directories
+/// next to where it's going, so that nothing in the project changes
+/// until all of it has been written. See `Staging::commit`.
+#[derive(Default)]
+struct Staging {
+    /// The directory the new inix directory is put together in (as
+    /// `inix`), and where it goes.
+    inix_dir: Option<(tempfile::TempDir, PathBuf)>,
+    /// The directory the top-level files are written to before
+    /// they're moved into the project.
+    files_dir: Option<tempfile::TempDir>,
+    files: Vec<StagedFile>,
+    /// Everything that's been written to the staged inix directory,
+    /// where it'll end up.
+    inix_entries: Vec<PlannedFile>,
+}
+
+/// A top-level file that's waiting in `Staging::files_dir`.
+struct StagedFile {
+    name: String,
+    path: PathBuf,
+    /// Whether an existing file at `path` should be kept as a backup
+    /// (see `backup_path`) instead of being replaced.
+    back_up: bool,
+}
+
+/// Something `Staging::commit` moved into place, and where whatever
+/// was there before was moved to, so that it can be put back.
+struct Moved {
+    path: PathBuf,
+    previous: Option<PathBuf>,
+}
+
+/// Remove whatever is at `path`, if anything: a directory along with
+/// everything in it, or a file or symlink (without following it).
+fn remove_entry(path: &Path) -> anyhow::Result<()> {
+    let removed = match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    };
+    removed.with_context(|| format!(r#"I was unable to remove "{}"."#, path.display()))
+}
+
+impl Moved {
+    fn undo(self) -> anyhow::Result<()> {
+        remove_entry(&self.path)?;
+        if let Some(previous) = self.previous {
+            fs::rename(&previous, &self.path).with_context(|| {
+                format!(
+                    r#"I was unable to move "{}" back to "{}"."#,
+                    previous.display(),
+                    self.path.display()
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// What `Staging::commit` moved into place. Until it's dropped, it
+/// can still be rolled back: whatever it replaced is kept around
+/// until then.
+struct Committed {
+    staging: Staging,
+    moved: Vec<Moved>,
+    /// Every file (and empty directory) that was written, in the
+    /// order it was written in.
+    written: Vec<PlannedFile>,
+}
+
+impl Committed {
+    /// The top-level files that were written.
+    fn written_files(&self) -> Vec<PathBuf> {
+        self.staging
+            .files
+            .iter()
+            .map(|file| file.path.clone())
+            .collect()
+    }
+
+    /// Remember that `path` was created after everything was moved
+    /// into place (by something other than inix), so that it's
+    /// removed when rolling back too.
+    fn track_created(&mut self, path: PathBuf) {
+        self.moved.push(Moved {
+            path,
+            previous: None,
+        })
+    }
+
+    /// Undo everything, newest first, and describe what was done.
+    /// Things that can't be undone are described too, but don't stop
+    /// the rest from being undone.
+    fn roll_back(self) -> Vec<String> {
+        self.moved
+            .into_iter()
+            .rev()
+            .map(|step| {
+                let description = match &step.previous {
+                    Some(_) => format!(
+                        r#"I put back what was at "{}" before."#,
+                        step.path.display()
+                    ),
+                    None => format!(r#"I removed "{}"."#, step.path.display()),
+                };
+                match step.undo() {
+                    Ok(()) => description,
+                    Err(error) => format!("{error:#}"),
+                }
+            })
+            .collect()
+    }
+}
+
+/// A temporary directory in the directory that `path` is in, so that
+/// things can be renamed from it to `path`.
+fn staging_dir_for(path: &Path) -> anyhow::Result<tempfile::TempDir> {
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    tempfile::Builder::new()
+        .prefix(".inix-")
+        .tempdir_in(parent)
+        .with_context(|| {
+            format!(
+                r#"I was unable to create a temporary directory in "{}"."#,
+                parent.display()
+            )
+        })
+}
+
+/// Copy the directory at `from`, and everything in it, to `to`.
+/// Symlinks are copied as symlinks where that's possible.
+fn copy_dir_all(from: &Path, to: &Path) -> anyhow::Result<()> {
+    create_dir_all(to).with_context(|| format!(r#"I was unable to create "{}"."#, to.display()))?;
+    let entries = fs::read_dir(from)
+        .with_context(|| format!(r#"I was unable to read "{}"."#, from.display()))?;
+    for entry in entries {
+        let entry =
+            entry.with_context(|| format!(r#"I was unable to read "{}"."#, from.display()))?;
+        let (source, target) = (entry.path(), to.join(entry.file_name()));
+        #[cfg(unix)]
+        if source.is_symlink() {
+            let link = fs::read_link(&source)
+                .with_context(|| format!(r#"I was unable to read "{}"."#, source.display()))?;
+            std::os::unix::fs::symlink(&link, &target).with_context(|| {
+                format!(
+                    r#"I was unable to copy "{}" to "{}"."#,
+                    source.display(),
+                    target.display()
+                )
+            })?;
+            continue;
+        }
+        if source.is_dir() {
+            copy_dir_all(&source, &target)?;
+        } else {
+            fs::copy(&source, &target).with_context(|| {
+                format!(
+                    r#"I was unable to copy "{}" to "{}"."#,
+                    source.display(),
+                    target.display()
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+impl Staging {
+    /// Start a new inix directory that'll replace the one at `path`
+    /// (if there is one), starting from a copy of the existing one if
+    /// `copy_existing` is set. Returns the directory to write to.
+    fn stage_inix_dir(&mut self, path: &Path, copy_existing: bool) -> anyhow::Result<PathBuf> {
+        let staging = staging_dir_for(path)?;
+        let staged = staging.path().join("inix");
+        if copy_existing && path.is_dir() {
+            copy_dir_all(path, &staged)?;
+        } else {
+            create_dir_all(&staged).with_context(|| {
+                format!(
+                    r#"I was unable to create the inix directory "{}"."#,
+                    staged.display()
+                )
+            })?;
+        }
+        self.inix_dir = Some((staging, path.to_path_buf()));
+        Ok(staged)
+    }
+
+    /// Remember that the files and directories at `staged_paths` were
+    /// written to the staged inix directory (see `stage_inix_dir`).
+    fn track_inix_entries(&mut self, staged_paths: impl IntoIterator<Item = PathBuf>) {
+        let Some((staging, path)) = &self.inix_dir else {
+            return;
+        };
+        let staged = staging.path().join("inix");
+        for staged_path in staged_paths {
+            if let Ok(relative) = staged_path.strip_prefix(&staged) {
+                let path = path.join(relative);
+                self.inix_entries.push(PlannedFile {
+                    action: match path.exists() {
+                        true => FileAction::Overwrite,
+                        false => FileAction::Create,
+                    },
+                    path,
+                    backup: None,
+                });
+            }
+        }
+    }
+
+    /// Write `contents` to a file that'll end up at `path`.
+    fn stage_file(&mut self, path: &Path, contents: &str, back_up: bool) -> anyhow::Result<()> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!(r#"I can't write "{}"."#, path.display()))?
+            .to_string();
+        let files_dir = match &self.files_dir {
+            Some(files_dir) => files_dir,
+            None => self.files_dir.insert(staging_dir_for(path)?),
+        };
+        let staged = files_dir.path().join(&name);
+        fs::write(&staged, contents)
+            .with_context(|| format!(r#"I was unable to write "{}"."#, staged.display()))?;
+        self.files.push(StagedFile {
+            name,
+            path: path.to_path_buf(),
+            back_up,
+        });
+        Ok(())
+    }
+
+    /// Move everything that's been staged into place. If any of it
+    /// can't be moved, everything that already was is put back the
+    /// way it was.
+    fn commit(mut self) -> anyhow::Result<Committed> {
+        let mut moved = vec![];
+        let mut written = std::mem::take(&mut self.inix_entries);
+        match self.move_into_place(&mut moved, &mut written) {
+            Ok(()) => Ok(Committed {
+                staging: self,
+                moved,
+                written,
+            }),
+            Err(error) => {
+                for step in moved.into_iter().rev() {
+                    let _ = step.undo();
+                }
+                Err(error)
+            }
+        }
+    }
+
+    fn move_into_place(
+        &self,
+        moved: &mut Vec<Moved>,
+        written: &mut Vec<PlannedFile>,
+    ) -> anyhow::Result<()> {
+        // whatever is replaced is moved into the staging directories,
+        // where it's deleted along with them once we're done.
+        if let Some((staging, path)) = &self.inix_dir {
+            let previous = path.is_dir().then(|| staging.path().join("previous"));
+            moved.push(replace(&staging.path().join("inix"), path, previous)?);
+        }
+
+        if let Some(files_dir) = &self.files_dir {
+            for file in &self.files {
+                // only files are ever replaced: if there's a
+                // directory in the way, moving the new file fails
+                let previous = match file.path.is_file() {
+                    true if file.back_up => Some(backup_path(&file.path)?),
+                    true => Some(files_dir.path().join(format!("{}.previous", file.name))),
+                    false => None,
+                };
+                written.push(PlannedFile {
+                    path: file.path.clone(),
+                    action: match &previous {
+                        None => FileAction::Create,
+                        Some(_) if file.back_up => FileAction::Create,
+                        Some(_) if file.name == ".envrc" => FileAction::Update,
+                        Some(_) => FileAction::Overwrite,
+                    },
+                    backup: previous.clone().filter(|_| file.back_up),
+                });
+                moved.push(replace(
+                    &files_dir.path().join(&file.name),
+                    &file.path,
+                    previous,
+                )?);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Move `new` to `path`, first moving whatever is at `path` to
+/// `previous` (if given).
+fn replace(new: &Path, path: &Path, previous: Option<PathBuf>) -> anyhow::Result<Moved> {
+    if let Some(previous) = &previous {
+        fs::rename(path, previous).with_context(|| {
+            format!(
+                r#"I was unable to move "{}" to "{}"."#,
+                path.display(),
+                previous.display()
+            )
+        })?;
+    }
+    if let Err(error) = fs::rename(new, path) {
+        if let Some(previous) = &previous {
+            let _ = fs::rename(previous, path);
+        }
+        return Err(error)
+            .with_context(|| format!(r#"I was unable to write "{}"."#, path.display()));
+    }
+    Ok(Moved {
+        path: path.to_path_buf(),
+        previous,
+    })
+}
+
+/// What `--dry-run` reports: everything inix would do with these
+/// arguments. `--format json` prints it as it is, and `--format text`
+/// describes it step by step (see `Plan::steps`).
+#[derive(Debug, Serialize)]
+struct Plan {
+    target_dir: PathBuf,
+    inix_dir: PlannedInixDir,
+    on_conflict: ConflictBehavior,
+    templates: Vec<PlannedTemplate>,
+    /// Directories that would be removed before anything is written.
+    removed: Vec<PathBuf>,
+    /// The files that would be created or changed.
+    files: Vec<PlannedFile>,
+    /// The commands that would be run for `--var-from-command`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    commands: Vec<PlannedCommand>,
+    /// The `--template-repo` repositories, which are used as they
+    /// were last cloned instead of being cloned or updated.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    cached_repos: Vec<String>,
+    /// Whether `git init` would be run in the target directory.
+    git_init: bool,
+    /// Whether `direnv allow` would be run in the target directory.
+    direnv_allow: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct PlannedCommand {
+    variable: String,
+    command: String,
+}
+
+#[derive(Debug, Serialize)]
+struct PlannedInixDir {
+    path: PathBuf,
+    exists: bool,
+    /// The templates that already have a directory in it.
+    conflicting_templates: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct PlannedTemplate {
+    name: String,
+    template_type: TemplateType,
+    /// Where the template is read from: see `template_location`.
+    source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+struct PlannedFile {
+    path: PathBuf,
+    action: FileAction,
+    /// Where the existing file would be moved to first, if anywhere.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum FileAction {
+    Create,
+    Overwrite,
+    /// Only the part of the file that inix manages is replaced.
+    Update,
+}
+
+/// Work out everything that inix would do with these arguments,
+/// without doing any of it.
+fn plan(
+    cli: &Cli,
+    target_dir: &Path,
+    inix_dir: &InixDir,
+    templates: &[Template2],
+    on_conflict: ConflictBehavior,
+) -> Plan {
+    let conflicting_templates: Vec<String> = match &inix_dir.state {
+        InixDirState::AlreadyExists {
+            template_collisions: TemplateCollisions::Some(ts) | TemplateCollisions::All(ts),
+        } => ts.iter().map(|t| t.to_string()).collect(),
+        _ => vec![],
+    };
+    let inix_dir_exists = matches!(inix_dir.state, InixDirState::AlreadyExists { .. });
+
+    let copied_templates: Vec<_> = match on_conflict {
+        ConflictBehavior::Cancel if inix_dir_exists => vec![],
+        ConflictBehavior::MergeKeep => templates
+            .iter()
+            .filter(|t| !conflicting_templates.iter().any(|name| name == t.name()))
+            .collect(),
+        _ => templates.iter().collect(),
+    };
+
+    let file_action = |path: &Path| {
+        if path.exists() {
+            FileAction::Overwrite
+        } else {
+            FileAction::Create
+        }
+    };
+
+    let mut files: Vec<PlannedFile> = copied_templates
+        .iter()
+        .flat_map(|template| {
+            template
+                .files()
+                .into_iter()
+                .map(|(file_name, _)| file_name)
+                .chain(template.empty_dirs.iter().cloned())
+                .map(|file_name| {
+                    let path = inix_dir.path.join(template.name()).join(file_name);
+                    PlannedFile {
+                        action: file_action(&path),
+                        path,
+                        backup: None,
+                    }
+                })
+        })
+        .collect();
+    if cli.allow_empty && templates.is_empty() && !inix_dir_exists {
+        files.push(PlannedFile {
+            path: inix_dir.path.join(".gitkeep"),
+            action: FileAction::Create,
+            backup: None,
+        });
+    }
+
+    let base_files = [
+        (target_dir.join("shell.nix"), !cli.flake),
+        (target_dir.join(".envrc"), true),
+        (target_dir.join("flake.nix"), cli.emit_flake),
+    ];
+    for (path, wanted) in base_files {
+        if !wanted || !should_write_base_file(&path, on_conflict, inix_dir_exists) {
+            continue;
+        }
+        // only the part of an `.envrc` that inix manages is updated if
+        // it has one, so there's nothing to back up
+        let updates_managed_block = path.ends_with(".envrc")
+            && fs::read_to_string(&path).is_ok_and(|existing| has_managed_block(&existing));
+        let backup = match on_conflict {
+            ConflictBehavior::MergeKeep if path.exists() && !updates_managed_block => {
+                backup_path(&path).ok()
+            }
+            _ => None,
+        };
+        let action = match file_action(&path) {
+            _ if backup.is_some() => FileAction::Create,
+            FileAction::Overwrite if path.ends_with(".envrc") => FileAction::Update,
+            action => action,
+        };
+        files.push(PlannedFile {
+            path,
+            action,
+            backup,
+        });
+    }
+
+    Plan {
+        target_dir: target_dir.to_path_buf(),
+        inix_dir: PlannedInixDir {
+            path: inix_dir.path.to_path_buf(),
+            exists: inix_dir_exists,
+            conflicting_templates,
+        },
+        on_conflict,
+        removed: match on_conflict {
+            ConflictBehavior::Overwrite if inix_dir_exists => vec![inix_dir.path.to_path_buf()],
+            _ => vec![],
+        },
+        templates: templates
+            .iter()
+            .map(|template| PlannedTemplate {
+                name: template.name().to_string(),
+                template_type: template.template_type,
+                source: template_location(template),
+                description: template.description().map(String::from),
+            })
+            .collect(),
+        files,
+        commands: cli
+            .variable_commands
+            .iter()
+            .map(|(variable, command)| PlannedCommand {
+                variable: variable.clone(),
+                command: command.clone(),
+            })
+            .collect(),
+        cached_repos: cli.template_repos.clone(),
+        git_init: cli.git_init && !target_dir.join(".git").exists(),
+        direnv_allow: cli.auto_allow,
+    }
+}
+
+impl Plan {
+    /// The plan as a step-by-step description, for `--format text`.
+    /// It's made from nothing but the plan itself, so that it always
+    /// says the same thing as `--format json`.
+    fn steps(&self) -> Vec<String> {
+        let mut steps = vec!["So here's the plan:".to_string()];
+        for url in &self.cached_repos {
+            steps.push(format!(
+                r#"I haven't cloned or updated "{url}" for this, so I'm using its templates from the last time it was used, if any."#
+            ));
+        }
+        for PlannedCommand { variable, command } in &self.commands {
+            steps.push(format!(
+                r#"I will run "{command}" and set "{variable}" to what it prints."#
+            ));
+        }
+
+        let inix_dir = &self.inix_dir.path;
+        let conflicting = &self.inix_dir.conflicting_templates;
+        if self.inix_dir.exists {
+            steps.push(if conflicting.is_empty() {
+                format!(
+                    r#"The inix directory ("{}") already exists, but none of the new templates conflict with existing subdirectories."#,
+                    inix_dir.display()
+                )
+            } else {
+                format!(
+                    r#"The inix directory ("{}") already exists, and the following templates you're trying to add already exist in it: {}."#,
+                    inix_dir.display(),
+                    combine_strings(conflicting.iter())
+                )
+            });
+        }
+        for removed in &self.removed {
+            steps.push(format!(
+                r#"Because you have chosen to overwrite the inix directory on conflicts, I will delete "{}" first."#,
+                removed.display()
+            ));
+        }
+
+        // the templates that would be written are the ones with files
+        // in the plan: the others are left as they are
+        let (added, left): (Vec<_>, Vec<_>) = self.templates.iter().partition(|template| {
+            let dir = inix_dir.join(&template.name);
+            self.files.iter().any(|file| file.path.starts_with(&dir))
+        });
+        if self.templates.is_empty() && !self.inix_dir.exists {
+            let gitkeep = inix_dir.join(".gitkeep");
+            steps.push(if self.files.iter().any(|file| file.path == gitkeep) {
+                format!(
+                    r#"I will create an empty "{}" directory, because you asked me to, even though there are no templates to add to it."#,
+                    inix_dir.display()
+                )
+            } else {
+                format!(
+                    r#"You haven't chosen any templates, so I will not create the "{}" directory."#,
+                    inix_dir.display()
+                )
+            });
+        }
+        if !added.is_empty() {
+            steps.push(format!(
+                r#"I will add the {} template(s) to "{}"{}."#,
+                combine_strings(added.iter().map(|template| template.name.as_str())),
+                inix_dir.display(),
+                match self.on_conflict {
+                    ConflictBehavior::MergeReplace if !conflicting.is_empty() => {
+                        ", replacing the ones that are there already"
+                    }
+                    _ => "",
+                }
+            ));
+        }
+        if !left.is_empty() {
+            steps.push(format!(
+                r#"Because you have chosen to {}, I will leave the {} template(s) in "{}" as they are."#,
+                match self.on_conflict {
+                    ConflictBehavior::Cancel => "cancel on conflicts",
+                    _ => "keep existing files on conflicts",
+                },
+                combine_strings(left.iter().map(|template| template.name.as_str())),
+                inix_dir.display()
+            ));
+        }
+
+        let descriptions: Vec<_> = self
+            .templates
+            .iter()
+            .filter_map(|t| Some(format!("- {}: {}", t.name, t.description.as_ref()?)))
+            .collect();
+        if !descriptions.is_empty() {
+            steps.push(format!(
+                "This is what the templates are for:\n{}",
+                descriptions.join("\n")
+            ));
+        }
+
+        for file in &self.files {
+            let path = file.path.display();
+            steps.push(match (&file.backup, file.action) {
+                (Some(backup), _) => format!(
+                    r#"I will move the existing "{path}" to "{}" and create a new one in its place."#,
+                    backup.display()
+                ),
+                (None, FileAction::Create) => format!(r#"I will create "{path}"."#),
+                (None, FileAction::Overwrite) => format!(r#"I will overwrite "{path}"."#),
+                (None, FileAction::Update) => format!(
+                    r#"I will update the part of "{path}" that inix manages and leave the rest of it as it is."#
+                ),
+            });
+        }
+        if self.files.is_empty() {
+            steps.push("I will not write anything.".to_string());
+        }
+
+        if self.git_init {
+            steps.push(format!(
+                r#"Because you have asked for a git repository, I will run "git init" in "{}"."#,
+                self.target_dir.display()
+            ));
+        }
+        if self.direnv_allow {
+            steps.push(format!(
+                r#"Because you have chosen to allow the environment automatically, I will finish by running "direnv allow {}"."#,
+                self.target_dir.display()
+            ));
+        }
+
+        steps
+    }
+}
+
+/// Describe the differences between two plans for the same
+/// arguments (but different conflict behaviors): the files and
+/// directories that one of them would do something different to.
+fn compare_plans(a: &Plan, b: &Plan) -> Vec<String> {
+    let name = |behavior: ConflictBehavior| {
+        behavior
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    };
+    let (a_name, b_name) = (name(a.on_conflict), name(b.on_conflict));
+
+    let describe = |file: Option<&PlannedFile>| match file {
+        None => "leave it alone".to_string(),
+        Some(PlannedFile {
+            backup: Some(backup),
+            ..
+        }) => format!(r#"move it to "{}" and create a new one"#, backup.display()),
+        Some(file) => match file.action {
+            FileAction::Create => "create it",
+            FileAction::Overwrite => "overwrite it",
+            FileAction::Update => "update the part of it that inix manages",
+        }
+        .to_string(),
+    };
+    fn file_in<'a>(plan: &'a Plan, path: &Path) -> Option<&'a PlannedFile> {
+        plan.files.iter().find(|file| file.path == path)
+    }
+
+    let mut differences = vec![];
+    for dir in a.removed.iter().chain(&b.removed).sorted().dedup() {
+        if a.removed.contains(dir) != b.removed.contains(dir) {
+            let remover = if a.removed.contains(dir) {
+                &a_name
+            } else {
+                &b_name
+            };
+            differences.push(format!(
+                r#"- "{}": {remover} would remove it and everything in it first"#,
+                dir.display()
+            ));
+        }
+    }
+    for path in a
+        .files
+        .iter()
+        .chain(&b.files)
+        .map(|file| &file.path)
+        .sorted()
+        .dedup()
+    {
+        let (in_a, in_b) = (file_in(a, path), file_in(b, path));
+        if in_a != in_b {
+            differences.push(format!(
+                r#"- "{}": {a_name} would {}, {b_name} would {}"#,
+                path.display(),
+                describe(in_a),
+                describe(in_b)
+            ));
+        }
+    }
+
+    if differences.is_empty() {
+        vec![format!(
+            "{a_name} and {b_name} would do exactly the same thing here."
+        )]
+    } else {
+        std::iter::once(format!("Here's where {a_name} and {b_name} differ:"))
+            .chain(differences)
+            .collect()
+    }
+}
+
+/// Write the completion script for `shell` to `out`.
+fn write_completions(shell: clap_complete::Shell, out: &mut impl Write) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, out);
+}
+
+/// The completion script for `shell` with `--completions-dynamic`:
+/// the usual one, with template names completed by asking inix for
+/// them (`--list-template-names`) every time.
+fn dynamic_completions(shell: clap_complete::Shell) -> anyhow::Result<String> {
+    let mut script = vec![];
+    write_completions(shell, &mut script);
+    let script = String::from_utf8(script)?;
+    let list = "inix --list-template-names 2>/dev/null";
+
+    Ok(match shell {
+        clap_complete::Shell::Bash => formatdoc! {r#"
+            {script}
+            _inix_with_templates() {{
+                _inix "$@"
+                local cur="${{COMP_WORDS[COMP_CWORD]}}"
+                local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+                if [[ $cur != -* && $prev != -* ]]; then
+                    COMPREPLY+=($(compgen -W "$({list})" -- "$cur"))
+                fi
+            }}
+            complete -F _inix_with_templates -o bashdefault -o default inix
+            "#},
+        clap_complete::Shell::Zsh => {
+            // the spec for a templates argument ends in the action that
+            // completes them, after the last colon. Depending on the
+            // version of clap_complete, that's `_default` or nothing.
+            let with_template_names = |line: &str| {
+                if !line.contains("::templates -- ") {
+                    return None;
+                }
+                let end = line.rfind('\'')?;
+                let start = line[..end].rfind(':')? + 1;
+                Some(format!(
+                    "{}_inix_template_names{}",
+                    &line[..start],
+                    &line[end..]
+                ))
+            };
+            let (compdef, rest) = script.split_once('\n').unwrap_or(("#compdef inix", &script));
+            let mut rewritten = false;
+            let rest = rest
+                .lines()
+                .map(|line| match with_template_names(line) {
+                    Some(line) => {
+                        rewritten = true;
+                        line
+                    }
+                    None => line.to_string(),
+                })
+                .join("\n");
+            if !rewritten {
+                bail!("I was unable to find the template names in the zsh completion script.")
+            }
+            formatdoc! {r#"
+                {compdef}
+
+                _inix_template_names() {{
+                    local -a templates
+                    templates=(${{(f)"$({list})"}})
+                    _describe 'template' templates
+                }}
+                {rest}
+                "#}
+        }
+        clap_complete::Shell::Fish => formatdoc! {r#"
+            {script}
+            complete -c inix -a '({list})' -d 'Template'
+            "#},
+        _ => bail!(
+            "I can only install completions with template names for bash, zsh, and fish. You can still get the usual completion script for {shell} with `--completions {shell}`."
+        ),
+    })
+}
+
+/// Where a user's completion scripts for `shell` go, and what the
+/// script for inix should be called there.
+fn completion_path(shell: clap_complete::Shell, home: &Path) -> anyhow::Result<(PathBuf, &str)> {
+    Ok(match shell {
+        clap_complete::Shell::Bash => (
+            home.join(".local/share/bash-completion/completions"),
+            "inix",
+        ),
+        // this needs to be on your $fpath
+        clap_complete::Shell::Zsh => (home.join(".zfunc"), "_inix"),
+        clap_complete::Shell::Fish => (home.join(".config/fish/completions"), "inix.fish"),
+        _ => bail!("I don't know where {shell} looks for completion scripts."),
+    })
+}
+
+/// Write the `--completions-dynamic` script for `shell` to `dir`, or
+/// to the user's completion directory for it, and return its path.
+fn install_dynamic_completions(
+    shell: clap_complete::Shell,
+    dir: Option<&Path>,
+    home: Option<&Path>,
+) -> anyhow::Result<PathBuf> {
+    let script = dynamic_completions(shell)?;
+    let path = match (dir, home) {
+        (Some(dir), _) => dir.join(completion_path(shell, Path::new(""))?.1),
+        (None, Some(home)) => {
+            let (dir, file_name) = completion_path(shell, home)?;
+            dir.join(file_name)
+        }
+        (None, None) => bail!(
+            "I was unable to find your home directory, so I don't know where to put the completion script. Please tell me with `--completions-dir`."
+        ),
+    };
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).with_context(|| {
+            format!(
+                r#"I was unable to create the directory "{}"."#,
+                parent.display()
+            )
+        })?;
+    }
+    fs::write(&path, script)
+        .with_context(|| format!(r#"I was unable to write "{}"."#, path.display()))?;
+    Ok(path)
+}
+
+fn run(cli: Cli, dirs: &Dirs) -> anyhow::Result<()> {
+    run_to(cli, dirs, &mut io::stdout())
+}
+
+/// Like `run`, but with what would go to stdout written to `out`
+/// instead (except for the pager and the conflict prompt).
+fn run_to(cli: Cli, dirs: &Dirs, out: &mut impl Write) -> anyhow::Result<()> {
+    if let Some(shell) = cli.completions {
+        write_completions(shell, out);
+        return Ok(());
+    }
+
+    if let Some(shell) = cli.completions_dynamic {
+        let path = install_dynamic_completions(
+            shell,
+            cli.completions_dir.as_deref(),
+            dirs.home.as_deref(),
+        )?;
+        if !cli.quiet {
+            writeln!(
+                out,
+                "I wrote the completion script for {shell} to \"{}\". Start a new shell to use it.",
+                path.display()
+            )?;
+        }
+        return Ok(());
+    }
+
+    if cli.each_subdir {
+        let parent = try_get_target_dir(cli.directory.clone())?;
+        for dir in visible_subdirs(&parent)? {
+            if !cli.quiet && !cli.print_created {
+                writeln!(out, "{}:", dir.display())?;
+            }
+            let cli = Cli {
+                directory: Some(dir.clone()),
+                each_subdir: false,
+                ..cli.clone()
+            };
+            run_to(cli, dirs, out)
+                .with_context(|| format!(r#"I was unable to set up "{}"."#, dir.display()))?;
+        }
+        return Ok(());
+    }
+
+    // colors are decided for each stream on its own: the prompts go to
+    // stdout, but warnings and notes go to stderr, which can be a
+    // terminal when stdout isn't (and the other way around)
+    let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let colors = Colors::new(cli.color, no_color, io::stdout().is_terminal());
+    let stderr_colors = Colors::new(cli.color, no_color, io::stderr().is_terminal());
+
+    // a project's own templates take precedence over everything else,
+    // and template directories from the command line take precedence
+    // over the ones from the environment
+    let local_dir = try_get_target_dir(cli.directory.clone())
+        .ok()
+        .and_then(|dir| find_local_template_dir(&dir));
+    // only setting templates up clones or updates the repositories:
+    // anything else, including a dry run, uses the clones that are
+    // already there, if any
+    let fetch_repos = !cli.dry_run
+        && !cli.list
+        && !cli.list_template_names
+        && cli.save_as.is_none()
+        && cli.print_template_path.is_none()
+        && matches!(cli.action, None | Some(Action::Update { .. }));
+    let repo_dirs: Vec<_> = cli
+        .template_repos
+        .iter()
+        .map(|url| match fetch_repos {
+            true => fetch_template_repo(url, dirs, "git", cli.refresh_templates).map(Some),
+            false => Ok(cached_template_repo(url, dirs)),
+        })
+        .flatten_ok()
+        .collect::<anyhow::Result<_>>()?;
+    let dirs = Dirs {
+        templates: local_dir
+            .iter()
+            .chain(&cli.template_dirs)
+            .chain(&repo_dirs)
+            .chain(&dirs.templates)
+            .cloned()
+            .collect(),
+        single_template_dirs: cli.template_dirs.clone(),
+        ..dirs.clone()
+    };
+    // ...except for templates that were asked for along with the
+    // repository they're in, which is what they're taken from. So are
+    // templates that can only be found in the registry.
+    let (templates, remote) = resolve_remote_templates(
+        &requested_templates(&cli)?,
+        cli.template_dir_precedence,
+        &dirs,
+        cli.refresh_templates,
+    )?;
+    let cli = Cli {
+        templates,
+        template_list: None,
+        ..cli
+    };
+    let dirs = &Dirs {
+        remote: remote.into_iter().chain(dirs.remote).collect(),
+        ..dirs
+    };
+
+    if cli.list_template_names {
+        let existing_dirs = dirs.existing_template_dirs();
+        let custom_dirs: Vec<_> = existing_dirs.iter().map(PathBuf::as_path).collect();
+        for name in template_names(&custom_dirs, &dirs.single_template_dirs)? {
+            writeln!(out, "{name}")?;
+        }
+        return Ok(());
+    }
+
+    if cli.list || cli.action == Some(Action::List) {
+        for line in template_listing(cli.template_dir_precedence, dirs)? {
+            writeln!(out, "{line}")?;
+        }
+        return Ok(());
+    }
+
+    if cli.action == Some(Action::InitConfig) {
+        for line in init_config(dirs, cli.force, cli.dry_run)? {
+            writeln!(out, "{line}")?;
+        }
+        return Ok(());
+    }
+
+    if let Some(name) = &cli.save_as {
+        let target_dir = try_get_target_dir(cli.directory.clone())?;
+        for line in save_as_template(name, &target_dir, dirs, cli.on_conflict, cli.dry_run)? {
+            writeln!(out, "{line}")?;
+        }
+        return Ok(());
+    }
+
+    if let Some(Action::Show { template }) = &cli.action {
+        let templates = try_get_templates(
+            std::slice::from_ref(template),
+            cli.template_dir_precedence,
+            dirs,
+        )?;
+        for template in &templates {
+            write!(out, "{}", show_template(template))?;
+        }
+        return Ok(());
+    }
+
+    if let Some(Action::Search { keyword }) = &cli.action {
+        let entries = registry_entries(dirs, "curl")?.ok_or_else(|| {
+            anyhow!(
+                r#"You haven't told me where the template registry is. Please add `registry = "<url or path>"` to "{}"."#,
+                dirs.config
+                    .as_ref()
+                    .map(|dir| dir.join("inix").join("config.toml").display().to_string())
+                    .unwrap_or_else(|| "<your user configuration directory>/inix/config.toml".into())
+            )
+        })?;
+        let found = search_registry(&entries, keyword);
+        if found.is_empty() && !cli.quiet {
+            eprintln!(r#"There are no templates matching "{keyword}" in the registry."#);
+        }
+        for entry in found {
+            writeln!(out, "{entry}")?;
+        }
+        return Ok(());
+    }
+
+    if let Some(Action::Remove { templates }) = &cli.action {
+        let target_dir = try_get_target_dir(cli.directory.clone())?;
+        if cli.dry_run {
+            for step in removal_steps(templates, &target_dir) {
+                writeln!(out, "{step}")?;
+            }
+            return Ok(());
+        }
+        let _lock = match cli.no_lock {
+            true => None,
+            false => Some(RunLock::acquire(&target_dir, false)?),
+        };
+        let (written_files, warnings) = remove_templates(templates, &target_dir, &cli, dirs)?;
+        for warning in warnings {
+            eprintln!("{}", stderr_colors.warning(&warning));
+        }
+        if !cli.quiet {
+            if let Some(summary) = summary(&written_files) {
+                writeln!(out, "{summary}")?;
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(name) = &cli.print_template_path {
+        let templates = try_get_templates(
+            std::slice::from_ref(name),
+            cli.template_dir_precedence,
+            dirs,
+        )?;
+        for template in &templates {
+            writeln!(out, "{}", template_location(template))?;
+        }
+        return Ok(());
+    }
+
+    // updating is setting the templates up again, replacing what's
+    // there by default. Templates that came from git repositories are
+    // taken from there again.
+    let (cli, dirs) = match &cli.action {
+        Some(Action::Update { templates }) => {
+            let target_dir = try_get_target_dir(cli.directory.clone())?;
+            let inix_dir = target_dir.join("inix");
+            let mut remote = vec![];
+            for (name, source) in read_template_sources(&inix_dir)? {
+                if templates.is_empty() || templates.contains(&name) {
+                    remote.push(fetch_remote_template(&name, &source, dirs, true)?);
+                }
+            }
+            let dirs = Dirs {
+                remote: remote.into_iter().chain(dirs.remote.clone()).collect(),
+                ..dirs.clone()
+            };
+            let (templates, warnings) =
+                templates_to_update(templates, &inix_dir, cli.template_dir_precedence, &dirs)?;
+            for warning in warnings {
+                eprintln!("{}", stderr_colors.warning(&warning));
+            }
+            // like with `inix remove`, a project with a flake keeps
+            // it, and one that's set up with just a flake stays that way
+            let has_flake = target_dir.join("flake.nix").exists();
+            let cli = Cli {
+                action: None,
+                templates,
+                template_list: None,
+                on_conflict: cli.on_conflict.or(Some(ConflictBehavior::MergeReplace)),
+                emit_flake: cli.emit_flake || has_flake,
+                flake: cli.flake || (has_flake && !target_dir.join("shell.nix").exists()),
+                ..cli
+            };
+            (cli, dirs)
+        }
+        _ => (cli, dirs.clone()),
+    };
+    let dirs = &dirs;
+
+    // PREPARE //
+
+    // a single template can be asked for by the start of its name, as
+    // long as that's not ambiguous
+    let mut requested = requested_templates(&cli)?;
+
+    // template directories that are templates themselves are used if
+    // no templates were asked for
+    if requested.is_empty() {
+        requested = cli
+            .template_dirs
+            .iter()
+            .filter_map(|dir| single_template_name(dir))
+            .collect();
+    }
+    if let [name] = requested.as_mut_slice() {
+        if let Some(full_name) = complete_template_name(name, cli.template_dir_precedence, dirs)? {
+            if !cli.quiet {
+                eprintln!(
+                    r#"There's no template called "{name}", so I'm using "{}"."#,
+                    stderr_colors.template(&full_name)
+                );
+            }
+            *name = full_name;
+        }
+    }
+
+    // this is printed before checking the templates, so that it's
+    // there when some of them are missing too
+    if cli.log_decisions || cli.format == OutputFormat::Json {
+        let decisions = template_decisions(&requested, cli.template_dir_precedence, dirs);
+        eprintln!("{}", serde_json::to_string_pretty(&decisions)?);
+    }
+
+    // check to see whether we can find all the templates
+    let templates = try_get_templates(&requested, cli.template_dir_precedence, dirs)?;
+    for template in &templates {
+        template.ensure_supported()?;
+    }
+
+    // flake-based templates can only be used from a flake, so make sure
+    // that there is one
+    let mut cli = Cli {
+        emit_flake: cli.emit_flake || cli.flake || templates.iter().any(Template2::is_flake),
+        ..cli
+    };
+
+    // check to see if the target directory exists
+    let target_dir = try_get_target_dir(cli.directory.clone())?;
+    if !cli.dry_run {
+        ensure_not_in_nix_store(&target_dir, &nix_store_dir())?;
+    }
+
+    // the lock is taken before anything in the target directory is
+    // looked at, so that a concurrent run can't change it underneath
+    // us. It's held until the run is over, however it ends.
+    let mut _lock = None;
+    if !cli.dry_run && cli.compare_behaviors.is_empty() {
+        let created = !target_dir.exists();
+        if created {
+            ensure_dir_can_be_created(&target_dir)?;
+            create_dir_all(&target_dir).with_context(|| {
+                format!(
+                    r#"I was unable to create the target project dir ("{}")"#,
+                    &target_dir.display()
+                )
+            })?
+        } else {
+            let metadata = target_dir.metadata().with_context(|| {
+                format!(
+                    "Unable to read permission status for \"{}\".",
+                    &target_dir.display()
+                )
+            })?;
+
+            let false = metadata.permissions().readonly() else {
+                bail!(
+                    "I don't have the right permissions to write to \"{}\"",
+                    &target_dir.display()
+                )
+            };
+        }
+
+        if !cli.no_lock {
+            _lock = Some(RunLock::acquire(&target_dir, created)?);
+        }
+    }
+
+    // variables from commands are used just like the ones from `--set`.
+    // Nothing is run without writing anything, though: the plan says
+    // what would be run instead.
+    let command_variables = if cli.dry_run || !cli.compare_behaviors.is_empty() {
+        cli.variable_commands
+            .iter()
+            .map(|(key, command)| (key.clone(), format!("<output of `{command}`>")))
+            .collect()
+    } else {
+        variables_from_commands(&cli.variable_commands, &target_dir)?
+    };
+    cli.variables.extend(command_variables);
+
+    if cli.require_empty_dir {
+        ensure_dir_is_empty(&target_dir)?;
+    }
+
+    // templates can't be rendered without the variables they need, so
+    // ask for the ones that haven't been set if we can
+    let missing = match cli.no_render {
+        true => vec![],
+        false => missing_variables(&templates, &cli.variables),
+    };
+    if !missing.is_empty() {
+        if io::stdin().is_terminal() && !cli.non_interactive && !cli.quiet {
+            let prompted = prompt_for_variables(&missing)?;
+            cli.variables.extend(prompted);
+        } else {
+            let reason = if cli.non_interactive {
+                "You have asked me not to prompt you (`--non-interactive`), so I can't ask for them"
+            } else if cli.quiet {
+                "You have asked me to be quiet (`--quiet`), so I can't ask for them"
+            } else {
+                "I can't ask for them, because I'm not running in a terminal"
+            };
+            bail!(
+                "These templates need variables that you haven't set:\n{}\n\n{reason}. Please set them with `--set <name>=<value>`.",
+                missing
+                    .iter()
+                    .map(|(template, variable)| format!(r#"- {variable} (for the "{template}" template)"#))
+                    .join("\n")
+            )
+        }
+    }
+
+    // check the variables for the base templates and render the
+    // templates' files before writing anything
+    let handlebars = base_handlebars(cli.strict_vars);
+    let handlebars_args = base_template_args(&templates, &cli)?;
+    let template_files = templates
+        .iter()
+        .map(|template| {
+            let files = if cli.no_render {
+                template
+                    .files()
+                    .into_iter()
+                    .map(|(file_name, contents)| (file_name, contents.to_vec()))
+                    .collect()
+            } else {
+                render_template_files(&handlebars, template, &handlebars_args)?
+            };
+            Ok((template.name().to_string(), files))
+        })
+        .collect::<anyhow::Result<HashMap<_, _>>>()?;
+
+    let has_flake = cli.emit_flake || target_dir.join("flake.nix").exists();
+    for warning in flake_warnings(&templates, has_flake) {
+        eprintln!("{}", stderr_colors.warning(&warning));
+    }
+
+    // check to see whether we have write permissions in the target
+    // directory
+
+    // does the inix subdirectory already exist?
+    let inix_dir_path = target_dir.join("inix");
+
+    // gather all data about the inix dir
+
+    let inix_dir = {
+        let state = if inix_dir_path.is_dir() {
+            let conflicting_templates: Vec<&str> = templates
+                .iter()
+                // anything with the template's name is in the way,
+                // including files and (even dangling) symlinks
+                .filter_map(|template| {
+                    if inix_dir_path
+                        .join(template.name())
+                        .symlink_metadata()
+                        .is_ok()
+                    {
+                        Some(template.name())
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+
+            let template_collisions = match conflicting_templates.as_slice() {
+                [] => TemplateCollisions::None,
+                [head, tail @ ..] if conflicting_templates.len() == templates.len() => {
+                    TemplateCollisions::All(NonEmpty::from((*head, tail.iter().copied().collect())))
+                }
+                [head, tail @ ..] => TemplateCollisions::Some(NonEmpty::from((
+                    *head,
+                    tail.iter().copied().collect(),
+                ))),
+            };
+
+            InixDirState::AlreadyExists {
+                template_collisions,
+            }
+        } else {
+            InixDirState::DoesNotExist
+        };
+
+        InixDir {
+            state,
+            path: &inix_dir_path,
+        }
+    };
+
+    if let [a, b] = cli.compare_behaviors[..] {
+        let comparison = compare_plans(
+            &plan(&cli, &target_dir, &inix_dir, &templates, a),
+            &plan(&cli, &target_dir, &inix_dir, &templates, b),
+        );
+        print_paged(&comparison.join("\n"), cli.no_pager)?;
+        return Ok(());
+    }
+
+    let on_conflict = match resolve_conflict_behavior(
+        &inix_dir,
+        cli.on_conflict,
+        io::stdin().is_terminal(),
+    ) {
+        ConflictResolution::Use(behavior) => behavior,
+        ConflictResolution::Prompt | ConflictResolution::CancelWithWarning(_)
+            if cli.non_interactive || cli.quiet =>
+        {
+            let reason = if cli.non_interactive {
+                "You have asked me not to prompt you (`--non-interactive`)"
+            } else {
+                "You have asked me to be quiet (`--quiet`)"
+            };
+            bail!(
+                "{}\n\n{reason}, so I can't ask what to do about this. Please run me again with `--on-conflict <overwrite|merge-keep|merge-replace|cancel>`.",
+                inix_dir.conflict_description().trim()
+            )
+        }
+        ConflictResolution::Prompt => prompt_for_conflict_behavior(&inix_dir, colors)?,
+        ConflictResolution::CancelWithWarning(warning) => {
+            eprintln!("{}", stderr_colors.warning(&warning));
+            ConflictBehavior::Cancel
+        }
+    };
+
+    if let ConflictBehavior::MergeKeep | ConflictBehavior::MergeReplace = on_conflict {
+        ensure_template_dirs_are_not_files(&inix_dir_path, &templates)?;
+    }
+
+    // don't start writing templates into a directory that has nothing
+    // to do with inix
+    if on_conflict != ConflictBehavior::Cancel
+        && !templates.is_empty()
+        && inix_dir_path.is_dir()
+        && !cli.force
+        && !looks_like_inix_dir(&inix_dir_path, &templates, dirs)?
+    {
+        bail!(
+            "There's already a directory called \"{}\", but it doesn't look like anything I've set up: none of the directories in it are templates. I won't write templates into it in case it's something else.\n\nPlease rename or move it first, or run me again with `--force` if you're sure that you want me to use it.",
+            inix_dir_path.display()
+        )
+    }
+
+    // without any templates, an existing inix directory is left alone
+    // (see below), so there's nothing to protect
+    if on_conflict == ConflictBehavior::Overwrite
+        && !templates.is_empty()
+        && inix_dir_path.is_dir()
+        && !cli.force
+    {
+        let unexpected = unexpected_inix_entries(
+            &inix_dir_path,
+            &templates,
+            dirs,
+            cli.template_dir_precedence,
+        )?;
+        if !unexpected.is_empty() {
+            bail!(
+                "I won't overwrite the inix directory (\"{}\"), because these things in it don't belong to any template and would be deleted:\n{}\n\nPlease move them somewhere else first, or run me again with `--force` if you're sure that you want to delete them.",
+                inix_dir_path.display(),
+                unexpected
+                    .iter()
+                    .map(|path| format!("- {}", path.display()))
+                    .join("\n")
+            )
+        }
+    }
+
+    // the base files source every template in the inix directory,
+    // including the ones that were there before
+    let (final_templates, warnings) = final_templates(&inix_dir_path, &templates, on_conflict)?;
+    for warning in warnings {
+        eprintln!("{}", stderr_colors.warning(&warning));
+    }
+    let handlebars_args = base_template_args(&final_templates, &cli)?;
+
+    // EXECUTE //
+    // the top-level files that were written, for the summary
+    let mut written_files = vec![];
+    // every file that was written, for --print-created and --verbose
+    let mut created_files = vec![];
+    // what was written, for --rollback-on-allow-failure
+    let mut committed = None;
+    // the existing top-level files that were left alone
+    let mut skipped_files = vec![];
+    if cli.dry_run {
+        let plan = plan(&cli, &target_dir, &inix_dir, &templates, on_conflict);
+        match cli.format {
+            OutputFormat::Text => print_paged(&plan.steps().join("\n"), cli.no_pager)?,
+            OutputFormat::Json => writeln!(out, "{}", serde_json::to_string_pretty(&plan)?)?,
+        }
+    } else {
+        let inix_dir_exists = matches!(inix_dir.state, InixDirState::AlreadyExists { .. });
+
+        // everything is written to a staging area first and only
+        // moved into the project once all of it has been written, so
+        // that a failure along the way doesn't leave things half done
+        let mut staging = Staging::default();
+
+        // copy templates over (into an inix directory)
+        match (inix_dir.state, on_conflict) {
+            // without any templates, there's nothing to put in the
+            // inix directory, so don't create it unless we've been
+            // asked to.
+            (InixDirState::DoesNotExist, _) if templates.is_empty() => {
+                if cli.allow_empty {
+                    let staged = staging.stage_inix_dir(inix_dir.path, false)?;
+                    let gitkeep = staged.join(".gitkeep");
+                    fs::write(&gitkeep, "").with_context(|| {
+                        format!(r#"I was unable to create "{}"."#, gitkeep.display())
+                    })?;
+                    staging.track_inix_entries([gitkeep]);
+                }
+            }
+
+            // likewise, an existing inix directory is never touched
+            // if there's nothing to write to it.
+            (InixDirState::AlreadyExists { .. }, _) if templates.is_empty() => {
+                // intentionally left blank
+            }
+
+            // the old inix directory is only replaced once the new
+            // one is complete
+            (InixDirState::DoesNotExist, _)
+            | (InixDirState::AlreadyExists { .. }, ConflictBehavior::Overwrite) => {
+                let staged = staging.stage_inix_dir(inix_dir.path, false)?;
+                for template in &templates {
+                    let written =
+                        write_template(template, &template_files[template.name()], &staged)?;
+                    staging.track_inix_entries(written);
+                }
+                let sources = record_template_sources(&staged, &templates, dirs)?;
+                staging.track_inix_entries(sources);
+            }
+            (
+                InixDirState::AlreadyExists {
+                    template_collisions,
+                },
+                ConflictBehavior::MergeKeep,
+            ) => {
+                let templates_to_copy = match template_collisions {
+                    TemplateCollisions::Some(ts) => templates
+                        .iter()
+                        .filter(|t| !ts.contains(&t.name()))
+                        .map(|t| t.clone())
+                        .collect(),
+                    TemplateCollisions::None => templates.clone(),
+                    TemplateCollisions::All(_) => vec![],
+                };
+
+                if !templates_to_copy.is_empty() {
+                    let staged = staging.stage_inix_dir(inix_dir.path, true)?;
+                    for template in &templates_to_copy {
+                        let written =
+                            write_template(template, &template_files[template.name()], &staged)?;
+                        staging.track_inix_entries(written);
+                    }
+                    let sources = record_template_sources(&staged, &templates_to_copy, dirs)?;
+                    staging.track_inix_entries(sources);
+                }
+            }
+            (InixDirState::AlreadyExists { .. }, ConflictBehavior::MergeReplace) => {
+                let staged = staging.stage_inix_dir(inix_dir.path, true)?;
+                for template in &templates {
+                    // a symlink in the template's place is replaced
+                    // rather than written through
+                    let template_dir = staged.join(template.name());
+                    if template_dir.is_symlink() {
+                        remove_entry(&template_dir)?;
+                    }
+                    let written =
+                        write_template(template, &template_files[template.name()], &staged)?;
+                    staging.track_inix_entries(written);
+                }
+                let sources = record_template_sources(&staged, &templates, dirs)?;
+                staging.track_inix_entries(sources);
+            }
+            (InixDirState::AlreadyExists { .. }, ConflictBehavior::Cancel) => {
+                // intentionally left blank
+            }
+        }
+
+        // render base templates
+
+        let (nix_template, envrc_template) =
+            base_template_files(cli.template_dir_precedence, dirs)?;
+
+        let should_write = |path: &Path| should_write_base_file(path, on_conflict, inix_dir_exists);
+        // when merging and keeping, existing files are backed up
+        // instead of replaced
+        let back_up = on_conflict == ConflictBehavior::MergeKeep;
+        let render = |template: &str, path: &Path| -> anyhow::Result<String> {
+            if cli.no_render {
+                Ok(template.to_string())
+            } else {
+                handlebars
+                    .render_template(template, &handlebars_args)
+                    .with_context(|| format!(r#"I was unable to render "{}"."#, path.display()))
+            }
+        };
+
+        let shell_nix_path = target_dir.join("shell.nix");
+        if !cli.flake && should_write(&shell_nix_path) {
+            staging.stage_file(
+                &shell_nix_path,
+                &render(&nix_template, &shell_nix_path)?,
+                back_up,
+            )?;
+        }
+
+        let envrc_path = target_dir.join(".envrc");
+        if should_write(&envrc_path) {
+            let envrc = render(&envrc_template, &envrc_path)?;
+            // there's nothing to back up if only the part that inix
+            // manages changes
+            let (envrc, back_up) = match fs::read_to_string(&envrc_path) {
+                Ok(existing) => (
+                    update_managed_block(&existing, &envrc),
+                    back_up && !(has_managed_block(&existing) && has_managed_block(&envrc)),
+                ),
+                Err(_) => (envrc, back_up),
+            };
+            staging.stage_file(&envrc_path, &envrc, back_up)?;
+        }
+
+        let flake_nix_path = target_dir.join("flake.nix");
+        if cli.emit_flake && should_write(&flake_nix_path) {
+            let flake = builtin_file(
+                dirs.builtin.as_deref(),
+                "base/flake.nix.template",
+                include_str!("templates/base/flake.nix.template"),
+            );
+            let flake = render(&flake, &flake_nix_path)?;
+            staging.stage_file(&flake_nix_path, &flake, back_up)?;
+        }
+
+        skipped_files = skipped_base_files(&target_dir, &cli, on_conflict, inix_dir_exists);
+
+        let done = staging.commit()?;
+        written_files = done.written_files();
+        created_files = done.written.clone();
+        committed = Some(done);
+
+        if cli.verbose {
+            for line in file_log(&created_files) {
+                writeln!(out, "{line}")?;
+            }
+        }
+    }
+
+    // the files have been written at this point, so a failure here
+    // shouldn't make it look like nothing happened
+    if cli.git_init && !cli.dry_run {
+        match git_init(&target_dir, "git") {
+            Ok(true) => {
+                if let Some(committed) = &mut committed {
+                    committed.track_created(target_dir.join(".git"));
+                }
+            }
+            Ok(false) => {}
+            Err(error) => eprintln!("{}", stderr_colors.warning(&format!("Warning: {error:#}"))),
+        }
+    }
+
+    if cli.auto_allow && !cli.dry_run {
+        match committed.take() {
+            Some(committed) if cli.rollback_on_allow_failure => {
+                allow_or_roll_back(committed, &target_dir, "direnv")?
+            }
+            _ => direnv_allow(&target_dir, "direnv")?,
+        }
+    }
+
+    if cli.smoke_test && !cli.dry_run {
+        smoke_test(&target_dir, cli.emit_flake, cli.quiet)?;
+    }
+
+    if cli.print_created {
+        let paths: Vec<_> = created_files.into_iter().map(|file| file.path).collect();
+        write!(out, "{}", path_list(&paths, cli.null))?;
+    } else if !cli.quiet {
+        if let Some(summary) = summary(&written_files) {
+            writeln!(out, "{summary}")?;
+        }
+    }
+    for path in &skipped_files {
+        eprintln!(
+            "{}",
+            stderr_colors.warning(&format!(
+                r#"Warning: "{}" already exists, so I left it as it is. To change it, run me again with `--on-conflict merge-replace` (which only replaces the part of an `.envrc` that inix manages) or with `--on-conflict merge-keep` (which backs it up first)."#,
+                path.display()
+            ))
+        );
+    }
+
+    // .render_template(&nix_template, &handlebars_args)
+
+    // println!("{}", fs::read_to_string(inix_dir.path.join("shell.nix"))?);
+
+    // println!("{output}, {handlebars_args:?}");
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let quiet_errors = cli.quiet_errors;
+
+    match run(cli, &Dirs::default()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {}", format_error(&error, quiet_errors));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The `paths`, each ended by a NUL character if `null`, or by a
+/// newline otherwise.
+fn path_list(paths: &[PathBuf], null: bool) -> String {
+    let end = if null { '\0' } else { '\n' };
+    paths
+        .iter()
+        .map(|path| format!("{}{end}", path.display()))
+        .collect()
+}
+
+/// The summary printed after a successful run: the absolute paths of
+/// the top-level files that were written, so that they're easy to
+/// copy (or click on). There's nothing to say if nothing was written.
+fn summary(written_files: &[PathBuf]) -> Option<String> {
+    if written_files.is_empty() {
+        return None;
+    }
+
+    Some(format!(
+        "All done! I wrote these files:\n{}",
+        written_files
+            .iter()
+            .map(|path| format!("- {}", absolute_path(path).display()))
+            .join("\n")
+    ))
+}
+
+/// What `--verbose` prints: a line for each file that was written,
+/// with its full path, and one for each backup that was made.
+fn file_log(files: &[PlannedFile]) -> Vec<String> {
+    files
+        .iter()
+        .flat_map(|file| {
+            let path = absolute_path(&file.path);
+            let backup = file.backup.as_ref().map(|backup| {
+                format!(
+                    "Backed up {} to {}",
+                    path.display(),
+                    absolute_path(backup).display()
+                )
+            });
+            let action = match file.action {
+                FileAction::Create => "Created",
+                FileAction::Overwrite => "Overwrote",
+                FileAction::Update => "Updated",
+            };
+            backup
+                .into_iter()
+                .chain(std::iter::once(format!("{action} {}", path.display())))
+        })
+        .collect()
+}
+
+/// `path` relative to the current directory, if it isn't absolute
+/// already.
+fn absolute_path(path: &Path) -> PathBuf {
+    match current_dir() {
+        Ok(dir) if path.is_relative() => normalize_path(&dir.join(path)),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Format an error for the user: either just the top-level message,
+/// or the message along with everything that caused it.
+fn format_error(error: &anyhow::Error, quiet: bool) -> String {
+    if quiet {
+        error.to_string()
+    } else {
+        format!("{error:?}")
+    }
+}
+
+/// Warn about templates whose `.envrc` does `use flake` when the
+/// project won't have a `flake.nix`: direnv would fail to load them.
+fn flake_warnings(templates: &[Template2], has_flake: bool) -> Vec<String> {
+    if has_flake {
+        return vec![];
+    }
+
+    templates
+        .iter()
+        .filter(|template| match &template.files {
+            TemplateFiles2::Envrc(envrc)
+            | TemplateFiles2::Both { envrc, .. }
+            | TemplateFiles2::Flake {
+                envrc: Some(envrc), ..
+            } => {
+                envrc.lines().any(|line| {
+                    let line = line.trim();
+                    line == "use flake" || line.starts_with("use flake ")
+                })
+            }
+            TemplateFiles2::Nix(_) | TemplateFiles2::Flake { envrc: None, .. } => false,
+        })
+        .map(|template| {
+            format!(
+                r#"Warning: The .envrc file of the "{}" template uses a flake ("use flake"), but there is no flake.nix in the project, so direnv won't be able to load it. You can use `--emit-flake` to create one, or change the template to "use nix" instead."#,
+                template.name()
+            )
+        })
+        .collect()
+}
+
+/// Run `direnv allow` for the `.envrc` in `target_dir`, using the
+/// `direnv` program.
+fn direnv_allow(target_dir: &Path, direnv: &str) -> anyhow::Result<()> {
+    let output = match Command::new(direnv).arg("allow").arg(target_dir).output() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| {
+                format!(
+                    r#"You asked me to run "direnv allow", but I couldn't find "{direnv}". Is direnv installed and on your PATH? The files have been created, so you can run "direnv allow {}" yourself once it is."#,
+                    target_dir.display()
+                )
+            })
+        }
+        result => result.with_context(|| format!(r#"I was unable to run "{direnv} allow"."#))?,
+    };
+
+    if !output.status.success() {
+        bail!(
+            r#""{direnv} allow {}" failed ({}): {}"#,
+            target_dir.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+    }
+
+    Ok(())
+}
+
+/// Run `direnv allow` like `direnv_allow`, but if it fails, roll back
+/// everything that was `committed` first, so that what's left is the
+/// way it was before.
+fn allow_or_roll_back(committed: Committed, target_dir: &Path, direnv: &str) -> anyhow::Result<()> {
+    let Err(error) = direnv_allow(target_dir, direnv) else {
+        return Ok(());
+    };
+    let rolled_back = committed.roll_back();
+    Err(error).with_context(|| {
+        format!(
+            "Because `direnv allow` failed, I have rolled back what I did (`--rollback-on-allow-failure`):\n{}",
+            rolled_back
+                .iter()
+                .map(|line| format!("- {line}"))
+                .join("\n")
+        )
+    })
+}
+
+/// If `spec` is a template in a git repository (`<url>#<name>`), the
+/// URL of the repository and the name of the template. Anything that
+/// doesn't look like a URL is a template name.
+fn remote_template_spec(spec: &str) -> anyhow::Result<Option<(&str, &str)>> {
+    let looks_like_url = |url: &str| url.contains("://") || url.starts_with("git@");
+    match spec.rsplit_once('#') {
+        Some((url, name)) if looks_like_url(url) && is_plain_name(name) => Ok(Some((url, name))),
+        _ if looks_like_url(spec) => bail!(
+            r##""{spec}" looks like a git repository, but I don't know which template in it to use. Please add the name of the template's directory after a "#", like "{}#rust"."##,
+            spec.split('#').next().unwrap_or(spec)
+        ),
+        _ => Ok(None),
+    }
+}
+
+/// Get the template that `source` (`<url>#<name>`) points to, to be
+/// set up as `name`. It's an error if there's no template in that
+/// directory of the repository.
+fn fetch_remote_template(
+    name: &str,
+    source: &str,
+    dirs: &Dirs,
+    refresh: bool,
+) -> anyhow::Result<RemoteTemplate> {
+    let Some((url, template_dir)) = remote_template_spec(source)? else {
+        bail!(
+            r##""{source}" isn't a git repository followed by "#" and the name of a template in it."##
+        )
+    };
+    let dir = fetch_template_repo(url, dirs, "git", refresh)?.join(template_dir);
+    if read_custom_template(name, &dir)?.is_none() {
+        bail!(
+            r#"There's no template called "{template_dir}" in "{url}": it has no "{template_dir}" directory with a shell.nix, an .envrc, or a flake.nix in it."#
+        )
+    }
+    Ok(RemoteTemplate {
+        name: name.to_string(),
+        source: source.to_string(),
+        dir,
+    })
+}
+
+/// Get the `requested` templates that come from git repositories:
+/// the ones given as `<url>#<name>`, and the ones that can only be
+/// found in the registry. Returns the names that all the requested
+/// templates are set up under, in order, along with the templates
+/// that were fetched.
+///
+/// The registry is only asked about names that aren't local
+/// templates. A single template that's the start of the name of
+/// exactly one local template is completed to that later (see
+/// `complete_template_name`), so it isn't looked up either.
+fn resolve_remote_templates(
+    requested: &[String],
+    precedence: TemplateDirPrecedence,
+    dirs: &Dirs,
+    refresh: bool,
+) -> anyhow::Result<(Vec<String>, Vec<RemoteTemplate>)> {
+    let existing_dirs = dirs.existing_template_dirs();
+    let custom_dirs: Vec<_> = existing_dirs.iter().map(PathBuf::as_path).collect();
+    let is_local = |name: &str| -> anyhow::Result<bool> {
+        let found = find_template(
+            name,
+            &custom_dirs,
+            &dirs.single_template_dirs,
+            precedence,
+            dirs.builtin.as_deref(),
+        )?
+        .is_some();
+        let completed = requested.len() == 1
+            && matches!(complete_template_name(name, precedence, dirs), Ok(Some(_)));
+        Ok(found || completed)
+    };
+
+    let mut registry = None;
+    let mut names = vec![];
+    let mut remote = vec![];
+    for spec in requested {
+        // (the name it's set up under, where it comes from)
+        let source = match remote_template_spec(spec)? {
+            Some((_, name)) => Some((name.to_string(), spec.clone())),
+            None if is_plain_name(spec) && !is_local(spec)? => {
+                if registry.is_none() {
+                    registry = Some(registry_entries(dirs, "curl")?.unwrap_or_default());
+                }
+                registry
+                    .iter()
+                    .flatten()
+                    .find(|entry| &entry.name == spec)
+                    .map(|entry| (entry.name.clone(), entry.source.clone()))
+            }
+            None => None,
+        };
+        match source {
+            Some((name, source)) => {
+                if remote_template_spec(&source)?.is_none() {
+                    bail!(
+                        r##"The registry says that the "{spec}" template is at "{source}", but that isn't a git repository followed by "#" and the name of a template in it."##
+                    )
+                }
+                remote.push(fetch_remote_template(&name, &source, dirs, refresh)?);
+                names.push(name);
+            }
+            None => names.push(spec.clone()),
+        }
+    }
+    Ok((names, remote))
+}
+
+/// The file in the inix directory that records which templates came
+/// from git repositories, and from where, so that `inix update` can
+/// get them from there again.
+const TEMPLATE_SOURCES_FILE: &str = ".sources.toml";
+
+/// The templates in `inix_dir` that came from git repositories, and
+/// where they came from (as `<url>#<name>`), by name.
+fn read_template_sources(inix_dir: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    let path = inix_dir.join(TEMPLATE_SOURCES_FILE);
+    match fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents)
+            .with_context(|| format!(r#"I was unable to understand "{}"."#, path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e).with_context(|| format!(r#"I was unable to read "{}"."#, path.display())),
+    }
+}
+
+/// Record where the `templates` that were just written to `inix_dir`
+/// came from: the ones from git repositories are added to its sources
+/// file, and any others are taken out of it. The file is removed when
+/// there's nothing left in it. Returns the file, if it was written.
+fn record_template_sources<'a>(
+    inix_dir: &Path,
+    templates: impl IntoIterator<Item = &'a Template2>,
+    dirs: &Dirs,
+) -> anyhow::Result<Option<PathBuf>> {
+    let mut sources = read_template_sources(inix_dir)?;
+    for template in templates {
+        match dirs.remote_template(template.name()) {
+            Some(remote) => sources.insert(template.name().to_string(), remote.source.clone()),
+            None => sources.remove(template.name()),
+        };
+    }
+    write_template_sources(inix_dir, &sources)
+}
+
+fn write_template_sources(
+    inix_dir: &Path,
+    sources: &BTreeMap<String, String>,
+) -> anyhow::Result<Option<PathBuf>> {
+    let path = inix_dir.join(TEMPLATE_SOURCES_FILE);
+    if sources.is_empty() {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!(r#"I was unable to remove "{}"."#, path.display()))
+            }
+            _ => Ok(None),
+        };
+    }
+    let contents = format!(
+        "# Where the templates that came from git repositories came from. inix\n# keeps this up to date.\n{}",
+        toml::to_string(sources)?
+    );
+    fs::write(&path, contents)
+        .with_context(|| format!(r#"I was unable to write "{}"."#, path.display()))?;
+    Ok(Some(path))
+}
+
+/// The name of the directory in the cache that the repository at
+/// `url` is cloned to: a hash of the URL (64-bit FNV-1a, which is
+/// stable across versions of inix and Rust), so that different URLs
+/// never share a clone.
+fn repo_cache_name(url: &str) -> String {
+    let hash = url.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+/// The existing clone of the git repository at `url` in the cache
+/// directory, if there is one, without cloning or updating anything.
+fn cached_template_repo(url: &str, dirs: &Dirs) -> Option<PathBuf> {
+    dirs.cache
+        .as_ref()
+        .map(|dir| dir.join("inix").join("repos").join(repo_cache_name(url)))
+        .filter(|clone_dir| clone_dir.is_dir())
+}
+
+/// Make sure that there's an up-to-date (if `refresh`) clone of the
+/// git repository at `url` in the cache directory, using the `git`
+/// program, and return where it is. Clones are shallow, and a clone
+/// that fails is cleaned up rather than left half-done.
+fn fetch_template_repo(
+    url: &str,
+    dirs: &Dirs,
+    git: &str,
+    refresh: bool,
+) -> anyhow::Result<PathBuf> {
+    let repos_dir = dirs
+        .cache
+        .as_ref()
+        .map(|dir| dir.join("inix").join("repos"))
+        .ok_or_else(|| {
+            anyhow!("I was unable to find your cache directory, so I have nowhere to clone template repositories to.")
+        })?;
+    let clone_dir = repos_dir.join(repo_cache_name(url));
+
+    let run_git = |args: &[&str], dir: &Path| -> anyhow::Result<()> {
+        let output = match Command::new(git).args(args).current_dir(dir).output() {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| {
+                    format!(
+                        r#"You asked me to use templates from "{url}", but I couldn't find "{git}" to get them with. Is git installed and on your PATH?"#
+                    )
+                })
+            }
+            result => result.with_context(|| format!(r#"I was unable to run "{git}"."#))?,
+        };
+        if !output.status.success() {
+            bail!(
+                r#"I was unable to get the templates from "{url}": "{git} {}" failed ({}): {}"#,
+                args.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+        }
+        Ok(())
+    };
+
+    if clone_dir.is_dir() {
+        if refresh {
+            run_git(&["fetch", "--depth", "1", "origin"], &clone_dir)?;
+            run_git(&["reset", "--hard", "FETCH_HEAD"], &clone_dir)?;
+        }
+        return Ok(clone_dir);
+    }
+
+    create_dir_all(&repos_dir).with_context(|| {
+        format!(
+            r#"I was unable to create the directory "{}"."#,
+            repos_dir.display()
+        )
+    })?;
+
+    // clone next to where it should end up and move it into place when
+    // it's done, so that a failed clone is never mistaken for a good one
+    let partial_dir = tempfile::tempdir_in(&repos_dir).with_context(|| {
+        format!(
+            r#"I was unable to create a temporary directory in "{}"."#,
+            repos_dir.display()
+        )
+    })?;
+    let partial_clone = partial_dir.path().join("repo");
+    let partial_clone_str = partial_clone.to_string_lossy();
+    run_git(
+        &["clone", "--depth", "1", "--", url, &partial_clone_str],
+        &repos_dir,
+    )?;
+    fs::rename(&partial_clone, &clone_dir).with_context(|| {
+        format!(
+            r#"I was unable to move the clone of "{url}" to "{}"."#,
+            clone_dir.display()
+        )
+    })?;
+
+    Ok(clone_dir)
+}
+
+/// The user's settings, from `config.toml` in the `inix` directory in
+/// their configuration directory. Every setting is optional, and so is
+/// the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserConfig {
+    /// Where the template registry is: a URL (fetched with curl) or a
+    /// path to a local file. See `RegistryIndex`.
+    registry: Option<String>,
+}
+
+/// Read the user's `config.toml`, if there is one.
+fn read_user_config(dirs: &Dirs) -> anyhow::Result<UserConfig> {
+    let Some(path) = dirs
+        .config
+        .as_ref()
+        .map(|dir| dir.join("inix").join("config.toml"))
+    else {
+        return Ok(UserConfig::default());
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(UserConfig::default()),
+        Err(e) => {
+            return Err(e).with_context(|| format!(r#"I was unable to read "{}"."#, path.display()))
+        }
+    };
+    toml::from_str(&contents)
+        .with_context(|| format!(r#"I was unable to understand "{}"."#, path.display()))
+}
+
+/// A template registry: a list of templates that live in git
+/// repositories, so that they can be found with `inix search` and used
+/// by name. It's a TOML file, or a JSON file if its name ends in
+/// `.json`, with an entry in `templates` for each template.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistryIndex {
+    #[serde(default)]
+    templates: Vec<RegistryEntry>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct RegistryEntry {
+    /// The name that the template is used by.
+    name: String,
+    /// What the template is for.
+    description: Option<String>,
+    /// Where the template is, as `<url>#<name>` (see
+    /// `remote_template_spec`).
+    source: String,
+}
+
+impl Display for RegistryEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.description {
+            Some(description) => write!(f, "{} ({}): {description}", self.name, self.source),
+            None => write!(f, "{} ({})", self.name, self.source),
+        }
+    }
+}
+
+/// The templates in the registry that the user has configured, using
+/// the `curl` program to fetch it if it's not a local file, or `None`
+/// if there's no registry configured.
+fn registry_entries(dirs: &Dirs, curl: &str) -> anyhow::Result<Option<Vec<RegistryEntry>>> {
+    let Some(location) = read_user_config(dirs)?.registry else {
+        return Ok(None);
+    };
+
+    let contents = if location.starts_with("http://") || location.starts_with("https://") {
+        let output = match Command::new(curl)
+            .args(["--fail", "--silent", "--show-error", "--location", &location])
+            .output()
+        {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| {
+                    format!(
+                        r#"I couldn't find "{curl}" to get the template registry from "{location}" with. Is curl installed and on your PATH?"#
+                    )
+                })
+            }
+            result => result.with_context(|| format!(r#"I was unable to run "{curl}"."#))?,
+        };
+        if !output.status.success() {
+            bail!(
+                r#"I was unable to get the template registry from "{location}" ({}): {}"#,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+        }
+        String::from_utf8(output.stdout).with_context(|| {
+            format!(r#"The template registry at "{location}" isn't valid UTF-8."#)
+        })?
+    } else {
+        let path = location.strip_prefix("file://").unwrap_or(&location);
+        fs::read_to_string(path).with_context(|| {
+            format!(r#"I was unable to read the template registry at "{path}"."#)
+        })?
+    };
+
+    let index: RegistryIndex = if location.ends_with(".json") {
+        serde_json::from_str(&contents).map_err(anyhow::Error::from)
+    } else {
+        toml::from_str(&contents).map_err(anyhow::Error::from)
+    }
+    .with_context(|| {
+        format!(r#"I was unable to understand the template registry at "{location}"."#)
+    })?;
+
+    Ok(Some(index.templates))
+}
+
+/// The registry entries whose name or description contains `keyword`,
+/// ignoring case, sorted by name.
+fn search_registry<'a>(entries: &'a [RegistryEntry], keyword: &str) -> Vec<&'a RegistryEntry> {
+    let keyword = keyword.to_lowercase();
+    entries
+        .iter()
+        .filter(|entry| {
+            entry.name.to_lowercase().contains(&keyword)
+                || entry
+                    .description
+                    .as_ref()
+                    .is_some_and(|description| description.to_lowercase().contains(&keyword))
+        })
+        .sorted_by(|a, b| a.name.cmp(&b.name))
+        .collect()
+}
+
+/// Run `git init` in `target_dir`, using the `git` program, unless
+/// there's already a `.git` directory there. Returns whether a
+/// repository was initialized.
+fn git_init(target_dir: &Path, git: &str) -> anyhow::Result<bool> {
+    if target_dir.join(".git").exists() {
+        return Ok(false);
+    }
+
+    let output = match Command::new(git)
+        .arg("init")
+        .current_dir(target_dir)
+        .output()
+    {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| {
+                format!(
+                    r#"You asked me to run "git init", but I couldn't find "{git}". Is git installed and on your PATH? The files have been created, so you can run "git init" in "{}" yourself once it is."#,
+                    target_dir.display()
+                )
+            })
+        }
+        result => result.with_context(|| format!(r#"I was unable to run "{git} init"."#))?,
+    };
+
+    if !output.status.success() {
+        bail!(
+            r#""{git} init" failed in "{}" ({}): {}"#,
+            target_dir.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+    }
+
+    Ok(true)
+}
+
+/// Check that the generated environment can actually be built by
+/// starting a shell with it and running `true`. If nix isn't
+/// installed, the check is skipped with a warning. When `quiet`, only
+/// failures are reported.
+fn smoke_test(target_dir: &Path, flake: bool, quiet: bool) -> anyhow::Result<()> {
+    let mut command = if flake {
+        let mut command = Command::new("nix");
+        command
+            .arg("develop")
+            .arg(target_dir)
+            .args(["--command", "true"]);
+        command
+    } else {
+        let mut command = Command::new("nix-shell");
+        command
+            .arg(target_dir.join("shell.nix"))
+            .args(["--run", "true"]);
+        command
+    };
+
+    // stdout is kept for what inix was asked to print (like the
+    // files for `--print-created`), so progress goes to stderr
+    let program = command.get_program().to_string_lossy().to_string();
+    if quiet {
+        command.stdout(Stdio::null());
+    } else {
+        command.stdout(io::stderr());
+        eprintln!(r#"Checking that the environment works by running "{program}"..."#);
+    }
+
+    match command.status() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!(
+                r#"Warning: I couldn't find "{program}", so I skipped the smoke test. Is nix installed?"#
+            );
+            Ok(())
+        }
+        Err(e) => Err(e)
+            .with_context(|| format!(r#"I was unable to run "{program}" for the smoke test."#)),
+        Ok(status) if status.success() => {
+            if !quiet {
+                eprintln!("The smoke test passed: the environment builds.");
+            }
+            Ok(())
+        }
+        Ok(status) => bail!(
+            r#"The smoke test failed: "{program}" exited with {status}. I have left the generated files in place so that you can take a look."#
+        ),
+    }
+}
+
+/// Whether output should be shown in a pager: only if we're
+/// printing to a terminal and the output doesn't fit in it.
+fn should_page(
+    no_pager: bool,
+    is_terminal: bool,
+    output_lines: usize,
+    terminal_height: Option<usize>,
+) -> bool {
+    match terminal_height {
+        Some(height) => !no_pager && is_terminal && output_lines >= height,
+        None => false,
+    }
+}
+
+/// Print `output` to stdout, going through a pager if it's too long
+/// to fit in the terminal.
+fn print_paged(output: &str, no_pager: bool) -> anyhow::Result<()> {
+    let terminal_height =
+        terminal_size::terminal_size().map(|(_, terminal_size::Height(h))| h as usize);
+
+    if should_page(
+        no_pager,
+        io::stdout().is_terminal(),
+        output.lines().count(),
+        terminal_height,
+    ) {
+        let pager = env::var("PAGER").unwrap_or_else(|_| "less -FRX".into());
+        page(output, &pager)
+    } else {
+        println!("{output}");
+        Ok(())
+    }
+}
+
+/// Show `output` with the `pager` command.
+fn page(output: &str, pager: &str) -> anyhow::Result<()> {
+    let mut child = match Command::new("sh")
+        .arg("-c")
+        .arg(pager)
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        // if the pager can't be started, it's better to just print
+        // the output than to not show anything at all.
+        Err(_) => {
+            println!("{output}");
+            return Ok(());
+        }
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // the pager may exit before it has read everything (e.g. if
+        // you quit it early), so errors here are expected.
+        let _ = writeln!(stdin, "{output}");
+    }
+
+    child
+        .wait()
+        .with_context(|| format!(r#"The pager ("{pager}") failed."#))?;
+    Ok(())
+}
+
+fn combine_strings<T, Item>(strings: T) -> String
+where
+    Item: Display + Ord + Clone,
+    T: Iterator<Item = Item> + Clone,
+{
+    let quote = |item: Item| format!(r#""{}""#, item.to_string());
+
+    match strings.clone().count() {
+        0 | 1 => strings.map(quote).collect(),
+        2 => strings.map(quote).join(" and "),
+        len => strings
+            .enumerate()
+            .map(|(index, value)| {
+                if index == len - 1 {
+                    format!(r#"and {}"#, quote(value))
+                } else {
+                    quote(value)
+                }
+            })
+            .join(", "),
+    }
+}
+
+/// Highlights the important parts of what's printed for people
+/// (template names, conflict behaviors, and warnings) if colors are
+/// enabled. Errors, and anything meant for other tools, stay plain.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Colors {
+    enabled: bool,
+}
+
+impl Colors {
+    /// `no_color` is whether `NO_COLOR` is set (see
+    /// https://no-color.org), which turns colors off whatever the
+    /// `choice` is.
+    fn new(choice: ColorChoice, no_color: bool, is_terminal: bool) -> Self {
+        let enabled = !no_color
+            && match choice {
+                ColorChoice::Auto => is_terminal,
+                ColorChoice::Always => true,
+                ColorChoice::Never => false,
+            };
+        Self { enabled }
+    }
+
+    fn paint(self, code: &str, text: impl Display) -> String {
+        if self.enabled {
+            format!("\x1b[{code}m{text}\x1b[0m")
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn template(self, name: impl Display) -> String {
+        self.paint("1;36", name)
+    }
+
+    fn behavior(self, behavior: impl Display) -> String {
+        self.paint("1", behavior)
+    }
+
+    /// Highlight the "Warning:" that `message` starts with, if any.
+    fn warning(self, message: &str) -> String {
+        match message.strip_prefix("Warning:") {
+            Some(rest) => format!("{}{rest}", self.paint("1;33", "Warning:")),
+            None => message.to_string(),
+        }
+    }
+}
+
+/// The `shell.nix` and `.envrc` templates that the top-level files
+/// are rendered from. A `base` template in your template directory
+/// takes precedence over the builtin one. If it only has one of the
+/// files, the builtin version of the other one is used.
+fn base_template_files(
+    precedence: TemplateDirPrecedence,
+    dirs: &Dirs,
+) -> anyhow::Result<(String, String)> {
+    let (builtin_nix, builtin_envrc) =
+        match included_templates(dirs.builtin.as_deref()).remove("base") {
+            Some(Template2 {
+                files: TemplateFiles2::Both { nix, envrc },
+                ..
+            }) => (nix, envrc),
+            _ => unreachable!("The builtin base template has both a shell.nix and an .envrc"),
+        };
+
+    let base = try_get_templates(&["base".to_string()], precedence, dirs)?.pop();
+
+    Ok(match base.map(|base| base.files) {
+        Some(TemplateFiles2::Both { nix, envrc }) => (nix, envrc),
+        Some(TemplateFiles2::Nix(nix)) => (nix, builtin_envrc),
+        Some(TemplateFiles2::Envrc(envrc)) => (builtin_nix, envrc),
+        Some(TemplateFiles2::Flake { nix, envrc, .. }) => {
+            (nix.unwrap_or(builtin_nix), envrc.unwrap_or(builtin_envrc))
+        }
+        None => (builtin_nix, builtin_envrc),
+    })
+}
+
+/// `nix` without its `#` comments. A `#` in a string doesn't start a
+/// comment.
+fn without_nix_comments(nix: &str) -> String {
+    nix.lines()
+        .map(|line| {
+            let mut in_string = false;
+            let mut escaped = false;
+            for (index, c) in line.char_indices() {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' if in_string => escaped = true,
+                    '"' => in_string = !in_string,
+                    '#' if !in_string => return &line[..index],
+                    _ => {}
+                }
+            }
+            line
+        })
+        .join("\n")
+}
+
+/// Why the packages of a template's `shell.nix` can't be merged into
+/// the top-level `shell.nix`, if they can't. Its packages are only
+/// the same in the top-level `shell.nix` if they refer to nothing but
+/// the plain `pkgs`: not to names bound with `let`, and not to
+/// packages from overlays.
+fn merge_blocker(shell_nix: &str) -> Option<&'static str> {
+    if uses_nix_word(shell_nix, "overlays") {
+        Some("it brings its own nixpkgs overlays")
+    } else if uses_nix_word(shell_nix, "let") {
+        Some("it binds its own names with `let`")
+    } else {
+        None
+    }
+}
+
+/// Whether `word` is one of the identifiers or keywords in `nix`,
+/// outside of comments.
+fn uses_nix_word(nix: &str, word: &str) -> bool {
+    without_nix_comments(nix)
+        .split(|c: char| !(c.is_alphanumeric() || matches!(c, '_' | '-' | '\'')))
+        .any(|candidate| candidate == word)
+}
+
+/// The package expressions listed in the `buildInputs`,
+/// `nativeBuildInputs`, or `packages` of a template's `shell.nix`,
+/// found by reading it as text. Comments are skipped, and anything
+/// other than a plain list (like a concatenation) is ignored.
+fn package_expressions(shell_nix: &str) -> Vec<String> {
+    let without_comments = without_nix_comments(shell_nix);
+
+    let list_items = |list: &str| {
+        let mut items = vec![];
+        let mut current = String::new();
+        let mut depth = 0;
+        let mut in_string = false;
+
+        for c in list.chars() {
+            match c {
+                '"' => {
+                    in_string = !in_string;
+                    current.push(c);
+                }
+                _ if in_string => current.push(c),
+                ']' if depth == 0 => break,
+                '(' | '[' | '{' => {
+                    depth += 1;
+                    current.push(c);
+                }
+                ')' | ']' | '}' => {
+                    depth -= 1;
+                    current.push(c);
+                }
+                c if c.is_whitespace() && depth == 0 => {
+                    if !current.is_empty() {
+                        items.push(std::mem::take(&mut current));
+                    }
+                }
+                c => current.push(c),
+            }
+        }
+
+        if !current.is_empty() {
+            items.push(current);
+        }
+        items
+    };
+
+    ["buildInputs", "nativeBuildInputs", "packages"]
+        .iter()
+        .flat_map(|attribute| {
+            without_comments
+                .match_indices(attribute)
+                .filter_map(|(index, _)| {
+                    without_comments[index + attribute.len()..]
+                        .trim_start()
+                        .strip_prefix('=')
+                        .and_then(|value| value.trim_start().strip_prefix('['))
+                })
+                .flat_map(list_items)
+                .collect::<Vec<_>>()
+        })
+        .unique()
+        .collect()
+}
+
+/// The files of `template`, ready to be written, as (file name,
+/// contents) pairs. The files that inix knows of custom templates are
+/// rendered with Handlebars, using the same data as the base
+/// templates, unless their `inix.toml` says they're `verbatim`.
+/// Builtin templates and any other files are used as they are.
+fn render_template_files(
+    handlebars: &Handlebars,
+    template: &Template2,
+    args: &HashMap<String, JsonValue>,
+) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+    let rendered = template
+        .known_files()
+        .into_iter()
+        .map(|(file_name, contents)| {
+            let verbatim = template
+                .metadata
+                .as_ref()
+                .is_some_and(|metadata| metadata.verbatim.contains(&file_name));
+            let contents = match template.template_type {
+                TemplateType::Builtin => contents.to_string(),
+                TemplateType::Custom if verbatim => contents.to_string(),
+                TemplateType::Custom => {
+                    handlebars.render_template(contents, args).with_context(|| {
+                        format!(
+                            r#"I was unable to render the "{file_name}" file of the "{}" template (found at "{}")."#,
+                            template.name(),
+                            template.source_dir.display()
+                        )
+                    })?
+                }
+            };
+            Ok((file_name, contents.into_bytes()))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(rendered
+        .into_iter()
+        .chain(template.extra_files.iter().cloned())
+        .collect())
+}
+
+/// The Handlebars registry used to render the base templates. The
+/// output is Nix and shell code rather than HTML, so nothing is
+/// escaped. When `strict`, using a variable that isn't set is an
+/// error.
+fn base_handlebars(strict: bool) -> Handlebars<'static> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(handlebars::no_escape);
+    handlebars.set_strict_mode(strict);
+    handlebars
+}
+
+/// The data that's available when rendering the base templates.
+///
+/// `templates` is a list of objects with the `name` of each template,
+/// the `path` to its directory, relative to the project root, the
+/// `packages` its `shell.nix` lists, and whether it's a `flake` and
+/// accepts the top-level files' packages (`shared_pkgs`).
+/// `shell_hooks` has the `name` and the `lines` of the shell hook of
+/// each template that has one. Variables passed with `--set` are
+/// added too, but they can't replace any of inix's own data.
+fn base_template_args(
+    templates: &[Template2],
+    cli: &Cli,
+) -> anyhow::Result<HashMap<String, JsonValue>> {
+    let overlays: Vec<_> = templates
+        .iter()
+        .filter_map(|template| {
+            let overlay = Path::new("inix")
+                .join(template.name())
+                .join(template.overlay()?);
+            Some(overlay.display().to_string())
+        })
+        .collect();
+    let package_source = |template: &Template2| match &template.files {
+        TemplateFiles2::Nix(nix) | TemplateFiles2::Both { nix, .. } => Some(nix.clone()),
+        TemplateFiles2::Flake { flake, .. } => Some(flake.clone()),
+        TemplateFiles2::Envrc(_) => None,
+    };
+    if cli.combine_strategy == CombineStrategy::Merge {
+        let blocked: Vec<_> = templates
+            .iter()
+            .filter_map(|template| {
+                let blocker = merge_blocker(&package_source(template)?)?;
+                Some(format!("- {}: {blocker}", template.name()))
+            })
+            .collect();
+        if !blocked.is_empty() {
+            bail!(
+                "I can't merge the packages of these templates into one list (`--combine-strategy merge`), because they wouldn't mean the same thing there:\n{}\n\nPlease use `--combine-strategy import` with them instead.",
+                blocked.join("\n")
+            )
+        }
+    }
+    let shell_hooks: Vec<_> = templates
+        .iter()
+        .filter_map(|template| {
+            Some(hash_map! {
+                "name" => to_json(template.name()),
+                "lines" => to_json(template.shell_hook()?.trim_end().lines().collect::<Vec<_>>()),
+            })
+        })
+        .collect();
+    let templates: Vec<_> = templates
+        .iter()
+        .map(|template| {
+            let packages = package_source(template)
+                .map(|nix| package_expressions(&nix))
+                .unwrap_or_default();
+            hash_map! {
+                "name" => to_json(template.name()),
+                "path" => to_json(Path::new("inix").join(template.name()).display().to_string()),
+                "packages" => to_json(packages),
+                "flake" => to_json(template.is_flake()),
+                "shared_pkgs" => to_json(template.accepts_shared_pkgs()),
+            }
+        })
+        .collect();
+
+    let mut args: HashMap<String, JsonValue> = hash_map! {
+       "templates".into() => to_json(templates),
+       "shell_hooks".into() => to_json(shell_hooks),
+       "overlays".into() => to_json(overlays),
+       "flake".into() => to_json(cli.emit_flake),
+       "import_templates".into() => to_json(!cli.no_top_level_import),
+       "merge_packages".into() => to_json(cli.combine_strategy == CombineStrategy::Merge),
+       "min_direnv_version".into() => to_json(&cli.envrc_min_direnv),
+       "envrc_prelude".into() => to_json(envrc_prelude(
+           cli.envrc_prelude.as_deref(),
+           cli.envrc_prelude_hash.as_deref(),
+       )?),
+    };
+
+    if let Some((key, _)) = cli.variables.iter().find(|(key, _)| args.contains_key(key)) {
+        bail!(
+            r#"You can't set "{key}" with `--set`, because inix uses that name for its own data. These names are reserved: {}"#,
+            combine_strings(args.keys().sorted())
+        )
+    }
+
+    args.extend(
+        cli.variables
+            .iter()
+            .map(|(key, value)| (key.clone(), to_json(value))),
+    );
+
+    Ok(args)
+}
+
+/// The (template name, variable) pairs for the variables that the
+/// `templates` need that aren't among the `variables`, in order.
+fn missing_variables(
+    templates: &[Template2],
+    variables: &[(String, String)],
+) -> Vec<(String, String)> {
+    templates
+        .iter()
+        .flat_map(|template| {
+            template
+                .required_variables()
+                .iter()
+                .filter(|variable| !variables.iter().any(|(key, _)| key == *variable))
+                .map(|variable| (template.name().to_string(), variable.clone()))
+        })
+        .collect()
+}
+
+const MANAGED_BLOCK_START: &str = "# >>> inix >>>";
+const MANAGED_BLOCK_END: &str = "# <<< inix <<<";
+
+/// Find the first and last line of the inix-managed block in `lines`.
+fn find_managed_block(lines: &[&str]) -> Option<(usize, usize)> {
+    let start = lines
+        .iter()
+        .position(|line| line.trim() == MANAGED_BLOCK_START)?;
+    let end = start
+        + lines[start..]
+            .iter()
+            .position(|line| line.trim() == MANAGED_BLOCK_END)?;
+    Some((start, end))
+}
+
+/// Whether `contents` has a (complete) inix-managed block.
+fn has_managed_block(contents: &str) -> bool {
+    find_managed_block(&contents.lines().collect::<Vec<_>>()).is_some()
+}
+
+/// Replace the inix-managed block in `existing` with the one from
+/// `rendered`, leaving any lines outside of the block untouched. If
+/// either of them doesn't have a (complete) managed block, the
+/// rendered content is used as is.
+fn update_managed_block(existing: &str, rendered: &str) -> String {
+    let existing_lines: Vec<_> = existing.lines().collect();
+    let rendered_lines: Vec<_> = rendered.lines().collect();
+
+    match (
+        find_managed_block(&existing_lines),
+        find_managed_block(&rendered_lines),
+    ) {
+        (Some((start, end)), Some((new_start, new_end))) => existing_lines[..start]
+            .iter()
+            .chain(&rendered_lines[new_start..=new_end])
+            .chain(&existing_lines[end + 1..])
+            .map(|line| format!("{line}\n"))
+            .collect(),
+        _ => rendered.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PromptOption {
+    description: &'static str,
+    short_description: &'static str,
+    conflict_behavior: ConflictBehavior,
+}
+
+impl PromptOption {
+    /// The key you press to choose this option. Each conflict
+    /// behavior always uses the same key, regardless of which other
+    /// options are available, so that muscle memory works across
+    /// prompts.
+    fn key(&self) -> char {
+        match self.conflict_behavior {
+            ConflictBehavior::MergeKeep => 'M',
+            ConflictBehavior::MergeReplace => 'R',
+            ConflictBehavior::Overwrite => 'O',
+            ConflictBehavior::Cancel => 'C',
+        }
+    }
+}
+
+impl Display for PromptOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, r#"{} ({})"#, self.description, self.short_description)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Prompt {
+    text: String,
+    /// The options, ordered from least to most destructive, with
+    /// cancelling last.
+    options: Vec<PromptOption>,
+    colors: Colors,
+}
+
+impl Prompt {
+    fn list_options(&self) -> String {
+        self.options
+            .iter()
+            .map(|prompt_option| {
+                format!(
+                    "- {}: {} ({})",
+                    self.colors.behavior(prompt_option.key()),
+                    prompt_option.description,
+                    self.colors.behavior(prompt_option.short_description)
+                )
+            })
+            .join("\n")
+    }
+
+    fn list_option_keys(&self) -> String {
+        combine_strings(self.options.iter().map(PromptOption::key))
+    }
+
+    fn find_option(&self, input: &str) -> Option<&PromptOption> {
+        self.options
+            .iter()
+            .find(|option| input.trim().eq_ignore_ascii_case(&option.key().to_string()))
+    }
+}
+
+impl Display for Prompt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writedoc!(
+            f,
+            r#"{}
+
+            How would you like to proceed?
+            {}
+
+            Please enter exactly one option (one of {} [case-insensitive])."#,
+            self.text,
+            self.list_options(),
+            self.list_option_keys()
+        )
+    }
+}
+
+/// The prompt to show when the inix directory already exists, or
+/// `None` if there is no conflict to ask about.
+fn conflict_prompt(inix_dir: &InixDir) -> Option<Prompt> {
+    let conflicting_templates = match &inix_dir.state {
+        InixDirState::DoesNotExist => return None,
+        InixDirState::AlreadyExists {
+            template_collisions,
+        } => template_collisions,
+    };
+
+    let merge_replace = PromptOption {
+        description: "Add your templates to the inix directory, overwriting any templates that are there already, but leaving other templates untouched.",
+        short_description: "merge-replace",
+        conflict_behavior: ConflictBehavior::MergeReplace,
+    };
+    let overwrite = PromptOption {
+        description:
+            "Overwrite the entire inix directory, removing anything that exists there already.",
+        short_description: "overwrite",
+        conflict_behavior: ConflictBehavior::Overwrite,
+    };
+    let cancel = PromptOption {
+        description: "Cancel the operation",
+        short_description: "cancel",
+        conflict_behavior: ConflictBehavior::Cancel,
+    };
+
+    let options = match conflicting_templates {
+        TemplateCollisions::None => vec![
+            PromptOption {
+                description: "Merge the two inix directories, adding your new templates to the existing directory?",
+                short_description: "merge",
+                conflict_behavior: ConflictBehavior::MergeKeep,
+            },
+            PromptOption {
+                description: "Overwrite the whole directory, removing everything that's in it and replacing it with the new templates?",
+                ..overwrite
+            },
+            cancel,
+        ],
+        TemplateCollisions::All(_) => vec![merge_replace, overwrite, cancel],
+        TemplateCollisions::Some(_) => vec![
+            PromptOption {
+                description: "Add your templates to the inix directory, but leaving any templates that exist already.",
+                short_description: "merge-keep",
+                conflict_behavior: ConflictBehavior::MergeKeep,
+            },
+            merge_replace,
+            overwrite,
+            cancel,
+        ],
+    };
+
+    Some(Prompt {
+        text: inix_dir.conflict_description(),
+        options,
+        colors: Colors::default(),
+    })
+}
+
+fn prompt_for_conflict_behavior(
+    inix_dir: &InixDir,
+    colors: Colors,
+) -> anyhow::Result<ConflictBehavior> {
+    let mut rl = Editor::<()>::new()?;
+
+    let Some(prompt) = conflict_prompt(inix_dir) else {
+        return Ok(ConflictBehavior::Cancel);
+    };
+    let prompt = Prompt {
+        text: inix_dir.describe_conflict(colors),
+        colors,
+        ..prompt
+    };
+
+    println!();
+    println!("{}", prompt);
+    loop {
+        println!();
+        println!(r#"Tip: You can enter "?" to display the options again."#);
+        let readline = rl.readline(">> ");
+        match readline {
+            Ok(line) if line.trim() == "?" => {
+                println!("{}", prompt);
+            }
+            Ok(line) => {
+                match prompt.find_option(&line) {
+                    Some(option) => return Ok(option.conflict_behavior),
+                    None => println!("\nSorry, I don't understand what you mean. Please use only the character corresponding to the option you want."),
+                }
+            }
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
+                println!("\nUnderstood. I'll cancel the operation.");
+                bail!("The operation was cancelled.");
+            }
+            Err(err) => {
+                println!("\nErr, I got an error that I don't understand: {:?}", err);
+                println!("\nPlease try again or quit the program (Ctrl+C)");
+            }
+        }
+    }
+}
+
+/// Ask for the value of each of the `missing` variables (see
+/// `missing_variables`), once per variable, even if more than one
+/// template needs it. Returns (name, value) pairs.
+///
+/// The questions are asked on the terminal and the rest goes to
+/// stderr, so that stdout only has what inix was asked to print.
+fn prompt_for_variables(missing: &[(String, String)]) -> anyhow::Result<Vec<(String, String)>> {
+    let mut rl =
+        Editor::<()>::with_config(Config::builder().behavior(Behavior::PreferTerm).build())?;
+    let mut variables: Vec<(String, String)> = vec![];
+
+    eprintln!();
+    eprintln!(
+        "Some of the templates need variables that you haven't set. Please enter their values."
+    );
+    for (template, variable) in missing {
+        if variables.iter().any(|(key, _)| key == variable) {
+            continue;
+        }
+        loop {
+            match rl.readline(&format!(
+                r#"{variable} (for the "{template}" template) >> "#
+            )) {
+                Ok(value) => {
+                    variables.push((variable.clone(), value));
+                    break;
+                }
+                Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
+                    eprintln!("\nUnderstood. I'll cancel the operation.");
+                    bail!("The operation was cancelled.");
+                }
+                Err(err) => {
+                    eprintln!("\nErr, I got an error that I don't understand: {:?}", err);
+                    eprintln!("\nPlease try again or quit the program (Ctrl+C)");
+                }
+            }
+        }
+    }
+
+    Ok(variables)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{collections::HashSet, ops::Deref, time::SystemTime};
+
+    use proptest::prelude::*;
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+
+    #[test]
+    /// Verify that the CLI is configured correctly.
+    fn verify_cli() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert()
+    }
+
+    // - `-C` and `--dir` work the same as `--directory`
+    #[test]
+    fn directory_aliases() {
+        for flag in ["-d", "--directory", "--dir", "-C"] {
+            let cli = Cli::try_parse_from(["inix", flag, "some/project", "rust"])
+                .unwrap_or_else(|e| panic!(r#"Failed to parse "{flag}": {e}"#));
+
+            assert_eq!(
+                cli.directory,
+                Some(PathBuf::from("some/project")),
+                r#"The "{flag}" flag did not set the target directory."#
+            );
+            assert_eq!(cli.templates, vec!["rust".to_string()]);
+        }
+    }
+
+    // - `-C` sets up the given directory, and the project's own
+    //   templates are looked for from there
+    #[test]
+    fn c_flag_sets_up_the_given_directory() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let project_dir = tempdir().unwrap();
+        let template_dir = project_dir.path().join(".inix/templates/local");
+        create_dir_all(&template_dir).unwrap();
+        fs::write(template_dir.join("shell.nix"), "# local").unwrap();
+        let target_dir = project_dir.path().join("app");
+
+        let cli =
+            Cli::try_parse_from(["inix", "-C", target_dir.to_str().unwrap(), "local"]).unwrap();
+        run(cli, &dirs).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(target_dir.join("inix/local/shell.nix")).unwrap(),
+            "# local"
+        );
+    }
+
+    // - --require-empty-dir only accepts empty or missing directories
+    #[test]
+    fn it_requires_an_empty_dir_if_asked_to() {
+        let base_dir = tempdir().unwrap();
+        let dir = base_dir.path();
+
+        ensure_dir_is_empty(&dir.join("does-not-exist")).expect("A missing dir should be fine.");
+        ensure_dir_is_empty(dir).expect("An empty dir should be fine.");
+
+        create_dir_all(dir.join(".git")).unwrap();
+        ensure_dir_is_empty(dir).expect("A .git dir should be ignored.");
+
+        fs::write(dir.join("Cargo.toml"), "").unwrap();
+        let (_config_dir, dirs) = isolated_dirs();
+        let error = run(
+            Cli {
+                directory: Some(dir.into()),
+                require_empty_dir: true,
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .expect_err("A non-empty dir should be rejected.");
+
+        assert!(
+            error.to_string().contains("Cargo.toml"),
+            "The error doesn't list the existing entries: {error}"
+        );
+        assert!(
+            !dir.join("shell.nix").exists(),
+            "Files were written to the non-empty dir."
+        );
+    }
+
+    // - a template list resolves to the same templates as arguments
+    #[test]
+    fn template_lists_are_split() {
+        let parse = |args: &[&str]| {
+            let cli = Cli::try_parse_from(["inix"].iter().chain(args)).unwrap();
+            requested_templates(&cli).unwrap()
+        };
+
+        let expected = parse(&["rust", "node"]);
+
+        assert_eq!(parse(&["--templates", "rust,node"]), expected);
+        assert_eq!(parse(&["--templates", " rust , node "]), expected);
+        assert_eq!(parse(&["rust", "--templates", "node,rust"]), expected);
+        assert_eq!(
+            parse(&["--templates", "rust;node", "--template-separator", ";"]),
+            expected
+        );
+
+        for list in ["rust,,node", "rust,", ","] {
+            let cli = Cli::try_parse_from(["inix", "--templates", list]).unwrap();
+            assert!(
+                requested_templates(&cli).is_err(),
+                r#"The list "{list}" has an empty template name, but was accepted anyway."#
+            );
+        }
+    }
+
+    // - target directories are normalized, so equivalent paths give
+    //   the same target and inix directory
+    #[test]
+    fn target_dirs_are_normalized() {
+        let target = |dir: &str| try_get_target_dir(Some(dir.into())).unwrap();
+
+        assert_eq!(target("./foo/../bar/"), PathBuf::from("bar"));
+        assert_eq!(target("./foo/../bar/"), target("bar"));
+        assert_eq!(
+            target("./foo/../bar/").join("inix"),
+            target("bar").join("inix")
+        );
+
+        assert_eq!(target("/a/./b/../c/"), PathBuf::from("/a/c"));
+        assert_eq!(target("/.."), PathBuf::from("/"));
+        assert_eq!(target("../x/./y/.."), PathBuf::from("../x"));
+        assert_eq!(target("foo/.."), PathBuf::from("."));
+    }
+
+    // - it uses the provided directory without looking at the current
+    //   working directory
+    #[test]
+    fn target_dir_does_not_depend_on_cwd() {
+        let base_dir = tempdir().unwrap();
+
+        let new_dir = base_dir.path().join("does/not/exist/yet");
+        assert_eq!(
+            try_get_target_dir(Some(new_dir.clone())).unwrap(),
+            new_dir,
+            "A directory that doesn't exist yet should be accepted as is."
+        );
+
+        let existing_dir = base_dir.path().to_path_buf();
+        assert_eq!(
+            try_get_target_dir(Some(existing_dir.clone())).unwrap(),
+            existing_dir,
+            "An existing directory should be accepted as is."
+        );
+
+        let file = base_dir.path().join("file");
+        fs::File::create(&file).unwrap();
+        assert!(
+            try_get_target_dir(Some(file)).is_err(),
+            "A file should not be accepted as the target directory."
+        );
+    }
+
+    // - target directories in the nix store are refused with a
+    //   message about the store, whether they exist or not, and
+    //   whether they're reached through a symlink or not
+    #[test]
+    fn nix_store_targets_are_refused() {
+        let message = ensure_not_in_nix_store(
+            Path::new("/nix/store/abc123-source/project"),
+            Path::new("/nix/store"),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(message.contains("nix store"), "{message}");
+        assert!(message.contains("read-only"), "{message}");
+        assert!(
+            message.contains("/nix/store/abc123-source/project"),
+            "{message}"
+        );
+
+        let base_dir = tempdir().unwrap();
+        let store = base_dir.path().join("store");
+        create_dir_all(store.join("abc123-source")).unwrap();
+        assert!(ensure_not_in_nix_store(&store.join("abc123-source/new"), &store).is_err());
+        assert!(ensure_not_in_nix_store(&base_dir.path().join("project"), &store).is_ok());
+        assert!(ensure_not_in_nix_store(&base_dir.path().join("storefront"), &store).is_ok());
+
+        #[cfg(unix)]
+        {
+            let link = base_dir.path().join("link");
+            std::os::unix::fs::symlink(store.join("abc123-source"), &link).unwrap();
+            assert!(ensure_not_in_nix_store(&link, &store).is_err());
+        }
+    }
+
+    // - custom template files that exist but can't be read are
+    //   reported instead of being treated as missing
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_template_files_are_reported() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let template_dir = tempdir().unwrap();
+        let shell_nix = template_dir.path().join("shell.nix");
+        fs::write(&shell_nix, "{ }").unwrap();
+        fs::set_permissions(&shell_nix, fs::Permissions::from_mode(0o000)).unwrap();
+
+        if fs::read_to_string(&shell_nix).is_ok() {
+            // we're probably running as root, so permissions don't
+            // stop us from reading the file.
+            return;
+        }
+
+        let error = read_custom_template_files(template_dir.path())
+            .expect_err("Reading an unreadable template should fail.");
+        let kind = error.downcast_ref::<io::Error>().map(io::Error::kind);
+
+        assert_eq!(kind, Some(io::ErrorKind::PermissionDenied));
+        assert!(
+            format!("{error:#}").contains(&shell_nix.display().to_string()),
+            "The error doesn't mention the unreadable file: {error:#}"
+        );
+    }
+
+    // - custom template files that aren't valid UTF-8 are reported
+    //   instead of being treated as missing
+    #[test]
+    fn non_utf8_template_files_are_reported() {
+        let template_dir = tempdir().unwrap();
+        let envrc = template_dir.path().join(".envrc");
+        fs::write(template_dir.path().join("shell.nix"), "{ }").unwrap();
+        fs::write(&envrc, [0xff, 0xfe, 0xfd]).unwrap();
+
+        let error = read_custom_template_files(template_dir.path())
+            .expect_err("Reading a template with a non-UTF-8 file should fail.");
+        let kind = error.downcast_ref::<io::Error>().map(io::Error::kind);
+
+        assert_eq!(kind, Some(io::ErrorKind::InvalidData));
+        assert!(
+            format!("{error:#}").contains(&envrc.display().to_string()),
+            "The error doesn't mention the non-UTF-8 file: {error:#}"
+        );
+    }
+
+    // - template names that would point outside of the template and
+    //   inix directories are rejected before anything is created
+    #[test]
+    fn template_names_cant_escape() {
+        let base_dir = tempdir().unwrap();
+        let target_dir = base_dir.path().join("project");
+        let (_config_dir, dirs) = isolated_dirs();
+
+        let error = run(
+            Cli {
+                templates: vec!["../evil".into(), "node".into()],
+                directory: Some(target_dir.clone()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .expect_err("A template name with a path in it should be rejected.");
+        assert!(
+            error.to_string().contains("- ../evil"),
+            "The error doesn't mention the bad name: {error}"
+        );
+        assert!(!target_dir.exists());
+
+        for name in ["..", ".", "/etc", "a/b", ""] {
+            assert!(!is_plain_name(name), r#""{name}" was accepted"#);
+        }
+        for name in ["rust", "my-template", "..hidden", "a.b"] {
+            assert!(is_plain_name(name), r#""{name}" was rejected"#);
+        }
+    }
+
+    // - templates in the config dir shadow builtins by default, and
+    //   builtins shadow them if the precedence is flipped
+    #[test]
+    fn template_dir_precedence_decides_who_wins() {
+        let config_dir = tempdir().unwrap();
+        let rust_dir = config_dir.path().join("rust");
+        fs::create_dir(&rust_dir).unwrap();
+        fs::write(rust_dir.join("shell.nix"), "{ }").unwrap();
+
+        let custom_dirs = [config_dir.path()];
+        let source = |precedence| {
+            find_template("rust", &custom_dirs, &[], precedence, None)
+                .unwrap()
+                .expect("The rust template wasn't found.")
+                .template_type
+        };
+
+        assert_eq!(
+            source(TemplateDirPrecedence::default()),
+            TemplateType::Custom
+        );
+        assert_eq!(source(TemplateDirPrecedence::Before), TemplateType::Custom);
+        assert_eq!(source(TemplateDirPrecedence::After), TemplateType::Builtin);
+
+        // templates that only exist in one place are found either way
+        for precedence in [TemplateDirPrecedence::Before, TemplateDirPrecedence::After] {
+            assert!(find_template("node", &custom_dirs, &[], precedence, None)
+                .unwrap()
+                .is_some());
+            assert!(
+                find_template("missing", &custom_dirs, &[], precedence, None)
+                    .unwrap()
+                    .is_none()
+            );
+        }
+    }
+
+    // - it warns about templates that use a flake when there won't be
+    //   one
+    #[test]
+    fn it_warns_about_missing_flakes() {
+        let template = |name: &str, envrc: &str| Template2 {
+            name: name.into(),
+            files: TemplateFiles2::Envrc(envrc.into()),
+            extra_files: vec![],
+            empty_dirs: vec![],
+            metadata: None,
+            source_dir: PathBuf::from("inix/templates"),
+            template_type: TemplateType::Custom,
+        };
+        let templates = [
+            template("flaky", "# uses the flake\nuse flake\n"),
+            template("flaky-with-args", "use flake .#dev"),
+            template("nixy", "use nix\n# use flake"),
+        ];
+
+        let warnings = flake_warnings(&templates, false);
+        assert_eq!(warnings.len(), 2, "Unexpected warnings: {warnings:#?}");
+        assert!(warnings[0].contains(r#""flaky""#));
+        assert!(warnings[1].contains(r#""flaky-with-args""#));
+        assert!(warnings
+            .iter()
+            .all(|warning| warning.contains("--emit-flake")));
+
+        assert!(flake_warnings(&templates, true).is_empty());
+    }
+
+    // - --git-init creates a git repository, but not if there already
+    //   is one
+    #[test]
+    fn it_runs_git_init() {
+        let error = git_init(tempdir().unwrap().path(), "inix-test-missing-git")
+            .expect_err("Running a missing git should fail.");
+        assert!(
+            format!("{error:#}").contains("Is git installed"),
+            "Unhelpful error: {error:#}"
+        );
+
+        let existing = tempdir().unwrap();
+        create_dir_all(existing.path().join(".git")).unwrap();
+        assert!(!git_init(existing.path(), "inix-test-missing-git").unwrap());
+        assert!(fs::read_dir(existing.path().join(".git"))
+            .unwrap()
+            .next()
+            .is_none());
+
+        if Command::new("git").arg("--version").output().is_ok() {
+            let (_config_dir, dirs) = isolated_dirs();
+            let target_dir = tempdir().unwrap();
+            run(
+                Cli {
+                    templates: vec!["rust".into()],
+                    directory: Some(target_dir.path().into()),
+                    git_init: true,
+                    ..Default::default()
+                },
+                &dirs,
+            )
+            .unwrap();
+            assert!(target_dir.path().join(".git").is_dir());
+        }
+    }
+
+    // - templates can come from a git repository, which is cloned
+    //   once and only updated when asked to
+    #[test]
+    fn it_uses_templates_from_git_repos() {
+        let (_config_dir, dirs) = isolated_dirs();
+
+        let error = fetch_template_repo("/nowhere", &dirs, "inix-test-missing-git", false)
+            .expect_err("Cloning with a missing git should fail.");
+        assert!(
+            format!("{error:#}").contains("Is git installed"),
+            "Unhelpful error: {error:#}"
+        );
+
+        if Command::new("git").arg("--version").output().is_err() {
+            return;
+        }
+
+        let git = |dir: &Path, args: &[&str]| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=inix", "-c", "user.email=inix@example.com"])
+                .args(args)
+                .current_dir(dir)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {args:?} failed");
+        };
+        let repo = tempdir().unwrap();
+        create_dir_all(repo.path().join("remote")).unwrap();
+        let write_template = |content: &str| {
+            fs::write(repo.path().join("remote/shell.nix"), content).unwrap();
+            git(repo.path(), &["add", "."]);
+            git(repo.path(), &["commit", "-q", "-m", content]);
+        };
+        git(repo.path(), &["init", "-q"]);
+        write_template("# first");
+
+        // listing templates and dry runs don't clone anything, and
+        // dry runs say so
+        let repo_url = repo.path().display().to_string();
+        let dry_run_dir = tempdir().unwrap();
+        let mut out = vec![];
+        for cli in [
+            Cli {
+                list: true,
+                ..Default::default()
+            },
+            Cli {
+                dry_run: true,
+                format: OutputFormat::Json,
+                directory: Some(dry_run_dir.path().into()),
+                ..Default::default()
+            },
+        ] {
+            out.clear();
+            let cli = Cli {
+                template_repos: vec![repo_url.clone()],
+                ..cli
+            };
+            run_to(cli, &dirs, &mut out).unwrap();
+            assert!(cached_template_repo(&repo_url, &dirs).is_none());
+        }
+        let plan: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(plan["cached_repos"], serde_json::json!([repo_url]));
+
+        let instantiate = |refresh_templates| {
+            let project_dir = tempdir().unwrap();
+            run(
+                Cli {
+                    templates: vec!["remote".into()],
+                    template_repos: vec![repo.path().display().to_string()],
+                    refresh_templates,
+                    directory: Some(project_dir.path().into()),
+                    ..Default::default()
+                },
+                &dirs,
+            )
+            .unwrap();
+            fs::read_to_string(project_dir.path().join("inix/remote/shell.nix")).unwrap()
+        };
+
+        assert_eq!(instantiate(false), "# first");
+        write_template("# second");
+        assert_eq!(instantiate(false), "# first");
+        assert_eq!(instantiate(true), "# second");
+
+        // a failed clone doesn't leave anything behind
+        let missing = repo.path().join("missing");
+        fetch_template_repo(&missing.display().to_string(), &dirs, "git", false)
+            .expect_err("Cloning a missing repository should fail.");
+        let repos: Vec<_> = fs::read_dir(dirs.cache.as_ref().unwrap().join("inix/repos"))
+            .unwrap()
+            .collect();
+        assert_eq!(repos.len(), 1, "A failed clone was left behind.");
+
+        // URLs that only differ in punctuation get clones of their own
+        assert_ne!(
+            repo_cache_name("https://example.com/a-b"),
+            repo_cache_name("https://example.com/a_b")
+        );
+        assert_eq!(repo_cache_name(""), "cbf29ce484222325");
+
+        // a template can be asked for along with its repository, and
+        // then it's taken from there even if there's another one with
+        // the same name
+        let project_dir = tempdir().unwrap();
+        create_dir_all(project_dir.path().join(".inix/templates/remote")).unwrap();
+        fs::write(
+            project_dir.path().join(".inix/templates/remote/shell.nix"),
+            "# local",
+        )
+        .unwrap();
+        let url = format!("file://{}", repo.path().display());
+        run(
+            Cli {
+                templates: vec![format!("{url}#remote"), "rust".into()],
+                directory: Some(project_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(project_dir.path().join("inix/remote/shell.nix")).unwrap(),
+            "# second"
+        );
+        assert!(project_dir.path().join("inix/rust/shell.nix").is_file());
+
+        // ...also when it's in the `--templates` list
+        let listed_dir = tempdir().unwrap();
+        run(
+            Cli {
+                template_list: Some(format!("rust,{url}#remote")),
+                directory: Some(listed_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(listed_dir.path().join("inix/remote/shell.nix")).unwrap(),
+            "# second"
+        );
+
+        let error = run(
+            Cli {
+                templates: vec![url.clone()],
+                directory: Some(project_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .expect_err("There's no template name.");
+        assert!(error.to_string().contains(r#"#rust"#), "{error}");
+
+        // the template has to be in the repository, even if there's
+        // another one with the same name
+        let error = run(
+            Cli {
+                templates: vec![format!("{url}#node")],
+                directory: Some(project_dir.path().into()),
+                on_conflict: Some(ConflictBehavior::MergeReplace),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .expect_err("There's no node template in the repository.");
+        assert!(
+            error.to_string().contains(r#"no template called "node""#),
+            "{error}"
+        );
+
+        // it's used over a builtin template whatever the precedence,
+        // and updating takes it from the repository again
+        create_dir_all(repo.path().join("rust")).unwrap();
+        fs::write(repo.path().join("rust/shell.nix"), "# remote rust").unwrap();
+        git(repo.path(), &["add", "."]);
+        git(repo.path(), &["commit", "-q", "-m", "rust"]);
+        let project_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec![format!("{url}#rust")],
+                template_dir_precedence: TemplateDirPrecedence::After,
+                refresh_templates: true,
+                directory: Some(project_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        let rust = project_dir.path().join("inix/rust/shell.nix");
+        assert_eq!(fs::read_to_string(&rust).unwrap(), "# remote rust");
+        fs::write(repo.path().join("rust/shell.nix"), "# newer rust").unwrap();
+        git(repo.path(), &["commit", "-q", "-am", "newer rust"]);
+        run(
+            Cli {
+                action: Some(Action::Update { templates: vec![] }),
+                directory: Some(project_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(&rust).unwrap(), "# newer rust");
+
+        assert_eq!(remote_template_spec("c#").unwrap(), None);
+        assert_eq!(
+            remote_template_spec("git@github.com:org/templates.git#rust").unwrap(),
+            Some(("git@github.com:org/templates.git", "rust"))
+        );
+    }
+
+    // - `inix search` lists the registry's templates that match, and
+    //   templates from the registry can be used by name
+    #[test]
+    fn templates_can_be_found_in_a_registry() {
+        let (config_dir, dirs) = isolated_dirs();
+        let search = |keyword: &str| {
+            let mut out = vec![];
+            run_to(
+                Cli {
+                    action: Some(Action::Search {
+                        keyword: keyword.into(),
+                    }),
+                    ..Default::default()
+                },
+                &dirs,
+                &mut out,
+            )
+            .map(|_| String::from_utf8(out).unwrap())
+        };
+
+        let error = search("rust").expect_err("There's no registry yet.");
+        assert!(error.to_string().contains("config.toml"), "{error}");
+
+        let repo = tempdir().unwrap();
+        let registry = config_dir.path().join("registry.toml");
+        fs::write(
+            &registry,
+            formatdoc!(
+                r#"
+                [[templates]]
+                name = "zig"
+                description = "The Zig compiler and ZLS"
+                source = "file://{repo}#zig"
+
+                [[templates]]
+                name = "nightly-rust"
+                source = "file://{repo}#rust"
+                "#,
+                repo = repo.path().display()
+            ),
+        )
+        .unwrap();
+        create_dir_all(config_dir.path().join("inix")).unwrap();
+        fs::write(
+            config_dir.path().join("inix/config.toml"),
+            format!("registry = {:?}", registry.display().to_string()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            search("ZLS").unwrap(),
+            format!(
+                "zig (file://{}#zig): The Zig compiler and ZLS\n",
+                repo.path().display()
+            )
+        );
+        assert_eq!(search("r").unwrap().lines().count(), 2);
+        assert_eq!(search("python").unwrap(), "");
+
+        if Command::new("git").arg("--version").output().is_err() {
+            return;
+        }
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=inix", "-c", "user.email=inix@example.com"])
+                .args(args)
+                .current_dir(repo.path())
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {args:?} failed");
+        };
+        create_dir_all(repo.path().join("zig")).unwrap();
+        fs::write(repo.path().join("zig/shell.nix"), "# zig").unwrap();
+        create_dir_all(repo.path().join("rust")).unwrap();
+        fs::write(repo.path().join("rust/shell.nix"), "# nightly").unwrap();
+        git(&["init", "-q"]);
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "zig"]);
+
+        let project_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["zig".into(), "rust".into(), "nightly-rust".into()],
+                template_dir_precedence: TemplateDirPrecedence::After,
+                directory: Some(project_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(project_dir.path().join("inix/zig/shell.nix")).unwrap(),
+            "# zig"
+        );
+        // builtin templates aren't looked up in the registry, and
+        // templates from it are set up under the registry's name for
+        // them
+        assert_eq!(
+            fs::read_to_string(project_dir.path().join("inix/rust/shell.nix")).unwrap(),
+            include_str!("templates/rust/shell.nix")
+        );
+        assert_eq!(
+            fs::read_to_string(project_dir.path().join("inix/nightly-rust/shell.nix")).unwrap(),
+            "# nightly"
+        );
+
+        // ...also when they're in the `--templates` list
+        let listed_dir = tempdir().unwrap();
+        run(
+            Cli {
+                template_list: Some("node,zig".into()),
+                directory: Some(listed_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(listed_dir.path().join("inix/zig/shell.nix")).unwrap(),
+            "# zig"
+        );
+    }
+
+    // - names that are local templates or unique prefixes of them are
+    //   resolved without asking the registry, so a registry that can't
+    //   be reached doesn't get in the way
+    #[test]
+    fn prefixes_are_completed_without_the_registry() {
+        let (config_dir, dirs) = isolated_dirs();
+        create_dir_all(config_dir.path().join("inix")).unwrap();
+        fs::write(
+            config_dir.path().join("inix/config.toml"),
+            format!(
+                "registry = {:?}",
+                config_dir
+                    .path()
+                    .join("missing/registry.toml")
+                    .display()
+                    .to_string()
+            ),
+        )
+        .unwrap();
+
+        let project_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["ru".into()],
+                directory: Some(project_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        assert!(project_dir.path().join("inix/rust/shell.nix").exists());
+
+        // names that are neither are still looked up in it
+        let error = run(
+            Cli {
+                templates: vec!["zig".into()],
+                directory: Some(project_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .expect_err("The registry can't be read.");
+        assert!(format!("{error:#}").contains("registry"), "{error:#}");
+    }
+
+    // - when direnv allow fails, rolling back removes what was created
+    //   and puts back what was replaced, but leaves everything else
+    #[cfg(unix)]
+    #[test]
+    fn failed_allows_can_be_rolled_back() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let stub_dir = tempdir().unwrap();
+        let direnv = stub_dir.path().join("direnv");
+        fs::write(&direnv, "#!/bin/sh\necho 'bad .envrc' >&2\nexit 1\n").unwrap();
+        fs::set_permissions(&direnv, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let target_dir = tempdir().unwrap();
+        let path = |name: &str| target_dir.path().join(name);
+        fs::write(path("README.md"), "# mine").unwrap();
+        fs::write(path("shell.nix"), "# the old one").unwrap();
+
+        let mut staging = Staging::default();
+        let inix_dir = staging.stage_inix_dir(&path("inix"), false).unwrap();
+        fs::write(inix_dir.join("template.nix"), "{ }").unwrap();
+        staging
+            .stage_file(&path("shell.nix"), "# new", false)
+            .unwrap();
+        staging
+            .stage_file(&path(".envrc"), "use nix", false)
+            .unwrap();
+        let mut committed = staging.commit().unwrap();
+        create_dir_all(path(".git")).unwrap();
+        committed.track_created(path(".git"));
+        assert_eq!(fs::read_to_string(path("shell.nix")).unwrap(), "# new");
+
+        let error = allow_or_roll_back(committed, target_dir.path(), &direnv.display().to_string())
+            .expect_err("The failing direnv allow should be reported.");
+        let message = format!("{error:#}");
+        assert!(message.contains("rolled back"), "{message}");
+        assert!(message.contains("bad .envrc"), "{message}");
+
+        assert!(!path("inix").exists());
+        assert!(!path(".envrc").exists());
+        assert!(!path(".git").exists());
+        assert_eq!(
+            fs::read_to_string(path("shell.nix")).unwrap(),
+            "# the old one"
+        );
+        assert_eq!(fs::read_to_string(path("README.md")).unwrap(), "# mine");
+    }
+
+    // - --auto-allow runs direnv allow on the target directory
+    #[cfg(unix)]
+    #[test]
+    fn it_runs_direnv_allow() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let stub_dir = tempdir().unwrap();
+        let calls = stub_dir.path().join("calls");
+        let direnv = stub_dir.path().join("direnv");
+        fs::write(
+            &direnv,
+            format!("#!/bin/sh\necho \"$@\" >> '{}'\n", calls.display()),
+        )
+        .unwrap();
+        fs::set_permissions(&direnv, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let target_dir = tempdir().unwrap();
+        direnv_allow(target_dir.path(), &direnv.display().to_string()).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&calls).unwrap().trim(),
+            format!("allow {}", target_dir.path().display())
+        );
+
+        // without direnv, the error should say what to do
+        let error = direnv_allow(target_dir.path(), "inix-test-missing-direnv")
+            .expect_err("Running a missing direnv should fail.");
+        assert!(
+            format!("{error:#}").contains("Is direnv installed"),
+            "Unhelpful error: {error:#}"
+        );
+    }
+
+    // - custom templates are read from the config dir we're given
+    #[test]
+    fn it_reads_templates_from_the_given_config_dir() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = config_dir.path().join("inix").join("my-template");
+        create_dir_all(&template_dir).unwrap();
+        fs::write(template_dir.join("shell.nix"), "{ }").unwrap();
+
+        let templates =
+            try_get_templates(&["my-template".to_string()], Default::default(), &dirs).unwrap();
+
+        assert_eq!(templates.len(), 1);
+        assert_eq!(templates[0].template_type, TemplateType::Custom);
+        assert_eq!(templates[0].source_dir, template_dir);
+        assert_eq!(
+            templates[0].known_files(),
+            vec![("shell.nix".to_string(), "{ }")]
+        );
+    }
+
+    // - template locations are the template's directory for custom
+    //   templates and a marker for builtins
+    #[test]
+    fn it_prints_template_locations() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = config_dir.path().join("inix").join("mine");
+        create_dir_all(&template_dir).unwrap();
+        fs::write(template_dir.join(".envrc"), "use nix").unwrap();
+
+        let location = |name: &str| {
+            try_get_templates(&[name.to_string()], Default::default(), &dirs)
+                .unwrap()
+                .iter()
+                .map(template_location)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(location("mine"), vec![template_dir.display().to_string()]);
+        assert_eq!(location("rust"), vec!["<builtin>".to_string()]);
+    }
+
+    // - a project's .inix/templates directory is found from any
+    //   directory below it, and wins over the builtin templates
+    #[test]
+    fn project_templates_are_found_from_below() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let repo = tempdir().unwrap();
+        let template_dir = repo.path().join(".inix").join("templates").join("rust");
+        create_dir_all(&template_dir).unwrap();
+        fs::write(template_dir.join("shell.nix"), "# the project's own").unwrap();
+        let target_dir = repo.path().join("crates").join("app");
+
+        assert_eq!(
+            find_local_template_dir(&target_dir),
+            Some(repo.path().join(".inix").join("templates"))
+        );
+
+        run(
+            Cli {
+                templates: vec!["rust".into()],
+                directory: Some(target_dir.clone()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(target_dir.join("inix").join("rust").join("shell.nix")).unwrap(),
+            "# the project's own"
+        );
+    }
+
+    // - --list shows builtin and custom templates with their files,
+    //   and which one wins when they share a name
+    #[test]
+    fn it_lists_templates() {
+        let (config_dir, dirs) = isolated_dirs();
+        for name in ["rust", "mine"] {
+            let template_dir = config_dir.path().join("inix").join(name);
+            create_dir_all(&template_dir).unwrap();
+            fs::write(template_dir.join("shell.nix"), "{ }").unwrap();
+        }
+        create_dir_all(config_dir.path().join("inix").join("empty")).unwrap();
+
+        let custom = |name| {
+            let path = config_dir.path().join("inix").join(name);
+            format!("custom, from {}", path.display())
+        };
+
+        assert_eq!(
+            template_listing(TemplateDirPrecedence::Before, &dirs).unwrap(),
+            vec![
+                "flake (builtin; flake.nix)".to_string(),
+                format!("mine ({}; shell.nix)", custom("mine")),
+                "node (builtin; .envrc, shell.nix)".to_string(),
+                format!("rust ({}; shell.nix)", custom("rust")),
+                "rust (builtin, shadowed by the custom template)".to_string(),
+            ]
+        );
+
+        assert_eq!(
+            template_listing(TemplateDirPrecedence::After, &dirs).unwrap()[3..],
+            vec![
+                "rust (builtin; .envrc, shell.nix)".to_string(),
+                format!(
+                    "rust ({}, shadowed by the builtin template)",
+                    custom("rust")
+                ),
+            ]
+        );
+
+        // a broken template that's shadowed is named after the one
+        // that shadows it
+        fs::write(
+            config_dir.path().join("inix/rust/inix.toml"),
+            "not_a_key = true",
+        )
+        .unwrap();
+        let listing = template_listing(TemplateDirPrecedence::After, &dirs).unwrap();
+        assert!(
+            listing[4].starts_with("rust (broken, shadowed by the builtin template: "),
+            "{listing:#?}"
+        );
+    }
+
+    // - `inix list` lists the templates, but templates can still be
+    //   called "list" when they're passed with --templates
+    #[test]
+    fn list_is_a_command() {
+        let cli = Cli::try_parse_from(["inix", "list"]).unwrap();
+        assert_eq!(cli.action, Some(Action::List));
+        assert!(cli.templates.is_empty());
+
+        let cli = Cli::try_parse_from(["inix", "rust", "node"]).unwrap();
+        assert_eq!(cli.action, None);
+        assert_eq!(cli.templates, vec!["rust", "node"]);
+
+        let cli = Cli::try_parse_from(["inix", "--templates", "list"]).unwrap();
+        assert_eq!(cli.action, None);
+
+        let (_config_dir, dirs) = isolated_dirs();
+        let mut out = vec![];
+        run_to(
+            Cli::try_parse_from(["inix", "list"]).unwrap(),
+            &dirs,
+            &mut out,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("rust (builtin; .envrc, shell.nix)\n"), "{out}");
+    }
+
+    // - with --flake, the project gets a flake.nix and an .envrc that
+    //   uses it, but no top-level shell.nix
+    #[test]
+    fn projects_can_be_flake_only() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        let cli = || Cli {
+            templates: vec!["rust".into(), "node".into()],
+            directory: Some(base_dir.path().into()),
+            flake: true,
+            ..Default::default()
+        };
+
+        let inix_dir_path = base_dir.path().join("inix");
+        let templates = try_get_templates(&cli().templates, Default::default(), &dirs).unwrap();
+        let planned = plan(
+            &Cli {
+                emit_flake: true,
+                ..cli()
+            },
+            base_dir.path(),
+            &InixDir {
+                path: &inix_dir_path,
+                state: InixDirState::DoesNotExist,
+            },
+            &templates,
+            ConflictBehavior::Cancel,
+        );
+        assert!(!planned
+            .files
+            .iter()
+            .any(|file| file.path == base_dir.path().join("shell.nix")));
+        assert!(!planned
+            .steps()
+            .iter()
+            .any(|step| step.contains("shell.nix\" so")));
+
+        run(cli(), &dirs).unwrap();
+
+        assert!(!base_dir.path().join("shell.nix").exists());
+        let flake = fs::read_to_string(base_dir.path().join("flake.nix")).unwrap();
+        assert!(flake.contains("devShells.default"), "{flake}");
+        // the rust template keeps the nixpkgs it sets up with its own
+        // overlay, while the others use the flake's
+        assert!(
+            flake.contains("(import ./inix/rust/shell.nix { })"),
+            "{flake}"
+        );
+        assert!(
+            flake.contains("(import ./inix/node/shell.nix { inherit pkgs; })"),
+            "{flake}"
+        );
+        let envrc = fs::read_to_string(base_dir.path().join(".envrc")).unwrap();
+        assert!(envrc.contains("use flake"), "{envrc}");
+        assert!(!envrc.contains("use nix"), "{envrc}");
+
+        // and it stays that way when templates are removed
+        run(
+            Cli {
+                action: Some(Action::Remove {
+                    templates: vec!["node".into()],
+                }),
+                directory: Some(base_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        assert!(!base_dir.path().join("shell.nix").exists());
+        let flake = fs::read_to_string(base_dir.path().join("flake.nix")).unwrap();
+        assert!(!flake.contains("./inix/node/shell.nix"), "{flake}");
+    }
+
+    // - `inix remove` removes templates from the inix directory and
+    //   from the top-level files, and leaves the rest alone
+    #[test]
+    fn templates_can_be_removed() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        let cli = |action| Cli {
+            action,
+            templates: vec!["rust".into(), "node".into()],
+            directory: Some(base_dir.path().into()),
+            ..Default::default()
+        };
+        run(cli(None), &dirs).unwrap();
+        let envrc_path = base_dir.path().join(".envrc");
+        let mut envrc = fs::read_to_string(&envrc_path).unwrap();
+        envrc.push_str("export MINE=1\n");
+        fs::write(&envrc_path, envrc).unwrap();
+
+        let remove = |template: &str| {
+            Some(Action::Remove {
+                templates: vec![template.into()],
+            })
+        };
+        run(cli(remove("rust")), &dirs).unwrap();
+
+        let inix_dir = base_dir.path().join("inix");
+        assert!(!inix_dir.join("rust").exists());
+        assert!(inix_dir.join("node/shell.nix").is_file());
+        let shell_nix = fs::read_to_string(base_dir.path().join("shell.nix")).unwrap();
+        assert!(!shell_nix.contains("inix/rust"), "{shell_nix}");
+        assert!(shell_nix.contains("./inix/node/shell.nix"), "{shell_nix}");
+        let envrc = fs::read_to_string(&envrc_path).unwrap();
+        assert!(!envrc.contains("inix/rust"), "{envrc}");
+        assert!(envrc.contains("inix/node/.envrc"), "{envrc}");
+        assert!(envrc.contains("export MINE=1"), "{envrc}");
+
+        let error = run(cli(remove("rust")), &dirs).expect_err("rust is gone already.");
+        assert!(error.to_string().contains(r#""rust""#), "{error}");
+        assert!(run(cli(remove("../inix")), &dirs).is_err());
+        assert!(inix_dir.join("node").exists());
+
+        assert!(Cli::try_parse_from(["inix", "remove"]).is_err());
+    }
+
+    // - `inix update` copies the templates in the inix directory again
+    //   from their sources, replacing what's there, and skips the ones
+    //   it can't find anymore
+    // - a flake-only project stays flake-only
+    #[test]
+    fn templates_can_be_updated() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = config_dir.path().join("inix/mine");
+        create_dir_all(&template_dir).unwrap();
+        fs::write(template_dir.join("shell.nix"), "# version 1").unwrap();
+        let base_dir = tempdir().unwrap();
+        let cli = |action, templates: &[&str]| Cli {
+            action,
+            templates: templates.iter().map(|t| t.to_string()).collect(),
+            directory: Some(base_dir.path().into()),
+            ..Default::default()
+        };
+        let update = |templates: &[&str]| {
+            Some(Action::Update {
+                templates: templates.iter().map(|t| t.to_string()).collect(),
+            })
+        };
+
+        let error = run(cli(update(&[]), &[]), &dirs).expect_err("Nothing is set up yet.");
+        assert!(error.to_string().contains("nothing to update"), "{error}");
+
+        run(cli(None, &["mine", "rust"]), &dirs).unwrap();
+        let inix_dir = base_dir.path().join("inix");
+        create_dir_all(inix_dir.join("gone")).unwrap();
+        fs::write(inix_dir.join("gone/shell.nix"), "# gone").unwrap();
+        fs::write(template_dir.join("shell.nix"), "# version 2").unwrap();
+        fs::write(inix_dir.join("rust/shell.nix"), "# edited").unwrap();
+
+        run(cli(update(&[]), &[]), &dirs).unwrap();
+        let read = |path: &str| fs::read_to_string(inix_dir.join(path)).unwrap();
+        assert_eq!(read("mine/shell.nix"), "# version 2");
+        assert_eq!(
+            read("rust/shell.nix"),
+            include_str!("templates/rust/shell.nix")
+        );
+        assert_eq!(read("gone/shell.nix"), "# gone");
+        let shell_nix = fs::read_to_string(base_dir.path().join("shell.nix")).unwrap();
+        for template in ["gone", "mine", "rust"] {
+            assert!(
+                shell_nix.contains(&format!("./inix/{template}/shell.nix")),
+                "{shell_nix}"
+            );
+        }
+
+        fs::write(template_dir.join("shell.nix"), "# version 3").unwrap();
+        fs::write(inix_dir.join("rust/shell.nix"), "# edited").unwrap();
+        run(cli(update(&["mine"]), &[]), &dirs).unwrap();
+        assert_eq!(read("mine/shell.nix"), "# version 3");
+        assert_eq!(read("rust/shell.nix"), "# edited");
+
+        let error = run(cli(update(&["node"]), &[]), &dirs).expect_err("node isn't set up.");
+        assert!(error.to_string().contains(r#""node""#), "{error}");
+
+        let flake_dir = base_dir.path().join("flake-only");
+        let flake_cli = |action: Option<Action>| Cli {
+            directory: Some(flake_dir.clone()),
+            flake: action.is_none(),
+            ..cli(action, &["mine"])
+        };
+        run(flake_cli(None), &dirs).unwrap();
+        fs::write(flake_dir.join("flake.nix"), "# old").unwrap();
+        run(flake_cli(update(&[])), &dirs).unwrap();
+        assert!(!flake_dir.join("shell.nix").exists());
+        let flake = fs::read_to_string(flake_dir.join("flake.nix")).unwrap();
+        assert!(flake.contains("./inix/mine/shell.nix"), "{flake}");
+        let envrc = fs::read_to_string(flake_dir.join(".envrc")).unwrap();
+        assert!(envrc.contains("use flake"), "{envrc}");
+        assert!(!envrc.contains("use nix"), "{envrc}");
+    }
+
+    // - `inix show` prints where a template comes from and its files,
+    //   and doesn't touch the target directory
+    #[test]
+    fn templates_can_be_shown() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = config_dir.path().join("inix/mine");
+        create_dir_all(&template_dir).unwrap();
+        fs::write(template_dir.join("shell.nix"), "{ {{name}} }").unwrap();
+        let base_dir = tempdir().unwrap();
+        let show = |template: &str| {
+            let mut out = vec![];
+            run_to(
+                Cli {
+                    action: Some(Action::Show {
+                        template: template.into(),
+                    }),
+                    directory: Some(base_dir.path().join("project")),
+                    ..Default::default()
+                },
+                &dirs,
+                &mut out,
+            )
+            .map(|_| String::from_utf8(out).unwrap())
+        };
+
+        assert_eq!(
+            show("mine").unwrap(),
+            format!(
+                "The \"mine\" template, from {}\n\n==> shell.nix <==\n{{ {{{{name}}}} }}\n",
+                template_dir.display()
+            )
+        );
+        let rust = show("rust").unwrap();
+        assert!(
+            rust.starts_with("The \"rust\" template, from <builtin>\n"),
+            "{rust}"
+        );
+        assert!(
+            rust.contains(&format!(
+                "==> shell.nix <==\n{}",
+                include_str!("templates/rust/shell.nix")
+            )),
+            "{rust}"
+        );
+        assert!(show("missing").is_err());
+        assert!(!base_dir.path().join("project").exists());
+
+        let cli = Cli::try_parse_from(["inix", "show", "rust"]).unwrap();
+        assert_eq!(
+            cli.action,
+            Some(Action::Show {
+                template: "rust".into()
+            })
+        );
+    }
+
+    // - the decision log says which templates were resolved, which
+    //   were shadowed by them, and which are missing
+    #[test]
+    fn it_logs_template_decisions() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = config_dir.path().join("inix").join("rust");
+        create_dir_all(&template_dir).unwrap();
+        fs::write(template_dir.join("shell.nix"), "{ }").unwrap();
+
+        let decisions = template_decisions(
+            &["rust".into(), "node".into(), "nope".into()],
+            Default::default(),
+            &dirs,
+        );
+        let summary: Vec<_> = decisions
+            .iter()
+            .map(|d| (d.name.as_str(), d.decision, d.source.clone()))
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "rust",
+                    Decision::Resolved,
+                    Some(template_dir.display().to_string())
+                ),
+                ("rust", Decision::Shadowed, Some("<builtin>".to_string())),
+                ("node", Decision::Resolved, Some("<builtin>".to_string())),
+                ("nope", Decision::Missing, None),
+            ]
+        );
+
+        let json = serde_json::to_value(&decisions).unwrap();
+        assert_eq!(json[1]["decision"], "shadowed");
+        assert!(json[3]["reason"].as_str().unwrap().contains("template"));
+    }
+
+    // - every conflict behavior has the same key in every prompt, and
+    //   the options are always listed in the same order
+    #[test]
+    fn prompt_keys_are_stable() {
+        let path = PathBuf::from("inix");
+        let order = |behavior| match behavior {
+            ConflictBehavior::MergeKeep => 0,
+            ConflictBehavior::MergeReplace => 1,
+            ConflictBehavior::Overwrite => 2,
+            ConflictBehavior::Cancel => 3,
+        };
+
+        let mut keys = HashMap::new();
+
+        for template_collisions in [
+            TemplateCollisions::None,
+            TemplateCollisions::All(NonEmpty::new("rust")),
+            TemplateCollisions::Some(NonEmpty::new("rust")),
+        ] {
+            let prompt = conflict_prompt(&InixDir {
+                path: &path,
+                state: InixDirState::AlreadyExists {
+                    template_collisions: template_collisions.clone(),
+                },
+            })
+            .expect("There should be a prompt when the inix directory exists.");
+
+            for option in &prompt.options {
+                let key = *keys.entry(option.conflict_behavior).or_insert(option.key());
+                assert_eq!(
+                    key,
+                    option.key(),
+                    "{:?} has different keys in different prompts.",
+                    option.conflict_behavior
+                );
+                assert_eq!(
+                    prompt
+                        .find_option(&key.to_ascii_lowercase().to_string())
+                        .map(|o| o.conflict_behavior),
+                    Some(option.conflict_behavior)
+                );
+            }
+
+            assert!(
+                prompt
+                    .options
+                    .iter()
+                    .map(|o| order(o.conflict_behavior))
+                    .tuple_windows()
+                    .all(|(a, b)| a < b),
+                "The options in {template_collisions:?} are not in the expected order."
+            );
+        }
+
+        assert!(
+            conflict_prompt(&InixDir {
+                path: &path,
+                state: InixDirState::DoesNotExist,
+            })
+            .is_none(),
+            "There should be no prompt when there is no conflict."
+        );
+    }
+
+    // - --color never (or NO_COLOR) keeps the prompt free of escape
+    //   sequences, while --color always highlights it
+    #[test]
+    fn colors_can_be_turned_off() {
+        let path = PathBuf::from("inix");
+        let inix_dir = InixDir {
+            path: &path,
+            state: InixDirState::AlreadyExists {
+                template_collisions: TemplateCollisions::Some(NonEmpty::from((
+                    "node",
+                    vec!["rust"],
+                ))),
+            },
+        };
+        let cli = Cli::try_parse_from(["inix", "--color", "never", "rust"]).unwrap();
+        let prompt_with = |colors| {
+            Prompt {
+                text: inix_dir.describe_conflict(colors),
+                colors,
+                ..conflict_prompt(&inix_dir).unwrap()
+            }
+            .to_string()
+        };
+
+        let plain = prompt_with(Colors::new(cli.color, false, true));
+        assert!(!plain.contains('\x1b'), "{plain}");
+        assert!(plain.contains(r#""node" and "rust""#), "{plain}");
+        assert_eq!(plain, conflict_prompt(&inix_dir).unwrap().to_string());
+
+        let colored = prompt_with(Colors::new(ColorChoice::Always, false, false));
+        assert!(colored.contains("\x1b[1;36mrust\x1b[0m"), "{colored}");
+        assert!(
+            !colored.contains("\x1b[1;36m\""),
+            "The quotes should stay outside the colors."
+        );
+
+        assert_eq!(
+            Colors::new(ColorChoice::Always, true, true),
+            Colors::default()
+        );
+        assert!(!Colors::new(ColorChoice::Auto, false, false).enabled);
+        assert!(Colors::new(ColorChoice::Auto, false, true).enabled);
+        assert_eq!(
+            Colors::new(ColorChoice::Always, false, false).warning("Warning: careful"),
+            "\x1b[1;33mWarning:\x1b[0m careful"
+        );
+    }
+
+    // - when there's a conflict, no conflict behavior, and nobody to
+    //   ask, inix cancels, but warns about it
+    #[test]
+    fn it_warns_when_it_cant_ask_about_conflicts() {
+        let path = PathBuf::from("inix");
+        let existing = InixDir {
+            path: &path,
+            state: InixDirState::AlreadyExists {
+                template_collisions: TemplateCollisions::None,
+            },
+        };
+
+        match resolve_conflict_behavior(&existing, None, false) {
+            ConflictResolution::CancelWithWarning(warning) => assert!(
+                warning.contains("--on-conflict"),
+                "The warning doesn't explain how to change the behavior: {warning}"
+            ),
+            other => panic!("Expected a warning, but got {other:?}"),
+        }
+
+        assert!(matches!(
+            resolve_conflict_behavior(&existing, None, true),
+            ConflictResolution::Prompt
+        ));
+        assert!(matches!(
+            resolve_conflict_behavior(&existing, Some(ConflictBehavior::MergeKeep), false),
+            ConflictResolution::Use(ConflictBehavior::MergeKeep)
+        ));
+        assert!(matches!(
+            resolve_conflict_behavior(
+                &InixDir {
+                    path: &path,
+                    state: InixDirState::DoesNotExist
+                },
+                None,
+                false
+            ),
+            ConflictResolution::Use(ConflictBehavior::Cancel)
+        ));
+    }
+
+    // - with --non-interactive, a conflict that nobody has said what
+    //   to do about is an error, and nothing is written
+    #[test]
+    fn non_interactive_fails_on_conflicts() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        let inix_dir = base_dir.path().join("inix");
+        create_dir_all(inix_dir.join("rust")).unwrap();
+
+        let error = run(
+            Cli {
+                templates: vec!["rust".into(), "node".into()],
+                directory: Some(base_dir.path().into()),
+                non_interactive: true,
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .expect_err("An unresolved conflict should fail.");
+
+        let message = error.to_string();
+        assert!(message.contains("--on-conflict"), "{message}");
+        assert!(message.contains("rust"), "{message}");
+        assert!(!inix_dir.join("node").exists());
+        assert!(fs::read_dir(inix_dir.join("rust"))
+            .unwrap()
+            .next()
+            .is_none());
+        assert!(!base_dir.path().join("shell.nix").exists());
+        assert!(!base_dir.path().join(".envrc").exists());
+    }
+
+    struct InixPaths<'a> {
+        base_dir: &'a Path,
+        inix_dir: &'a Path,
+        shell_nix: &'a Path,
+        envrc: &'a Path,
+    }
+
+    fn test_inix_with_setup<T, SetupOutput>(
+        args: Cli,
+        setup: impl FnOnce(&InixPaths) -> SetupOutput,
+        execute: impl FnOnce(&InixPaths, SetupOutput) -> T,
+    ) {
+        let target_dir = args
+            .directory
+            .unwrap_or(tempdir().expect("couldn't create a temp dir").path().into());
+
+        let paths = InixPaths {
+            base_dir: &target_dir,
+            inix_dir: &target_dir.join("inix"),
+            shell_nix: &target_dir.join("shell.nix"),
+            envrc: &target_dir.join("shell.nix"),
+        };
+
+        let args_p = Cli {
+            directory: Some(target_dir.clone()),
+            ..args
+        };
+
+        let setup_output = setup(&paths);
+
+        let (_config_dir, dirs) = isolated_dirs();
+        match run(args_p, &dirs) {
+            Err(e) => assert!(
+                false,
+                r#"Running the inix program failed with an error: {e:?}"#
+            ),
+            Ok(_) => {
+                execute(&paths, setup_output);
+            }
+        };
+    }
+
+    fn test_inix<T>(args: Cli, execute: impl FnOnce(&InixPaths) -> T) {
+        test_inix_with_setup(args, |_| {}, |paths, _| execute(paths))
+    }
+
+    /// Dirs that point at an empty temporary config directory instead
+    /// of the real one. The directory is removed when the returned
+    /// `TempDir` is dropped.
+    fn isolated_dirs() -> (TempDir, Dirs) {
+        let config_dir = tempdir().expect("couldn't create a temp dir");
+        let dirs = Dirs {
+            config: Some(config_dir.path().into()),
+            templates: vec![],
+            cache: Some(config_dir.path().join("cache")),
+            home: Some(config_dir.path().join("home")),
+            builtin: None,
+            remote: vec![],
+            single_template_dirs: vec![],
+        };
+        (config_dir, dirs)
+    }
+
+    fn power_set<'a, T>(a: &[T]) -> impl Iterator<Item = &[T]> {
+        std::iter::once([].as_ref()).chain(
+            (0..=a.len())
+                .tuple_combinations()
+                .map(move |(start, end)| &a[start..end]),
+        )
+    }
+
+    // Test cases
+    //
+    // - it completes successfully without templates
+    #[test]
+    fn it_works_without_provided_templates() {
+        test_inix(
+            Cli {
+                templates: vec![],
+                ..Default::default()
+            },
+            |paths| {
+                for expected_file in [paths.shell_nix, paths.envrc] {
+                    assert!(
+                        expected_file.is_file(),
+                        r#"The file "/{}" does not exist or is not a file."#,
+                        expected_file.display()
+                    );
+                }
+                assert_eq!(
+                    false,
+                    paths.inix_dir.exists(),
+                    r#"The /inix directory was created when it shouldn't have anything in it."#
+                );
+            },
+        )
+    }
+
+    // - it creates an empty inix directory without templates if asked
+    //   to, but doesn't touch an existing one
+    #[test]
+    fn it_creates_an_empty_inix_dir_if_allowed() {
+        let base_dir = tempdir().unwrap();
+        let args = || Cli {
+            templates: vec![],
+            directory: Some(base_dir.path().into()),
+            allow_empty: true,
+            on_conflict: Some(ConflictBehavior::Overwrite),
+            ..Default::default()
+        };
+
+        test_inix(args(), |paths| {
+            assert!(
+                paths.inix_dir.is_dir(),
+                "The inix directory was not created."
+            );
+            let entries: Vec<_> = fs::read_dir(paths.inix_dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            assert_eq!(entries, vec![".gitkeep"]);
+        });
+
+        let existing_file = base_dir.path().join("inix/notes.txt");
+        fs::write(&existing_file, "my notes").unwrap();
+
+        test_inix(args(), |_| {
+            assert!(
+                existing_file.exists(),
+                "The existing inix directory was changed."
+            );
+        });
+    }
+
+    // - creates shell.nix, .envrc, and inix/* files
+    // - creates any directories necessary if they don't exist
+    #[test]
+    fn it_creates_files() {
+        let base_dir = tempdir().unwrap();
+
+        let project_dir = base_dir.path().join("my/project");
+
+        for templates in power_set(&["rust", "node"]).filter(|set| set.len() > 0) {
+            let args = Cli {
+                templates: templates.iter().map(|s| s.deref().into()).collect(),
+                directory: Some(project_dir.clone()),
+                ..Default::default()
+            };
+
+            test_inix(args, |paths| {
+                for expected_file in [paths.shell_nix, paths.envrc] {
+                    assert!(
+                        expected_file.is_file(),
+                        r#"The file "/{}" does not exist or is not a file."#,
+                        expected_file.display()
+                    );
+                }
+
+                for expected_file in ["node/shell.nix", "node/.envrc"] {
+                    assert!(
+                        paths.inix_dir.join(expected_file).is_file(),
+                        r#"The file "/{expected_file}" does not exist or is not a file."#
+                    );
+                }
+            })
+        }
+    }
+
+    // - --emit-flake creates a flake.nix that uses every template's
+    //   shell and an .envrc that uses it
+    #[test]
+    fn it_emits_a_flake() {
+        let base_dir = tempdir().unwrap();
+        let templates = vec!["rust".to_string(), "node".to_string()];
+
+        test_inix(
+            Cli {
+                templates: templates.clone(),
+                directory: Some(base_dir.path().into()),
+                emit_flake: true,
+                ..Default::default()
+            },
+            |paths| {
+                let flake_nix = paths.base_dir.join("flake.nix");
+                let flake =
+                    fs::read_to_string(&flake_nix).expect("The flake.nix file was not created.");
+
+                for template in &templates {
+                    let shell = format!("./inix/{template}/shell.nix");
+                    assert!(
+                        flake.contains(&shell),
+                        r#"The flake does not reference the "{template}" template's shell ("{shell}")."#
+                    );
+                }
+
+                let envrc = fs::read_to_string(paths.base_dir.join(".envrc")).unwrap();
+                assert!(
+                    envrc.lines().any(|line| line.trim() == "use flake"),
+                    "The .envrc file does not use the flake."
+                );
+
+                // only check that the flake parses if we have nix available
+                if let Ok(output) = std::process::Command::new("nix-instantiate")
+                    .arg("--parse")
+                    .arg(&flake_nix)
+                    .output()
+                {
+                    assert!(
+                        output.status.success(),
+                        "The generated flake.nix does not parse: {}",
+                        String::from_utf8_lossy(&output.stderr)
+                    );
+                }
+            },
+        )
+    }
+
+    // - base templates can use the name and path of each template
+    #[test]
+    fn base_templates_get_template_names_and_paths() {
+        let templates: Vec<_> = ["rust", "node"]
+            .iter()
+            .map(|name| included_templates(None)[name].clone())
+            .collect();
+
+        let output = Handlebars::new()
+            .render_template(
+                "{{#each templates}}{{name}}: {{path}}/shell.nix\n{{/each}}",
+                &base_template_args(&templates, &Cli::default()).unwrap(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            output,
+            "rust: inix/rust/shell.nix\nnode: inix/node/shell.nix\n"
+        );
+    }
+
+    // - the top-level shell.nix either imports the templates' shells
+    //   or merges their packages into one
+    #[test]
+    fn combine_strategies() {
+        let templates: Vec<_> = ["rust", "node"]
+            .iter()
+            .map(|name| included_templates(None)[name].clone())
+            .collect();
+        let (_config_dir, dirs) = isolated_dirs();
+        let (nix_template, _) = base_template_files(Default::default(), &dirs).unwrap();
+        let render = |combine_strategy| {
+            let cli = Cli {
+                combine_strategy,
+                ..Default::default()
+            };
+            Handlebars::new()
+                .render_template(
+                    &nix_template,
+                    &base_template_args(&templates, &cli).unwrap(),
+                )
+                .unwrap()
+        };
+
+        let imported = render(CombineStrategy::Import);
+        for template in ["rust", "node"] {
+            assert!(
+                imported.contains(&format!("./inix/{template}/shell.nix")),
+                "The {template} template isn't imported:\n{imported}"
+            );
+        }
+
+        // the rust template's `rust` is bound with `let` and its
+        // packages come from an overlay, so they can't be merged
+        let error = base_template_args(
+            &templates,
+            &Cli {
+                combine_strategy: CombineStrategy::Merge,
+                ..Default::default()
+            },
+        )
+        .expect_err("The rust template can't be merged.");
+        assert!(error.to_string().contains("- rust: "), "{error}");
+        assert!(!error.to_string().contains("- node: "), "{error}");
+
+        let templates = &templates[1..];
+        let merged = Handlebars::new()
+            .render_template(
+                &nix_template,
+                &base_template_args(
+                    templates,
+                    &Cli {
+                        combine_strategy: CombineStrategy::Merge,
+                        ..Default::default()
+                    },
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        assert!(
+            !merged.contains("./inix/"),
+            "The merged shell imports templates:\n{merged}"
+        );
+        assert!(
+            merged
+                .lines()
+                .any(|line| line.trim() == "pkgs.nodejs_latest"),
+            "The merged shell doesn't have pkgs.nodejs_latest:\n{merged}"
+        );
+
+        assert_eq!(
+            package_expressions(
+                r##"pkgs.mkShell {
+                  buildInputs = [ pkgs.git (pkgs.python3.withPackages (ps: [ ps.requests ])) ];
+                  packages = [
+                    "not-a-package" # a string
+                    "#not-a-comment"
+                  ];
+                }"##
+            ),
+            vec![
+                "pkgs.git",
+                "(pkgs.python3.withPackages (ps: [ ps.requests ]))",
+                r#""not-a-package""#,
+                r##""#not-a-comment""##
+            ]
+        );
+        assert_eq!(
+            merge_blocker("# let it be\n{ pkgs }: pkgs.mkShell { }"),
+            None
+        );
+    }
+
+    // - variables from --set are available in the base templates, but
+    //   can't replace inix's own data
+    #[test]
+    fn base_templates_get_variables() {
+        let cli =
+            Cli::try_parse_from(["inix", "--set", "project_name=inix", "--set", "channel=a=b"])
+                .unwrap();
+        let output = base_handlebars(false)
+            .render_template(
+                "{{project_name}} {{channel}}",
+                &base_template_args(&[], &cli).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(output, "inix a=b");
+
+        let cli = Cli::try_parse_from(["inix", "--set", "templates=nope"]).unwrap();
+        let error = base_template_args(&[], &cli).expect_err("Setting templates should fail.");
+        assert!(
+            format!("{error}").contains(
+                r#"reserved: "envrc_prelude", "flake", "import_templates", "merge_packages", "min_direnv_version", "overlays", "shell_hooks", and "templates""#
+            ),
+            "{error}"
+        );
+
+        for malformed in ["project_name", "=inix"] {
+            let error = Cli::try_parse_from(["inix", "--set", malformed])
+                .err()
+                .unwrap_or_else(|| panic!(r#""{malformed}" was accepted."#));
+            assert!(
+                error.to_string().contains(malformed),
+                "The error doesn't mention the argument: {error}"
+            );
+        }
+    }
+
+    // - --no-render copies templates exactly as they are
+    #[test]
+    fn templates_can_be_copied_without_rendering() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = config_dir.path().join("inix").join("literal");
+        create_dir_all(&template_dir).unwrap();
+        let shell_nix = "{ pkgs ? import <nixpkgs> { } }: {{foo}} ${bar}";
+        fs::write(template_dir.join("shell.nix"), shell_nix).unwrap();
+
+        let base_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["literal".into()],
+                directory: Some(base_dir.path().into()),
+                emit_flake: true,
+                no_render: true,
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+
+        let read = |file: &str| fs::read_to_string(base_dir.path().join(file)).unwrap();
+        assert_eq!(read("inix/literal/shell.nix"), shell_nix);
+        assert_eq!(
+            read("shell.nix"),
+            include_str!("templates/base/shell.nix.template")
+        );
+        assert_eq!(
+            read(".envrc"),
+            include_str!("templates/base/.envrc.template")
+        );
+        assert_eq!(
+            read("flake.nix"),
+            include_str!("templates/base/flake.nix.template")
+        );
+    }
+
+    // - --var-from-command sets variables to the output of commands
+    #[test]
+    fn variables_can_come_from_commands() {
+        let dir = tempdir().unwrap();
+        let cli = Cli::try_parse_from([
+            "inix",
+            "--var-from-command",
+            "greeting=echo hi",
+            "--var-from-command",
+            "dir=pwd",
+        ])
+        .unwrap();
+
+        let variables = variables_from_commands(&cli.variable_commands, dir.path()).unwrap();
+        let cli = Cli { variables, ..cli };
+        let output = base_handlebars(false)
+            .render_template(
+                "{{greeting}} from {{dir}}",
+                &base_template_args(&[], &cli).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(
+            output,
+            format!("hi from {}", dir.path().canonicalize().unwrap().display())
+        );
+
+        let error = variables_from_commands(&[("nope".into(), "exit 3".into())], dir.path())
+            .expect_err("A failing command should be an error.");
+        assert!(
+            error.to_string().contains(r#""nope" ("exit 3")"#),
+            "The error doesn't say which command failed: {error}"
+        );
+
+        // a dry run says what it would run instead of running it
+        let (_config_dir, dirs) = isolated_dirs();
+        let mut out = vec![];
+        run_to(
+            Cli {
+                directory: Some(dir.path().into()),
+                variable_commands: vec![("marker".into(), "touch ran".into())],
+                dry_run: true,
+                format: OutputFormat::Json,
+                ..Default::default()
+            },
+            &dirs,
+            &mut out,
+        )
+        .unwrap();
+        assert!(!dir.path().join("ran").exists(), "The command was run.");
+        let json: JsonValue = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            json["commands"],
+            serde_json::json!([{ "variable": "marker", "command": "touch ran" }])
+        );
+        let cli = Cli {
+            variable_commands: vec![("marker".into(), "touch ran".into())],
+            ..Default::default()
+        };
+        let inix_dir_path = dir.path().join("inix");
+        let steps = plan(
+            &cli,
+            dir.path(),
+            &InixDir {
+                path: &inix_dir_path,
+                state: InixDirState::DoesNotExist,
+            },
+            &[],
+            ConflictBehavior::Cancel,
+        )
+        .steps();
+        assert_eq!(
+            steps[1],
+            r#"I will run "touch ran" and set "marker" to what it prints."#
+        );
+    }
+
+    // - --print-created -0 lists exactly the files that a run writes,
+    //   separated by NULs, including empty directories and the
+    //   .gitkeep of an empty inix directory
+    #[test]
+    fn created_files_can_be_listed() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = config_dir.path().join("inix/cached");
+        create_dir_all(template_dir.join("cache")).unwrap();
+        fs::write(template_dir.join("shell.nix"), "{ }").unwrap();
+
+        let base_dir = tempdir().unwrap();
+        let cli = |templates: &[&str], directory: &Path| Cli {
+            templates: templates.iter().map(|t| t.to_string()).collect(),
+            directory: Some(directory.into()),
+            print_created: true,
+            null: true,
+            allow_empty: true,
+            ..Default::default()
+        };
+        let print_created = |cli| {
+            let mut out = vec![];
+            run_to(cli, &dirs, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let output = print_created(cli(&["rust", "cached"], base_dir.path()));
+        let mut listed: Vec<_> = output
+            .strip_suffix('\0')
+            .unwrap()
+            .split('\0')
+            .map(PathBuf::from)
+            .collect();
+        listed.sort();
+        let mut written = vec![];
+        let mut dirs_to_walk = vec![base_dir.path().to_path_buf()];
+        while let Some(dir) = dirs_to_walk.pop() {
+            let entries: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+            if entries.is_empty() {
+                written.push(dir);
+            }
+            for entry in entries {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs_to_walk.push(path);
+                } else {
+                    written.push(path);
+                }
+            }
+        }
+        written.sort();
+        assert_eq!(listed, written);
+        assert!(listed.contains(&base_dir.path().join("inix/cached/cache")));
+        assert!(!output.contains('\n'));
+
+        let empty_dir = base_dir.path().join("empty");
+        let output = print_created(Cli {
+            null: false,
+            ..cli(&[], &empty_dir)
+        });
+        assert_eq!(
+            output,
+            format!(
+                "{}\n{}\n{}\n",
+                empty_dir.join("inix/.gitkeep").display(),
+                empty_dir.join("shell.nix").display(),
+                empty_dir.join(".envrc").display()
+            )
+        );
+    }
+
+    // - after merging a template into an inix directory, the base
+    //   files pull in the templates that were there before as well,
+    //   sorted by name
+    #[test]
+    fn merged_base_files_have_every_template() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        let cli = |template: &str, on_conflict| Cli {
+            templates: vec![template.into()],
+            directory: Some(base_dir.path().into()),
+            on_conflict,
+            ..Default::default()
+        };
+
+        run(cli("rust", None), &dirs).unwrap();
+        for behavior in [ConflictBehavior::MergeKeep, ConflictBehavior::MergeReplace] {
+            run(cli("node", Some(behavior)), &dirs).unwrap();
+
+            let shell_nix = fs::read_to_string(base_dir.path().join("shell.nix")).unwrap();
+            let node = shell_nix.find("./inix/node/shell.nix").expect(&shell_nix);
+            let rust = shell_nix.find("./inix/rust/shell.nix").expect(&shell_nix);
+            assert!(node < rust, "{shell_nix}");
+            let envrc = fs::read_to_string(base_dir.path().join(".envrc")).unwrap();
+            assert!(envrc.contains("inix/rust/.envrc"), "{envrc}");
+            assert!(envrc.contains("inix/node/.envrc"), "{envrc}");
+        }
+
+        // overwriting leaves only the new template
+        run(cli("node", Some(ConflictBehavior::Overwrite)), &dirs).unwrap();
+        let shell_nix = fs::read_to_string(base_dir.path().join("shell.nix")).unwrap();
+        assert!(!shell_nix.contains("inix/rust"), "{shell_nix}");
+    }
+
+    // - --each-subdir sets up every directory in the target directory
+    //   (but not hidden ones or files) and prints a summary for each
+    #[test]
+    fn every_subdir_can_be_set_up() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        for dir in ["service-a", "service-b", ".hidden"] {
+            create_dir_all(base_dir.path().join(dir)).unwrap();
+        }
+        fs::write(base_dir.path().join("README.md"), "").unwrap();
+        let mut out = vec![];
+
+        run_to(
+            Cli {
+                templates: vec!["rust".into()],
+                directory: Some(base_dir.path().into()),
+                each_subdir: true,
+                ..Default::default()
+            },
+            &dirs,
+            &mut out,
+        )
+        .unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        for dir in ["service-a", "service-b"] {
+            let dir = base_dir.path().join(dir);
+            assert!(dir.join("shell.nix").is_file());
+            assert!(dir.join("inix/rust/shell.nix").is_file());
+            assert!(out.contains(&format!("{}:\n", dir.display())), "{out}");
+            assert!(
+                out.contains(&format!("- {}", dir.join("shell.nix").display())),
+                "{out}"
+            );
+        }
+        assert_eq!(out.matches("All done!").count(), 2, "{out}");
+        assert!(!base_dir.path().join(".hidden/shell.nix").exists());
+        assert!(!base_dir.path().join("shell.nix").exists());
+    }
+
+    // - a run fails while another one holds the lock on the same
+    //   directory and leaves everything alone, and the lock is gone
+    //   again once a run is over
+    // - the lock is taken before the project is looked at, and a
+    //   target directory that a failed run created is removed again
+    #[test]
+    fn concurrent_runs_are_locked_out() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        let cli = |no_lock| Cli {
+            templates: vec!["rust".into()],
+            directory: Some(base_dir.path().into()),
+            no_lock,
+            ..Default::default()
+        };
+
+        let lock = RunLock::acquire(base_dir.path(), false).unwrap();
+        assert!(RunLock::acquire(base_dir.path(), false).is_err());
+        let error = run(cli(false), &dirs).expect_err("The directory is locked.");
+        let message = error.to_string();
+        assert!(
+            message.contains("Another inix run is in progress"),
+            "{message}"
+        );
+        assert!(message.contains("--no-lock"), "{message}");
+        assert!(!base_dir.path().join("shell.nix").exists());
+        assert!(!base_dir.path().join("inix").exists());
+
+        // a stray entry in the inix dir would be an error of its own,
+        // but the lock comes first
+        fs::create_dir_all(base_dir.path().join("inix/stray")).unwrap();
+        let error = run(cli(false), &dirs).expect_err("The directory is locked.");
+        assert!(
+            error
+                .to_string()
+                .contains("Another inix run is in progress"),
+            "{error}"
+        );
+        fs::remove_dir_all(base_dir.path().join("inix")).unwrap();
+
+        run(cli(true), &dirs).unwrap();
+        assert!(base_dir.path().join("shell.nix").exists());
+
+        drop(lock);
+        let lock_file = base_dir.path().join(RunLock::FILE_NAME);
+        assert!(!lock_file.exists());
+        let cli = Cli {
+            on_conflict: Some(ConflictBehavior::Overwrite),
+            ..cli(false)
+        };
+        run(cli, &dirs).unwrap();
+        assert!(!lock_file.exists());
+
+        let new_dir = base_dir.path().join("new");
+        run(
+            Cli {
+                templates: vec!["rust".into(), "node".into()],
+                directory: Some(new_dir.clone()),
+                combine_strategy: CombineStrategy::Merge,
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .expect_err("rust can't be merged with other templates.");
+        assert!(!new_dir.exists());
+    }
+
+    // - with --quiet, nothing is printed to stdout when everything
+    //   goes well
+    #[test]
+    fn quiet_runs_print_nothing() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        let mut out = vec![];
+
+        run_to(
+            Cli {
+                templates: vec!["rust".into()],
+                directory: Some(base_dir.path().into()),
+                quiet: true,
+                ..Default::default()
+            },
+            &dirs,
+            &mut out,
+        )
+        .unwrap();
+
+        assert!(base_dir.path().join("shell.nix").exists());
+        assert_eq!(String::from_utf8(out).unwrap(), "");
+    }
+
+    // - with --quiet, nobody is asked about conflicts: they're an error
+    //   unless --on-conflict says what to do
+    #[test]
+    fn quiet_runs_fail_on_conflicts() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        create_dir_all(base_dir.path().join("inix/rust")).unwrap();
+        let cli = |on_conflict| Cli {
+            templates: vec!["rust".into()],
+            directory: Some(base_dir.path().into()),
+            quiet: true,
+            on_conflict,
+            ..Default::default()
+        };
+
+        let error = run(cli(None), &dirs).expect_err("An unresolved conflict should fail.");
+        let message = error.to_string();
+        assert!(message.contains("--quiet"), "{message}");
+        assert!(!base_dir.path().join("shell.nix").exists());
+
+        run(cli(Some(ConflictBehavior::Overwrite)), &dirs).unwrap();
+        assert!(base_dir.path().join("shell.nix").exists());
+    }
+
+    // - with --verbose, every file that's written is listed with its
+    //   full path, and so are backups
+    #[test]
+    fn verbose_runs_list_every_file() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        fs::write(base_dir.path().join("shell.nix"), "old").unwrap();
+        create_dir_all(base_dir.path().join("inix")).unwrap();
+        let mut out = vec![];
+
+        run_to(
+            Cli {
+                templates: vec!["rust".into()],
+                directory: Some(base_dir.path().into()),
+                on_conflict: Some(ConflictBehavior::MergeKeep),
+                verbose: true,
+                ..Default::default()
+            },
+            &dirs,
+            &mut out,
+        )
+        .unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let shell_nix = base_dir.path().join("shell.nix");
+        let rust_shell_nix = base_dir.path().join("inix/rust/shell.nix");
+        assert!(
+            out.contains(&format!("Created {}\n", rust_shell_nix.display())),
+            "{out}"
+        );
+        assert!(
+            out.contains(&format!("Backed up {} to ", shell_nix.display())),
+            "{out}"
+        );
+        assert!(
+            out.contains(&format!("Created {}\n", shell_nix.display())),
+            "{out}"
+        );
+        assert!(Cli::try_parse_from(["inix", "-q", "-v", "rust"]).is_err());
+    }
+
+    // - the summary lists the absolute paths of the files that were
+    //   written
+    #[test]
+    fn the_summary_has_absolute_paths() {
+        let relative = PathBuf::from("project/shell.nix");
+        let absolute = current_dir().unwrap().join("elsewhere/.envrc");
+
+        let text = summary(&[relative.clone(), absolute.clone()]).unwrap();
+
+        assert!(text.contains(&format!(
+            "- {}",
+            current_dir().unwrap().join(&relative).display()
+        )));
+        assert!(text.contains(&format!("- {}", absolute.display())));
+        assert_eq!(summary(&[]), None);
+    }
+
+    // - quiet errors only have the top-level message, while normal
+    //   errors also list the causes
+    #[test]
+    fn errors_can_be_quiet() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let (_config_dir, dirs) = isolated_dirs();
+        let error = run(
+            Cli {
+                directory: Some(file.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .expect_err("Using a file as the target directory should fail.");
+
+        let message = format!(
+            r#""{}" is not a directory, so I cannot place any files there."#,
+            file.path().display()
+        );
+
+        assert_eq!(format_error(&error, true), message);
+
+        let full = format_error(&error, false);
+        assert!(full.starts_with(&message));
+        assert!(
+            full.contains("Caused by"),
+            "The error doesn't list its causes: {full}"
+        );
+    }
+
+    // - custom templates are rendered like the base templates, and
+    //   nothing is written if that fails
+    #[test]
+    fn custom_templates_are_rendered() {
+        let (config_dir, dirs) = isolated_dirs();
+        let write_template = |name: &str, shell_nix: &str| {
+            let template_dir = config_dir.path().join("inix").join(name);
+            create_dir_all(&template_dir).unwrap();
+            fs::write(template_dir.join("shell.nix"), shell_nix).unwrap();
+        };
+        write_template("mine", "{{#each templates}}{{name}} {{/each}}{{greeting}}");
+        write_template("broken", "{{#each templates}}");
+
+        let base_dir = tempdir().unwrap();
+        let cli = |templates: &[&str]| Cli {
+            templates: templates.iter().map(|t| t.to_string()).collect(),
+            directory: Some(base_dir.path().into()),
+            variables: vec![("greeting".into(), "hi".into())],
+            ..Default::default()
+        };
+
+        let error = run(cli(&["node", "broken"]), &dirs)
+            .expect_err("Rendering a broken template should fail.");
+        assert!(
+            format!("{error:#}").contains(r#"the "broken" template"#),
+            "The error doesn't name the template: {error:#}"
+        );
+        assert!(!base_dir.path().join("inix").exists());
+
+        run(cli(&["mine", "rust"]), &dirs).unwrap();
+        let inix_dir = base_dir.path().join("inix");
+        assert_eq!(
+            fs::read_to_string(inix_dir.join("mine/shell.nix")).unwrap(),
+            "mine rust hi"
+        );
+        assert_eq!(
+            fs::read_to_string(inix_dir.join("rust/shell.nix")).unwrap(),
+            include_str!("templates/rust/shell.nix")
+        );
+    }
+
+    // - the builtin rust template has an .envrc that the top-level
+    //   .envrc sources, which watches the template's shell.nix
+    #[test]
+    fn the_rust_template_has_an_envrc() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["rust".into()],
+                directory: Some(base_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+
+        let envrc = fs::read_to_string(base_dir.path().join("inix/rust/.envrc")).unwrap();
+        assert!(
+            envrc.lines().any(|line| line == "watch_file shell.nix"),
+            "{envrc}"
+        );
+        assert!(base_dir.path().join("inix/rust/shell.nix").is_file());
+        assert!(fs::read_to_string(base_dir.path().join(".envrc"))
+            .unwrap()
+            .contains("source_env_if_exists inix/rust/.envrc"));
+    }
+
+    // - every file in a custom template's directory is copied, not
+    //   just the ones inix knows about, byte for byte and without
+    //   following symlinks to directories
+    #[test]
+    fn custom_templates_copy_all_files() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = config_dir.path().join("inix/overlaid");
+        create_dir_all(template_dir.join("nix")).unwrap();
+        fs::write(template_dir.join("shell.nix"), "{ }").unwrap();
+        fs::write(template_dir.join("nix/overlay.nix"), "self: super: { }").unwrap();
+        fs::write(
+            template_dir.join("logo.png"),
+            [0x89, b'P', b'N', b'G', 0xff, 0x00],
+        )
+        .unwrap();
+        fs::write(template_dir.join("Makefile"), "echo {{not_a_variable}}").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("..", template_dir.join("nix/loop")).unwrap();
+
+        let base_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["overlaid".into()],
+                directory: Some(base_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+
+        let output_dir = base_dir.path().join("inix/overlaid");
+        assert_eq!(
+            fs::read_to_string(output_dir.join("nix/overlay.nix")).unwrap(),
+            "self: super: { }"
+        );
+        assert_eq!(
+            fs::read_to_string(output_dir.join("shell.nix")).unwrap(),
+            "{ }"
+        );
+        assert_eq!(
+            fs::read(output_dir.join("logo.png")).unwrap(),
+            [0x89, b'P', b'N', b'G', 0xff, 0x00]
+        );
+        assert_eq!(
+            fs::read_to_string(output_dir.join("Makefile")).unwrap(),
+            "echo {{not_a_variable}}"
+        );
+        assert!(!output_dir.join("nix/loop").exists());
+    }
+
+    // - files that a template's inix.toml lists as verbatim are copied
+    //   as they are, while its other files are rendered
+    #[test]
+    fn verbatim_files_are_not_rendered() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = config_dir.path().join("inix/mixed");
+        create_dir_all(&template_dir).unwrap();
+        fs::write(template_dir.join("shell.nix"), "# {{project_name}}").unwrap();
+        fs::write(template_dir.join(".envrc"), "# {{project_name}}").unwrap();
+        fs::write(
+            template_dir.join("inix.toml"),
+            "description = \"Half rendered\"\nverbatim = [\".envrc\"]\n",
+        )
+        .unwrap();
+
+        let base_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["mixed".into()],
+                directory: Some(base_dir.path().into()),
+                variables: vec![("project_name".into(), "inix".into())],
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        let read =
+            |file: &str| fs::read_to_string(base_dir.path().join("inix/mixed").join(file)).unwrap();
+        assert_eq!(read("shell.nix"), "# inix");
+        assert_eq!(read(".envrc"), "# {{project_name}}");
+
+        fs::write(
+            template_dir.join("inix.toml"),
+            "description = \"Half rendered\"\nverbatim = [\"missing.nix\"]\n",
+        )
+        .unwrap();
+        let error = try_get_templates(&["mixed".into()], Default::default(), &dirs)
+            .expect_err("missing.nix isn't in the template.");
+        assert!(format!("{error:#}").contains("missing.nix"), "{error:#}");
+    }
+
+    // - with --strict-vars, a template that uses a variable that isn't
+    //   set fails to render (naming the variable and the file), while
+    //   it's left empty otherwise
+    #[test]
+    fn strict_vars_catch_typos() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = config_dir.path().join("inix/typo");
+        create_dir_all(&template_dir).unwrap();
+        fs::write(template_dir.join("shell.nix"), "# {{projeect_name}}").unwrap();
+        let base_dir = tempdir().unwrap();
+        let cli = |strict_vars| Cli {
+            templates: vec!["typo".into(), "rust".into()],
+            directory: Some(base_dir.path().into()),
+            variables: vec![("project_name".into(), "inix".into())],
+            strict_vars,
+            ..Default::default()
+        };
+
+        let error = run(cli(true), &dirs).expect_err("The variable isn't set.");
+        let message = format!("{error:#}");
+        assert!(message.contains("projeect_name"), "{message}");
+        assert!(message.contains("shell.nix"), "{message}");
+        assert!(message.contains("typo"), "{message}");
+        assert!(!base_dir.path().join("inix").exists());
+
+        run(cli(false), &dirs).unwrap();
+        assert_eq!(
+            fs::read_to_string(base_dir.path().join("inix/typo/shell.nix")).unwrap(),
+            "# "
+        );
+
+        // the base files don't use anything that might not be set
+        fs::remove_dir_all(base_dir.path().join("inix")).unwrap();
+        run(
+            Cli {
+                templates: vec!["rust".into()],
+                on_conflict: Some(ConflictBehavior::Overwrite),
+                emit_flake: true,
+                ..cli(true)
+            },
+            &dirs,
+        )
+        .unwrap();
+    }
+
+    // - the builtin templates' files can be replaced by the ones in
+    //   the builtin directory, and the compiled-in ones are used for
+    //   anything that isn't there
+    #[test]
+    fn builtin_templates_can_be_overridden() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let builtin_dir = tempdir().unwrap();
+        create_dir_all(builtin_dir.path().join("rust")).unwrap();
+        create_dir_all(builtin_dir.path().join("base")).unwrap();
+        fs::write(builtin_dir.path().join("rust/shell.nix"), "# patched rust").unwrap();
+        fs::write(
+            builtin_dir.path().join("base/shell.nix.template"),
+            "# patched base for {{#each templates}}{{name}}{{/each}}",
+        )
+        .unwrap();
+        let dirs = Dirs {
+            builtin: Some(builtin_dir.path().into()),
+            ..dirs
+        };
+
+        let base_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["rust".into()],
+                directory: Some(base_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+
+        let read = |path: &str| fs::read_to_string(base_dir.path().join(path)).unwrap();
+        assert_eq!(read("inix/rust/shell.nix"), "# patched rust");
+        assert_eq!(
+            read("inix/rust/.envrc"),
+            include_str!("templates/rust/.envrc")
+        );
+        assert_eq!(read("shell.nix"), "# patched base for rust");
+        assert!(read(".envrc").contains("source_env_if_exists inix/rust/.envrc"));
+
+        let missing = builtin_dir.path().join("missing");
+        assert_eq!(
+            included_templates(Some(&missing))["rust"].files(),
+            included_templates(None)["rust"].files()
+        );
+    }
+
+    // - empty directories in a custom template are recreated, even
+    //   nested ones
+    #[test]
+    fn custom_templates_keep_empty_dirs() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = config_dir.path().join("inix/cached");
+        create_dir_all(template_dir.join("cache")).unwrap();
+        create_dir_all(template_dir.join("nix/empty")).unwrap();
+        fs::write(template_dir.join("shell.nix"), "{ }").unwrap();
+
+        let base_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["cached".into()],
+                directory: Some(base_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+
+        let output_dir = base_dir.path().join("inix/cached");
+        assert!(output_dir.join("cache").is_dir());
+        assert!(output_dir.join("nix/empty").is_dir());
+        assert!(output_dir.join("shell.nix").is_file());
+    }
+
+    // - templates with a flake.nix are flake-based, and using one makes
+    //   the project use a flake that includes it
+    #[test]
+    fn flake_templates() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = config_dir.path().join("inix").join("my-flake");
+        create_dir_all(&template_dir).unwrap();
+        for file in ["flake.nix", "flake.lock", ".envrc"] {
+            fs::write(template_dir.join(file), file).unwrap();
+        }
+
+        let template = find_template(
+            "my-flake",
+            &[config_dir.path().join("inix").as_path()],
+            &[],
+            Default::default(),
+            None,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(template.is_flake());
+        assert_eq!(
+            template.known_files(),
+            vec![
+                (".envrc".to_string(), ".envrc"),
+                ("flake.lock".to_string(), "flake.lock"),
+                ("flake.nix".to_string(), "flake.nix"),
+            ]
+        );
+
+        let base_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["flake".into(), "node".into()],
+                directory: Some(base_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+
+        let read = |file: &str| fs::read_to_string(base_dir.path().join(file)).unwrap();
+        assert_eq!(
+            read("inix/flake/flake.nix"),
+            include_str!("templates/flake/flake.nix")
+        );
+
+        let envrc = read(".envrc");
+        assert!(
+            envrc.lines().any(|line| line.trim() == "use flake"),
+            "The .envrc doesn't use the flake:\n{envrc}"
+        );
+
+        let flake = read("flake.nix");
+        for expected in [
+            r#"flake.url = "path:./inix/flake";"#,
+            "inputs.flake.devShells.${system}.default",
+            "(import ./inix/node/shell.nix { inherit pkgs; })",
+        ] {
+            assert!(
+                flake.contains(expected),
+                "The flake doesn't contain {expected}:\n{flake}"
+            );
+        }
+
+        let shell_nix = read("shell.nix");
+        assert!(
+            !shell_nix.contains("inix/flake"),
+            "The shell.nix imports the flake template:\n{shell_nix}"
+        );
+    }
+
+    // - the top-level shell.nix and flake.nix don't import the
+    //   templates if asked not to
+    #[test]
+    fn it_can_skip_top_level_imports() {
+        let base_dir = tempdir().unwrap();
+
+        test_inix(
+            Cli {
+                templates: vec!["rust".into(), "node".into()],
+                directory: Some(base_dir.path().into()),
+                emit_flake: true,
+                no_top_level_import: true,
+                ..Default::default()
+            },
+            |paths| {
+                for template in ["rust", "node"] {
+                    assert!(
+                        paths.inix_dir.join(template).join("shell.nix").exists(),
+                        r#"The "{template}" template's shell.nix was not created."#
+                    );
+                }
+
+                for file in ["shell.nix", "flake.nix"] {
+                    let content = fs::read_to_string(paths.base_dir.join(file)).unwrap();
+                    assert!(
+                        !content.contains("inix/"),
+                        "The top-level {file} imports the templates:\n{content}"
+                    );
+                }
+            },
+        )
+    }
+
+    // - a dry run with --auto-allow only says that it would run
+    //   direnv allow
+    #[test]
+    fn dry_runs_only_describe_direnv_allow() {
+        let base_dir = tempdir().unwrap();
+        let inix_dir_path = base_dir.path().join("inix");
+        let inix_dir = InixDir {
+            path: &inix_dir_path,
+            state: InixDirState::DoesNotExist,
+        };
+        let (_config_dir, dirs) = isolated_dirs();
+        let templates =
+            try_get_templates(&["rust".to_string()], Default::default(), &dirs).unwrap();
+        let allow = format!("direnv allow {}", base_dir.path().display());
+
+        let plan = |auto_allow| {
+            let cli = Cli {
+                dry_run: true,
+                auto_allow,
+                ..Default::default()
+            };
+            plan(
+                &cli,
+                base_dir.path(),
+                &inix_dir,
+                &templates,
+                ConflictBehavior::Cancel,
+            )
+            .steps()
+        };
+
+        assert!(
+            plan(true).iter().any(|step| step.contains(&allow)),
+            "The plan does not mention running direnv allow."
+        );
+        assert!(
+            !plan(false).iter().any(|step| step.contains("direnv allow")),
+            "The plan mentions running direnv allow even though it shouldn't."
+        );
+    }
+
+    // - the json plan has the templates and the conflict behavior that
+    //   it was given, and the text plan describes the same files
+    #[test]
+    fn dry_runs_can_be_described_as_json() {
+        let base_dir = tempdir().unwrap();
+        let inix_dir_path = base_dir.path().join("inix");
+        create_dir_all(inix_dir_path.join("rust")).unwrap();
+        let inix_dir = InixDir {
+            path: &inix_dir_path,
+            state: InixDirState::AlreadyExists {
+                template_collisions: TemplateCollisions::Some(NonEmpty::new("rust")),
+            },
+        };
+        let (_config_dir, dirs) = isolated_dirs();
+        let templates =
+            try_get_templates(&["rust".into(), "node".into()], Default::default(), &dirs).unwrap();
+        let cli = Cli {
+            dry_run: true,
+            format: OutputFormat::Json,
+            ..Default::default()
+        };
+
+        let plan = plan(
+            &cli,
+            base_dir.path(),
+            &inix_dir,
+            &templates,
+            ConflictBehavior::MergeKeep,
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&plan).unwrap()).unwrap();
+
+        assert_eq!(json["on_conflict"], "merge-keep");
+        assert_eq!(
+            json["templates"],
+            serde_json::json!([
+                { "name": "rust", "template_type": "builtin", "source": "<builtin>" },
+                { "name": "node", "template_type": "builtin", "source": "<builtin>" },
+            ])
+        );
+        assert_eq!(
+            json["inix_dir"]["conflicting_templates"],
+            serde_json::json!(["rust"])
+        );
+
+        let files: Vec<_> = json["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file["path"].as_str().unwrap().to_string())
+            .collect();
+        assert!(files.contains(&inix_dir_path.join("node/shell.nix").display().to_string()));
+        assert!(!files.contains(&inix_dir_path.join("rust/shell.nix").display().to_string()));
+        assert!(files.contains(&base_dir.path().join("shell.nix").display().to_string()));
+
+        // and the text is made from the same plan
+        let steps = plan.steps();
+        for file in files {
+            assert!(
+                steps.iter().any(|step| step.contains(&file)),
+                "{file} isn't in the steps: {steps:#?}"
+            );
+        }
+        assert!(
+            steps
+                .iter()
+                .any(|step| step.contains(r#"I will leave the "rust" template(s)"#)),
+            "{steps:#?}"
+        );
+    }
+
+    // - comparing two conflict behaviors shows the files that they
+    //   would treat differently, and only those
+    #[test]
+    fn conflict_behaviors_can_be_compared() {
+        let base_dir = tempdir().unwrap();
+        let inix_dir_path = base_dir.path().join("inix");
+        create_dir_all(inix_dir_path.join("rust")).unwrap();
+        fs::write(inix_dir_path.join("rust/shell.nix"), "{ }").unwrap();
+        let inix_dir = InixDir {
+            path: &inix_dir_path,
+            state: InixDirState::AlreadyExists {
+                template_collisions: TemplateCollisions::Some(NonEmpty::new("rust")),
+            },
+        };
+        let (_config_dir, dirs) = isolated_dirs();
+        let templates =
+            try_get_templates(&["rust".into(), "node".into()], Default::default(), &dirs).unwrap();
+        let cli = Cli {
+            dry_run: true,
+            compare_behaviors: vec![ConflictBehavior::MergeKeep, ConflictBehavior::MergeReplace],
+            ..Default::default()
+        };
+        let plan_for = |behavior| plan(&cli, base_dir.path(), &inix_dir, &templates, behavior);
+
+        let comparison = compare_plans(
+            &plan_for(ConflictBehavior::MergeKeep),
+            &plan_for(ConflictBehavior::MergeReplace),
+        );
+
+        let rust = inix_dir_path.join("rust/shell.nix").display().to_string();
+        assert!(
+            comparison.iter().any(|line| line.contains(&rust)
+                && line.contains("merge-keep would leave it alone")
+                && line.contains("merge-replace would overwrite it")),
+            "The comparison doesn't show the skipped template: {comparison:#?}"
+        );
+        let node = inix_dir_path.join("node").display().to_string();
+        assert!(
+            !comparison.iter().any(|line| line.contains(&node)),
+            "Both behaviors create the node template: {comparison:#?}"
+        );
+
+        assert_eq!(
+            compare_plans(
+                &plan_for(ConflictBehavior::MergeReplace),
+                &plan_for(ConflictBehavior::MergeReplace)
+            )
+            .len(),
+            1
+        );
+    }
+
+    // - it points at the read-only ancestor when it can't create the
+    //   target directory, even if others could write to it
+    #[cfg(unix)]
+    #[test]
+    fn it_reports_read_only_ancestors() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // read-only for everyone, and writable for everyone but us
+        for mode in [0o555, 0o575] {
+            let base_dir = tempdir().unwrap();
+            let read_only = base_dir.path().join("read-only");
+            fs::create_dir(&read_only).unwrap();
+            fs::set_permissions(&read_only, fs::Permissions::from_mode(mode)).unwrap();
+            if tempfile::tempfile_in(&read_only).is_ok() {
+                // the permissions don't apply to us (we're root)
+                continue;
+            }
+
+            let target_dir = read_only.join("a").join("b");
+            let (_config_dir, dirs) = isolated_dirs();
+            let result = run(
+                Cli {
+                    directory: Some(target_dir.clone()),
+                    ..Default::default()
+                },
+                &dirs,
+            );
+
+            fs::set_permissions(&read_only, fs::Permissions::from_mode(0o755)).unwrap();
+
+            let error = format!(
+                "{:#}",
+                result.expect_err("inix wrote to a read-only directory.")
+            );
+            assert!(
+                error.contains(&format!(
+                    r#"I can't create "{}" because "{}" isn't writable."#,
+                    target_dir.display(),
+                    read_only.display()
+                )),
+                "The error doesn't point at the read-only directory: {error}"
+            );
+            assert!(!target_dir.exists());
+        }
+    }
+
+    // - a dry run doesn't write any of the top-level files, but says
+    //   that it would
+    #[test]
+    fn dry_runs_dont_write_base_files() {
+        let base_dir = tempdir().unwrap();
+        let (_config_dir, dirs) = isolated_dirs();
+        let cli = || Cli {
+            templates: vec!["rust".into()],
+            directory: Some(base_dir.path().into()),
+            dry_run: true,
+            emit_flake: true,
+            ..Default::default()
+        };
+
+        run(cli(), &dirs).expect("The dry run failed.");
+
+        for file in ["shell.nix", ".envrc", "flake.nix", "inix"] {
+            assert!(
+                !base_dir.path().join(file).exists(),
+                "The dry run created {file}."
+            );
+        }
+
+        let inix_dir_path = base_dir.path().join("inix");
+        let templates = try_get_templates(&["rust".into()], Default::default(), &dirs).unwrap();
+        let plan = plan(
+            &cli(),
+            base_dir.path(),
+            &InixDir {
+                path: &inix_dir_path,
+                state: InixDirState::DoesNotExist,
+            },
+            &templates,
+            ConflictBehavior::Cancel,
+        )
+        .steps();
+        for file in ["shell.nix", ".envrc", "flake.nix"] {
+            let path = base_dir.path().join(file).display().to_string();
+            assert!(
+                plan.iter().any(|step| step.contains(&path)),
+                "The plan doesn't mention {file}: {plan:#?}"
+            );
+        }
+    }
+
+    // - the .envrc only checks the direnv version if asked to
+    #[test]
+    fn the_envrc_checks_the_direnv_version() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let (_, envrc_template) = base_template_files(Default::default(), &dirs).unwrap();
+        let render = |cli: &Cli| {
+            Handlebars::new()
+                .render_template(&envrc_template, &base_template_args(&[], cli).unwrap())
+                .unwrap()
+        };
+
+        let with_version = render(&Cli {
+            envrc_min_direnv: Some("2.21.0".into()),
+            ..Default::default()
+        });
+        assert!(
+            with_version
+                .lines()
+                .any(|line| line.trim() == "direnv_version 2.21.0"),
+            "The .envrc doesn't check the direnv version:\n{with_version}"
+        );
+
+        let without_version = render(&Cli::default());
+        assert!(
+            !without_version.contains("direnv_version"),
+            "The .envrc checks the direnv version when it shouldn't:\n{without_version}"
+        );
+
+        for invalid in ["", "latest", "2..1", "v2.21"] {
+            assert!(
+                Cli::try_parse_from(["inix", "--envrc-min-direnv", invalid]).is_err(),
+                r#""{invalid}" was accepted as a version."#
+            );
+        }
+    }
+
+    // - the .envrc loads the prelude before anything else, with the
+    //   right command for paths and URLs
+    #[test]
+    fn the_envrc_loads_the_prelude_first() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let (_, envrc_template) = base_template_files(Default::default(), &dirs).unwrap();
+        let first_command = |prelude: &str, hash: Option<&str>| {
+            let cli = Cli {
+                envrc_prelude: Some(prelude.into()),
+                envrc_prelude_hash: hash.map(String::from),
+                ..Default::default()
+            };
+            let envrc = Handlebars::new()
+                .render_template(&envrc_template, &base_template_args(&[], &cli).unwrap())
+                .unwrap();
+            envrc
+                .lines()
+                .map(str::trim)
+                .find(|line| !line.is_empty() && !line.starts_with('#'))
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(
+            first_command("https://example.com/direnvrc", Some("sha256-abc=")),
+            "source_url 'https://example.com/direnvrc' 'sha256-abc='"
+        );
+        assert_eq!(
+            first_command("../shared/direnvrc", None),
+            "source_env '../shared/direnvrc'"
+        );
+
+        for (prelude, hash) in [
+            ("https://example.com/direnvrc", None),
+            ("https://example.com/direnvrc", Some("abc")),
+            ("../shared/direnvrc", Some("sha256-abc=")),
+            ("ftp://example.com/direnvrc", None),
+        ] {
+            assert!(
+                envrc_prelude(Some(prelude), hash).is_err(),
+                "{prelude} with {hash:?} was accepted."
+            );
+        }
+    }
+
+    // - running inix again replaces the managed block in .envrc
+    //   instead of adding another one, and leaves other lines alone
+    #[test]
+    fn the_envrc_managed_block_is_replaced() {
+        let base_dir = tempdir().unwrap();
+        let envrc = base_dir.path().join(".envrc");
+        let user_line = "export MY_VARIABLE=1";
+
+        let (_config_dir, dirs) = isolated_dirs();
+        let run_with = |templates: &[&str]| {
+            run(
+                Cli {
+                    templates: templates.iter().map(|t| t.to_string()).collect(),
+                    directory: Some(base_dir.path().into()),
+                    on_conflict: Some(ConflictBehavior::MergeReplace),
+                    ..Default::default()
+                },
+                &dirs,
+            )
+            .expect("Running inix failed.")
+        };
+
+        run_with(&["node"]);
+
+        let mut contents = fs::read_to_string(&envrc).unwrap();
+        contents.push_str(user_line);
+        contents.push('\n');
+        fs::write(&envrc, contents).unwrap();
+
+        run_with(&["rust"]);
+
+        let contents = fs::read_to_string(&envrc).unwrap();
+        for marker in [MANAGED_BLOCK_START, MANAGED_BLOCK_END] {
+            assert_eq!(
+                contents.matches(marker).count(),
+                1,
+                r#"Expected exactly one "{marker}" line in:\n{contents}"#
+            );
+        }
+        assert!(
+            contents.contains("source_env_if_exists inix/rust/.envrc"),
+            "The managed block was not updated:\n{contents}"
+        );
+        // node is still in the inix directory, so it's still sourced,
+        // but only by the new block
+        assert_eq!(
+            contents
+                .matches("source_env_if_exists inix/node/.envrc")
+                .count(),
+            1,
+            "The old managed block is still there:\n{contents}"
+        );
+        assert!(
+            contents.contains(user_line),
+            "The line outside of the managed block was removed:\n{contents}"
+        );
+    }
+
+    // - long output only goes through the pager on a terminal and
+    //   when the pager hasn't been turned off
+    #[test]
+    fn it_only_pages_when_it_should() {
+        assert!(should_page(false, true, 100, Some(20)));
+
+        assert!(!should_page(true, true, 100, Some(20)), "--no-pager");
+        assert!(!should_page(false, false, 100, Some(20)), "not a terminal");
+        assert!(!should_page(false, true, 10, Some(20)), "short output");
+        assert!(!should_page(false, true, 100, None), "unknown height");
+    }
+
+    #[test]
+    fn it_sends_output_to_the_pager() {
+        let dir = tempdir().unwrap();
+        let paged = dir.path().join("paged");
+        let output = "So here's the plan:\nI will do things.";
+
+        page(output, &format!(r#"cat > "{}""#, paged.display())).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&paged).expect("The pager was not invoked."),
+            format!("{output}\n")
+        );
+    }
+
+    //
+    // - the resulting .envrc and shell.nix files actually work
+    #[test]
+    fn the_envrc_file_works() {
+        // todo: use proptest to generate this with and without
+        // subdirectories that it needs to source from?
+        todo!()
+    }
+
+    // - the top-level shell.nix is a valid Nix expression that
+    //   combines the templates' shells (and builds, if nix is
+    //   installed)
+    #[test]
+    fn the_nix_file_works() {
+        // todo: use proptest to generate this with and without
+        // subdirectories that it needs to source from?
+
+        // the smoke test needs nix to build the environment
+        let has_nix = |program: &str| Command::new(program).arg("--version").output().is_ok();
+
+        let base_dir = tempdir().unwrap();
+        let (_config_dir, dirs) = isolated_dirs();
+        run(
+            Cli {
+                templates: vec!["node".into(), "rust".into()],
+                directory: Some(base_dir.path().into()),
+                smoke_test: has_nix("nix-shell"),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .expect("The generated shell.nix does not work.");
 
-                let new_template_names = templates.iter().map(Template2::name);
+        let shell_nix = base_dir.path().join("shell.nix");
+        assert_eq!(
+            fs::read_to_string(&shell_nix).unwrap(),
+            "{ pkgs ? import <nixpkgs> { } }:\n\npkgs.mkShell {\n  inputsFrom = [\n    (import ./inix/node/shell.nix { })\n    (import ./inix/rust/shell.nix { })\n    # extra inputs\n  ];\n\n  packages = [\n    # extra packages\n  ];\n}\n"
+        );
 
-                let msg =
-                    // overwrite
-                match (on_conflict, template_collisions) {
-                    (ConflictBehavior::Overwrite, _) => format!(r#"Because you have chosen to overwrite the inix directory on conflicts, I will delete the existing directory ("{}") and recreate it with the templates you have chosen ({})."#, inix_dir.path.display(), combine_strings(new_template_names)),
+        if has_nix("nix-instantiate") {
+            let output = Command::new("nix-instantiate")
+                .arg("--parse")
+                .arg(&shell_nix)
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "The shell.nix isn't valid Nix: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+    }
 
-                    // merge (keep)
-                    (ConflictBehavior::MergeKeep, TemplateCollisions::Some(ts) ) => {
-                        format!(r#"Because you have chosen the merge (keep) option, I will merge the old and the new directories. These new templates will be added: {}"#, combine_strings(new_template_names.filter(|t| !ts.contains(t))))
-                    },
-                    (ConflictBehavior::MergeKeep, TemplateCollisions::None) => {
-                        format!(r#"Because you have chosen the merge (keep) option, I will merge the old and the new directories. There are no template collisions, so I will add these new templates: {}"#, combine_strings(new_template_names))
-                    },
-                    (ConflictBehavior::MergeKeep, TemplateCollisions::All(_)) => {
-                        format!(r#"Because you have chosen the merge (keep) option, I will merge the old and the new directories. However, all the templates you are trying to add ({}) already exist in the inix directory ("{}"), so I will not do anything."#, combine_strings(new_template_names) , inix_dir.path.display())
-                    },
+    // - templates can bring nixpkgs overlays, which the top-level
+    //   shell.nix applies to the packages it passes to the templates
+    #[test]
+    fn templates_can_bring_overlays() {
+        let (config_dir, dirs) = isolated_dirs();
+        let templates_dir = config_dir.path().join("inix");
+        let plain = templates_dir.join("plain");
+        create_dir_all(&plain).unwrap();
+        fs::write(
+            plain.join("shell.nix"),
+            "{ pkgs ? import <nixpkgs> { } }: { }",
+        )
+        .unwrap();
+        fs::write(plain.join("overlay.nix"), "final: prev: { }").unwrap();
+        let declared = templates_dir.join("declared");
+        create_dir_all(declared.join("nix")).unwrap();
+        fs::write(
+            declared.join("shell.nix"),
+            "{ pkgs ? import <nixpkgs> { } }: { }",
+        )
+        .unwrap();
+        fs::write(
+            declared.join("nix").join("overlays.nix"),
+            "final: prev: { }",
+        )
+        .unwrap();
+        fs::write(
+            declared.join("inix.toml"),
+            "description = \"Brings its own overlay\"\nfiles = []\noverlay = \"nix/overlays.nix\"",
+        )
+        .unwrap();
 
-                    // merge (replace)
-                    (ConflictBehavior::MergeReplace, TemplateCollisions::Some(ts) ) => {
-                        format!(r#"Because you have chosen the merge (replace) option, I will merge the old and the new directories. These templates will be overwritten: {}. When I'm done, all these templates will have been added or updated: {}"#, combine_strings(ts.into_iter()), combine_strings(new_template_names))
-                    },
-                    (ConflictBehavior::MergeReplace, TemplateCollisions::None) => {
-                        format!(r#"Because you have chosen the merge (replace) option, I will merge the old and the new directories. There are no template collisions, so I will add these new templates: {}"#, combine_strings(new_template_names))
-                    },
+        let base_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["plain".into(), "declared".into(), "rust".into()],
+                directory: Some(base_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
 
-                    (ConflictBehavior::MergeReplace, TemplateCollisions::All(_)) => {
-                        format!(r#"Because you have chosen the merge (replace) option, I will merge the old and the new directories. All the templates you are trying to add already exist in the inix directory ("{}"). I will add the following templates: {}"#, inix_dir.path.display(), combine_strings(new_template_names) )
-                    },
+        let shell_nix = fs::read_to_string(base_dir.path().join("shell.nix")).unwrap();
+        assert!(
+            shell_nix.contains("overlays = [\n    (import ./inix/declared/nix/overlays.nix)\n    (import ./inix/plain/overlay.nix)\n  ];"),
+            "{shell_nix}"
+        );
+        assert!(
+            shell_nix.contains("(import ./inix/plain/shell.nix { inherit pkgs; })"),
+            "{shell_nix}"
+        );
+        // the rust template sets up its own nixpkgs, which the shared
+        // packages would replace
+        assert!(
+            shell_nix.contains("(import ./inix/rust/shell.nix { })"),
+            "{shell_nix}"
+        );
+        assert!(base_dir
+            .path()
+            .join("inix/declared/nix/overlays.nix")
+            .is_file());
 
-                    // cancel
-                    (ConflictBehavior::Cancel, _) => format!(r#"Because you have chosen the cancel option and the inix directory ("{}") already exists, I will not do anything"#, inix_dir.path.display())
-                };
+        // without overlays, the templates use their own packages
+        let base_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["rust".into()],
+                directory: Some(base_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        let shell_nix = fs::read_to_string(base_dir.path().join("shell.nix")).unwrap();
+        assert!(
+            shell_nix.starts_with("{ pkgs ? import <nixpkgs> { } }:"),
+            "{shell_nix}"
+        );
+        assert!(
+            shell_nix.contains("(import ./inix/rust/shell.nix { })"),
+            "{shell_nix}"
+        );
+    }
 
-                println!("{msg}");
-            }
-        }
-    } else {
-        if !target_dir.exists() {
-            create_dir_all(&target_dir).with_context(|| {
-                format!(
-                    r#"I was unable to create the target project dir ("{}")"#,
-                    &target_dir.display()
-                )
-            })?
-        } else {
-            let metadata = target_dir.metadata().with_context(|| {
-                format!(
-                    "Unable to read permission status for \"{}\".",
-                    &target_dir.display()
-                )
-            })?;
+    // - the base .envrc and shell.nix files contain links to all the
+    // templates mentioned
+    #[test]
+    fn all_templates_are_linked() {
+        // in short:
+        // generate a set of templates. Ensure that each one is linked in both .envrc and shell.nix
+        todo!()
+    }
 
-            let false = metadata.permissions().readonly() else {
-        bail!(
-            "I don't have the right permissions to write to \"{}\"",
-            &target_dir.display()
-        )
-    };
+    // - a file in the inix dir with the same name as a template gives
+    //   a clear error when merging
+    #[test]
+    fn files_named_like_templates_are_reported() {
+        for on_conflict in [ConflictBehavior::MergeKeep, ConflictBehavior::MergeReplace] {
+            let base_dir = tempdir().unwrap();
+            let inix_dir = base_dir.path().join("inix");
+            create_dir_all(&inix_dir).unwrap();
+            fs::write(inix_dir.join("rust"), "not a template").unwrap();
+
+            let (_config_dir, dirs) = isolated_dirs();
+            let error = run(
+                Cli {
+                    templates: vec!["rust".into(), "node".into()],
+                    directory: Some(base_dir.path().into()),
+                    on_conflict: Some(on_conflict),
+                    ..Default::default()
+                },
+                &dirs,
+            )
+            .expect_err("Merging into a file should fail.");
+
+            assert!(
+                error
+                    .to_string()
+                    .contains(&format!("- {}", inix_dir.join("rust").display())),
+                "The error doesn't point at the file: {error}"
+            );
+            assert!(!inix_dir.join("node").exists());
         }
+    }
 
-        // copy templates over (into an inix directory)
-        match (inix_dir.state, on_conflict) {
-            (InixDirState::DoesNotExist, _) => {
-                let _ = create_dir_all(inix_dir.path).with_context(|| {
-                    format!(
-                        r#"I was unable to create the inix directory "{}"."#,
-                        inix_dir.path.display()
-                    )
-                })?;
-                for template in &templates {
-                    let target = inix_dir.path.join(template.name());
-                    create_dir_all(&target).with_context(|| {
-                        format!(
-                            r#"I was unable to create the template directory "{}"."#,
-                            target.display()
-                        )
-                    })?;
-                    for (file_name, contents) in template.files() {
-                        let file = target.join(file_name);
-                        fs::write(&file, &contents).with_context(|| {
-                            format!(
-                                r#"I was unable to write the "{}" template (found at "{}") to "{}"."#,
-                                template.name(),
-                                template.path().display(),
-                                target.display()
-                            )
-                        })?
-                    }
+    // - a file named like a template counts as a collision: it's left
+    //   alone unless the whole inix directory is overwritten
+    #[test]
+    fn files_named_like_templates_collide() {
+        for on_conflict in ConflictBehavior::value_variants() {
+            let base_dir = tempdir().unwrap();
+            let inix_dir = base_dir.path().join("inix");
+            create_dir_all(inix_dir.join("rust")).unwrap();
+            fs::write(inix_dir.join("rust/shell.nix"), "{ }").unwrap();
+            fs::write(inix_dir.join("node"), "not a template").unwrap();
+
+            let (_config_dir, dirs) = isolated_dirs();
+            let result = run(
+                Cli {
+                    templates: vec!["node".into()],
+                    directory: Some(base_dir.path().into()),
+                    on_conflict: Some(*on_conflict),
+                    ..Default::default()
+                },
+                &dirs,
+            );
+
+            match on_conflict {
+                ConflictBehavior::Overwrite => {
+                    result.unwrap();
+                    assert!(inix_dir.join("node/shell.nix").is_file());
+                }
+                ConflictBehavior::Cancel => {
+                    result.unwrap();
+                    assert!(inix_dir.join("node").is_file());
+                }
+                ConflictBehavior::MergeKeep | ConflictBehavior::MergeReplace => {
+                    result.expect_err("Merging into a file should fail.");
+                    assert!(inix_dir.join("node").is_file(), "{on_conflict:?}");
                 }
             }
+        }
+    }
 
-            (InixDirState::AlreadyExists { .. }, ConflictBehavior::Overwrite) => {
-                remove_dir_all(inix_dir.path)?;
-                let _ = create_dir_all(inix_dir.path).with_context(|| {
-                    format!(
-                        r#"I was unable to create the inix directory "{}"."#,
-                        inix_dir.path.display()
-                    )
-                })?;
-                for template in &templates {
-                    let target = inix_dir.path.join(template.name());
-                    create_dir_all(&target).with_context(|| {
-                        format!(
-                            r#"I was unable to create the template directory "{}"."#,
-                            target.display()
-                        )
-                    })?;
-                    for (file_name, contents) in template.files() {
-                        let file = target.join(file_name);
-                        fs::write(&file, &contents).with_context(|| {
-                            format!(
-                                r#"I was unable to write the "{}" template (found at "{}") to "{}"."#,
-                                template.name(),
-                                template.path().display(),
-                                target.display()
-                            )
-                        })?
-                    }
+    // - a symlink named like a template counts as a collision, and is
+    //   never written through: it's kept or replaced, but whatever it
+    //   points to is left alone, even if it points nowhere
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_named_like_templates_are_not_followed() {
+        for dangling in [false, true] {
+            for on_conflict in ConflictBehavior::value_variants() {
+                let base_dir = tempdir().unwrap();
+                let elsewhere = base_dir.path().join("elsewhere");
+                if !dangling {
+                    create_dir_all(&elsewhere).unwrap();
+                    fs::write(elsewhere.join("mine.txt"), "mine").unwrap();
                 }
-            }
-            (
-                InixDirState::AlreadyExists {
-                    template_collisions,
-                },
-                ConflictBehavior::MergeKeep,
-            ) => {
-                let templates_to_copy = match template_collisions {
-                    TemplateCollisions::Some(ts) => templates
-                        .iter()
-                        .filter(|t| !ts.contains(&t.name()))
-                        .map(|t| t.clone())
-                        .collect(),
-                    TemplateCollisions::None => templates.clone(),
-                    TemplateCollisions::All(_) => vec![],
-                };
+                let inix_dir = base_dir.path().join("inix");
+                create_dir_all(inix_dir.join("rust")).unwrap();
+                fs::write(inix_dir.join("rust/shell.nix"), "{ }").unwrap();
+                std::os::unix::fs::symlink(&elsewhere, inix_dir.join("node")).unwrap();
+
+                let (_config_dir, dirs) = isolated_dirs();
+                run(
+                    Cli {
+                        templates: vec!["node".into()],
+                        directory: Some(base_dir.path().into()),
+                        on_conflict: Some(*on_conflict),
+                        ..Default::default()
+                    },
+                    &dirs,
+                )
+                .unwrap_or_else(|e| panic!("{on_conflict:?} (dangling: {dangling}): {e:#}"));
 
-                for template in templates_to_copy {
-                    let target = inix_dir.path.join(template.name());
-                    create_dir_all(&target).with_context(|| {
-                        format!(
-                            r#"I was unable to create the template directory "{}"."#,
-                            target.display()
-                        )
-                    })?;
-                    for (file_name, contents) in template.files() {
-                        let file = target.join(file_name);
-                        fs::write(&file, &contents).with_context(|| {
-                            format!(
-                                r#"I was unable to write the "{}" template (found at "{}") to "{}"."#,
-                                template.name(),
-                                template.path().display(),
-                                target.display()
-                            )
-                        })?
+                let node = inix_dir.join("node");
+                match on_conflict {
+                    ConflictBehavior::Cancel | ConflictBehavior::MergeKeep => {
+                        assert!(node.is_symlink(), "{on_conflict:?}")
                     }
-                }
-            }
-            (InixDirState::AlreadyExists { .. }, ConflictBehavior::MergeReplace) => {
-                for template in &templates {
-                    let target = inix_dir.path.join(template.name());
-                    create_dir_all(&target).with_context(|| {
-                        format!(
-                            r#"I was unable to create the template directory "{}"."#,
-                            target.display()
-                        )
-                    })?;
-                    for (file_name, contents) in template.files() {
-                        let file = target.join(file_name);
-                        fs::write(&file, &contents).with_context(|| {
-                            format!(
-                                r#"I was unable to write the "{}" template (found at "{}") to "{}"."#,
-                                template.name(),
-                                template.path().display(),
-                                target.display()
-                            )
-                        })?
+                    ConflictBehavior::MergeReplace | ConflictBehavior::Overwrite => {
+                        assert!(!node.is_symlink(), "{on_conflict:?}");
+                        assert!(node.join("shell.nix").is_file(), "{on_conflict:?}");
                     }
                 }
-            }
-            (InixDirState::AlreadyExists { .. }, ConflictBehavior::Cancel) => {
-                // intentionally left blank
+                assert!(!elsewhere.join("shell.nix").exists(), "{on_conflict:?}");
+                if !dangling {
+                    assert_eq!(
+                        fs::read_to_string(elsewhere.join("mine.txt")).unwrap(),
+                        "mine"
+                    );
+                } else {
+                    assert!(!elsewhere.exists());
+                }
             }
         }
     }
 
-    // render base templates
-    let handlebars = Handlebars::new();
+    // - overwrite refuses to delete things in the inix directory that
+    //   don't belong to any template, unless forced to
+    #[test]
+    fn overwrite_protects_unexpected_files() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        let inix_dir = base_dir.path().join("inix");
+        create_dir_all(inix_dir.join("node")).unwrap();
+        fs::write(inix_dir.join("notes.md"), "don't delete me").unwrap();
+        fs::write(inix_dir.join("node/shell.nix"), "{ }").unwrap();
+        fs::write(inix_dir.join("node/todo.md"), "don't delete me").unwrap();
+        create_dir_all(inix_dir.join("node/scratch")).unwrap();
+
+        let cli = |force| Cli {
+            templates: vec!["rust".into()],
+            directory: Some(base_dir.path().into()),
+            on_conflict: Some(ConflictBehavior::Overwrite),
+            force,
+            ..Default::default()
+        };
 
-    let (nix_template, envrc_template) = {
-        match &included_templates().get("base").unwrap().files {
-            TemplateFiles2::Both { nix, envrc } => (nix.clone(), envrc.clone()),
-            TemplateFiles2::Nix(_) | TemplateFiles2::Envrc(_) => unreachable!(),
+        let error = run(cli(false), &dirs).expect_err("Overwriting should be refused.");
+        let message = error.to_string();
+        assert!(
+            message.contains(&inix_dir.join("notes.md").display().to_string()),
+            "The error doesn't list the unexpected file: {message}"
+        );
+        for unexpected in ["node/todo.md", "node/scratch"] {
+            assert!(
+                message.contains(&inix_dir.join(unexpected).display().to_string()),
+                "The error doesn't list {unexpected}, which isn't one of the node template's files: {message}"
+            );
         }
-    };
-
-    let handlebars_args = hash_map! {
-       "templates" =>  templates.iter().map(Template2::name).collect::<Vec<_>>()
-    };
-
-    // reg.render_file()
-    // for now, let's just print it to standard out?
-
-    handlebars.render_template_to_write(
-        &nix_template,
-        &handlebars_args,
-        &fs::File::create(target_dir.join("shell.nix"))?,
-    )?;
-
-    handlebars.render_template_to_write(
-        &envrc_template,
-        &handlebars_args,
-        &fs::File::create(target_dir.join(".envrc"))?,
-    )?;
-    // .render_template(&nix_template, &handlebars_args)
-
-    // println!("{}", fs::read_to_string(inix_dir.path.join("shell.nix"))?);
-
-    // println!("{output}, {handlebars_args:?}");
-
-    Ok(())
-}
-
-fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
-
-    run(cli)
-}
-
-fn combine_strings<T, Item>(strings: T) -> String
-where
-    Item: Display + Ord + Clone,
-    T: Iterator<Item = Item> + Clone,
-{
-    let quote = |item: Item| format!(r#""{}""#, item.to_string());
+        for expected in ["node", "node/shell.nix"] {
+            assert!(
+                !message.contains(&format!("- {}\n", inix_dir.join(expected).display())),
+                "{expected} belongs to the node template and isn't unexpected: {message}"
+            );
+        }
+        assert!(inix_dir.join("notes.md").exists());
+        assert!(!inix_dir.join("rust").exists());
 
-    match strings.clone().count() {
-        0 | 1 => strings.map(quote).collect(),
-        2 => strings.map(quote).join(" and "),
-        len => strings
-            .enumerate()
-            .map(|(index, value)| {
-                if index == len - 1 {
-                    format!(r#"and {}"#, quote(value))
-                } else {
-                    quote(value)
-                }
-            })
-            .join(", "),
+        run(cli(true), &dirs).unwrap();
+        assert!(!inix_dir.join("notes.md").exists());
+        assert!(inix_dir.join("rust").is_dir());
     }
-}
 
-fn prompt_for_conflict_behavior(inix_dir: &InixDir) -> anyhow::Result<ConflictBehavior> {
-    let mut rl = Editor::<()>::new()?;
+    // - templates aren't written into an existing inix directory that
+    //   has nothing to do with inix, unless forced
+    #[test]
+    fn unrelated_inix_dirs_are_not_adopted() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        let inix_dir = base_dir.path().join("inix");
+        create_dir_all(inix_dir.join("scripts")).unwrap();
+        fs::write(inix_dir.join("scripts").join("build.sh"), "make").unwrap();
+
+        let cli = |force| Cli {
+            templates: vec!["rust".into()],
+            directory: Some(base_dir.path().into()),
+            on_conflict: Some(ConflictBehavior::MergeReplace),
+            force,
+            ..Default::default()
+        };
 
-    #[derive(Debug, Clone, Copy)]
-    struct PromptOption {
-        description: &'static str,
-        short_description: &'static str,
-        conflict_behavior: ConflictBehavior,
-    }
+        let error = run(cli(false), &dirs).expect_err("The unrelated directory should be refused.");
+        assert!(error.to_string().contains("--force"), "{error}");
+        assert!(!inix_dir.join("rust").exists());
+        assert!(!base_dir.path().join("shell.nix").exists());
 
-    impl Display for PromptOption {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(f, r#"{} ({})"#, self.description, self.short_description)
-        }
+        run(cli(true), &dirs).unwrap();
+        assert!(inix_dir.join("rust").is_dir());
+        assert!(inix_dir.join("scripts").join("build.sh").exists());
     }
 
-    #[derive(Debug, Clone)]
-    struct Prompt {
-        text: String,
-        options: HashMap<char, PromptOption>,
-    }
+    // - merge-keep: backs up existing top-level files before writing
+    //   new ones, and creates the ones that are missing
+    #[test]
+    fn merge_keep_preserves_base_files() {
+        proptest!(|(
+            nix: bool,
+            envrc: bool,
+            existing_templates in prop::collection::hash_set("node|rust", 0..2),
+            new_templates in prop::collection::hash_set("node|rust", 0..2))|
+                  go(nix, envrc, existing_templates, new_templates)
+        );
 
-    impl Prompt {
-        fn list_options(&self) -> String {
-            self.options
-                .iter()
-                .sorted_by_key(|(key, _)| *key)
-                .map(|(key, prompt_option)| format!("- {}: {}", key, prompt_option.to_string()))
-                .join("\n")
-        }
+        fn go(
+            nix: bool,
+            envrc: bool,
+            existing_templates: HashSet<String>,
+            new_templates: HashSet<String>,
+        ) {
+            let base_dir = tempdir().unwrap();
+            let existing_content = "# this file was here first\n";
+            let base_files = [("shell.nix", nix), (".envrc", envrc)];
+
+            test_inix_with_setup(
+                Cli {
+                    templates: new_templates.into_iter().collect(),
+                    directory: Some(base_dir.path().into()),
+                    on_conflict: Some(ConflictBehavior::MergeKeep),
+                    ..Default::default()
+                },
+                |paths| {
+                    for (file, exists) in base_files {
+                        if exists {
+                            fs::write(paths.base_dir.join(file), existing_content).unwrap();
+                        }
+                    }
 
-        fn list_option_keys(&self) -> String {
-            combine_strings(self.options.keys().sorted())
+                    for dir in existing_templates.iter() {
+                        create_dir_all(paths.inix_dir.join(dir)).unwrap();
+                    }
+                },
+                |paths, _| {
+                    for (file, existed) in base_files {
+                        let content = fs::read_to_string(paths.base_dir.join(file))
+                            .unwrap_or_else(|_| panic!("{file} was not created."));
+
+                        assert_ne!(content, existing_content);
+                        if existed {
+                            let backup = paths.base_dir.join(format!("{file}.1"));
+                            assert_eq!(
+                                fs::read_to_string(&backup)
+                                    .unwrap_or_else(|_| panic!("{file} was not backed up.")),
+                                existing_content,
+                            );
+                        }
+                    }
+                },
+            )
         }
     }
 
-    impl Display for Prompt {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            writedoc!(
-                f,
-                r#"{}
+    // - a first run over an existing .envrc leaves it alone, but says
+    //   so
+    #[test]
+    fn first_runs_report_existing_base_files() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        let envrc = base_dir.path().join(".envrc");
+        fs::write(&envrc, "dotenv\n").unwrap();
 
-                How would you like to proceed?
-                {}
+        let cli = Cli {
+            templates: vec!["rust".into()],
+            directory: Some(base_dir.path().into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            skipped_base_files(base_dir.path(), &cli, ConflictBehavior::Cancel, false),
+            vec![envrc.clone()]
+        );
+        assert!(
+            skipped_base_files(base_dir.path(), &cli, ConflictBehavior::MergeReplace, true)
+                .is_empty()
+        );
 
-                Please enter exactly one option (one of {} [case-insensitive])."#,
-                self.text,
-                self.list_options(),
-                self.list_option_keys()
-            )
-        }
+        run(cli, &dirs).unwrap();
+        assert_eq!(fs::read_to_string(&envrc).unwrap(), "dotenv\n");
+        assert!(base_dir.path().join("shell.nix").is_file());
+        assert!(base_dir.path().join("inix/rust").is_dir());
     }
 
-    let conflicting_templates = match &inix_dir.state {
-        InixDirState::DoesNotExist => return Ok(ConflictBehavior::Cancel),
-        InixDirState::AlreadyExists {
-            template_collisions,
-        } => template_collisions,
-    };
+    // - templates are looked for in the --template-dir directories
+    //   (in order) before the config directory
+    #[test]
+    fn template_dirs_come_before_the_config_dir() {
+        let (config_dir, dirs) = isolated_dirs();
+        let shared_dir = tempdir().unwrap();
+        let other_dir = tempdir().unwrap();
+        let write_template = |dir: &Path, content: &str| {
+            let template_dir = dir.join("shared");
+            create_dir_all(&template_dir).unwrap();
+            fs::write(template_dir.join("shell.nix"), content).unwrap();
+        };
+        write_template(&config_dir.path().join("inix"), "# from the config dir");
+        write_template(shared_dir.path(), "# from the shared dir");
+        write_template(other_dir.path(), "# from the other dir");
 
-    let prompt = match conflicting_templates {
-        TemplateCollisions::None => Prompt {
-            options: hash_map! {
-                'A' => PromptOption {description:"Merge the two inix directories, adding your new templates to the existing directory?",short_description:"merge", conflict_behavior: ConflictBehavior::MergeKeep },
-                'B' => PromptOption{description:"Overwrite the whole directory, removing everything that's in it and replacing it with the new templates?",short_description:"overwrite", conflict_behavior: ConflictBehavior::Overwrite },
-                'C' => PromptOption {description:"Cancel the operation",short_description:"cancel", conflict_behavior: ConflictBehavior::Cancel }
-            },
-            text: inix_dir.conflict_description(),
-        },
-        TemplateCollisions::All(_) => Prompt {
-            text: inix_dir.conflict_description(),
-            options: hash_map! {
-                'A' =>
-                    PromptOption {description:"Overwrite the entire inix directory, removing anything that exists there already.",short_description:"overwrite", conflict_behavior: ConflictBehavior::Overwrite },
-                'B' => PromptOption{description:"Add your templates to the inix directory, overwriting any templates that are there already, but leaving other templates untouched.",short_description:"merge-replace", conflict_behavior: ConflictBehavior::MergeReplace },
-                'C' => PromptOption {description:"Cancel the operation",short_description:"cancel", conflict_behavior: ConflictBehavior::Cancel }
-            },
-        },
-        TemplateCollisions::Some(_) => Prompt {
-            text: inix_dir.conflict_description(),
-            options: hash_map! {
-                'A' => PromptOption {description:"Overwrite the entire inix directory, removing anything that exists there already.",short_description:"overwrite", conflict_behavior: ConflictBehavior::Overwrite },
-                'B' => PromptOption{description:"Add your templates to the inix directory, overwriting any templates that are there already, but leaving other templates untouched.",short_description:"merge-replace", conflict_behavior: ConflictBehavior::MergeReplace },
-                'C' => PromptOption{description:"Add your templates to the inix directory, but leaving any templates that exist already.",short_description:"merge-keep", conflict_behavior: ConflictBehavior::MergeKeep },
-                'D' => PromptOption {description:"Cancel the operation",short_description:"cancel", conflict_behavior: ConflictBehavior::Cancel }
+        let base_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["shared".into()],
+                template_dirs: vec![
+                    shared_dir.path().into(),
+                    other_dir.path().into(),
+                    PathBuf::from("/this/does/not/exist"),
+                ],
+                directory: Some(base_dir.path().into()),
+                ..Default::default()
             },
-        },
-    };
+            &dirs,
+        )
+        .unwrap();
 
-    println!();
-    println!("{}", prompt);
-    loop {
-        println!();
-        println!(r#"Tip: You can enter "?" to display the options again."#);
-        let readline = rl.readline(">> ");
-        match readline {
-            Ok(line) if line.trim() == "?" => {
-                println!("{}", prompt);
-            }
-            Ok(line) => {
-                match prompt
-                    .options
-                    .iter()
-                    .find(|(c, _)| line.trim().eq_ignore_ascii_case(&*c.to_string()))
-                {
-                    Some((_, option)) => return Ok(option.conflict_behavior),
-                    None => println!("\nSorry, I don't understand what you mean. Please use only the character corresponding to the option you want."),
-                }
-            }
-            Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
-                println!("\nUnderstood. I'll cancel the operation.");
-                bail!("The operation was cancelled.");
-            }
-            Err(err) => {
-                println!("\nErr, I got an error that I don't understand: {:?}", err);
-                println!("\nPlease try again or quit the program (Ctrl+C)");
-            }
+        assert_eq!(
+            fs::read_to_string(base_dir.path().join("inix/shared/shell.nix")).unwrap(),
+            "# from the shared dir"
+        );
+
+        let error = try_get_templates(
+            &["missing".into()],
+            Default::default(),
+            &Dirs {
+                templates: vec![shared_dir.path().into()],
+                ..dirs
+            },
+        )
+        .expect_err("A missing template should be an error.");
+        for dir in [shared_dir.path(), &config_dir.path().join("inix")] {
+            assert!(
+                error.to_string().contains(&dir.display().to_string()),
+                "The error doesn't mention {}: {error}",
+                dir.display()
+            );
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
 
-    use std::{collections::HashSet, ops::Deref, path::Path, time::SystemTime};
+    // - a single template can be asked for by an unambiguous prefix
+    //   of its name
+    #[test]
+    fn unique_prefixes_are_completed() {
+        let (config_dir, dirs) = isolated_dirs();
+        for name in ["haskell", "python-web", "python-data"] {
+            let template_dir = config_dir.path().join("inix").join(name);
+            create_dir_all(&template_dir).unwrap();
+            fs::write(template_dir.join("shell.nix"), "{ }").unwrap();
+        }
+        // not a template, so it doesn't make "has" ambiguous
+        create_dir_all(config_dir.path().join("inix/hash")).unwrap();
 
-    use proptest::prelude::*;
-    use tempfile::tempdir;
+        let base_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["has".into()],
+                directory: Some(base_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        assert!(base_dir.path().join("inix/haskell/shell.nix").exists());
 
-    use super::*;
+        let precedence = TemplateDirPrecedence::default();
+        assert_eq!(
+            complete_template_name("ru", precedence, &dirs).unwrap(),
+            Some("rust".to_string())
+        );
+        assert_eq!(
+            complete_template_name("rust", precedence, &dirs).unwrap(),
+            None
+        );
+        assert_eq!(
+            complete_template_name("go", precedence, &dirs).unwrap(),
+            None
+        );
+        // the base template isn't one that you'd ask for
+        assert_eq!(
+            complete_template_name("ba", precedence, &dirs).unwrap(),
+            None
+        );
+    }
 
+    // - an ambiguous prefix is an error that lists the candidates
     #[test]
-    /// Verify that the CLI is configured correctly.
-    fn verify_cli() {
-        use clap::CommandFactory;
-        Cli::command().debug_assert()
+    fn ambiguous_prefixes_are_errors() {
+        let (config_dir, dirs) = isolated_dirs();
+        for name in ["python-web", "python-data"] {
+            let template_dir = config_dir.path().join("inix").join(name);
+            create_dir_all(&template_dir).unwrap();
+            fs::write(template_dir.join("shell.nix"), "{ }").unwrap();
+        }
+
+        let base_dir = tempdir().unwrap();
+        let error = run(
+            Cli {
+                templates: vec!["python".into()],
+                directory: Some(base_dir.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .expect_err("An ambiguous prefix should fail.");
+
+        let message = error.to_string();
+        assert!(
+            message.contains("python-web") && message.contains("python-data"),
+            "The error doesn't list the candidates: {message}"
+        );
+        assert!(!base_dir.path().join("inix").exists());
     }
 
-    struct InixPaths<'a> {
-        base_dir: &'a Path,
-        inix_dir: &'a Path,
-        shell_nix: &'a Path,
-        envrc: &'a Path,
+    // - completion scripts can be generated without any templates
+    #[test]
+    fn it_generates_completions() {
+        let cli = Cli::try_parse_from(["inix", "--completions", "bash"]).unwrap();
+        assert_eq!(cli.completions, Some(clap_complete::Shell::Bash));
+
+        let (_config_dir, dirs) = isolated_dirs();
+        let mut script = vec![];
+        run_to(cli, &dirs, &mut script).unwrap();
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("inix"), "The script doesn't mention inix.");
+
+        assert!(Cli::try_parse_from(["inix", "--completions", "cmd.exe"]).is_err());
     }
 
-    fn test_inix_with_setup<T, SetupOutput>(
-        args: Cli,
-        setup: impl FnOnce(&InixPaths) -> SetupOutput,
-        execute: impl FnOnce(&InixPaths, SetupOutput) -> T,
-    ) {
-        let target_dir = args
-            .directory
-            .unwrap_or(tempdir().expect("couldn't create a temp dir").path().into());
+    // - the dynamic completion scripts ask inix for the template names,
+    //   and are written to the directory they're asked to be in, or
+    //   to the user's completion directory
+    #[test]
+    fn dynamic_completions_are_installed() {
+        let (config_dir, dirs) = isolated_dirs();
+        create_dir_all(config_dir.path().join("inix/mine")).unwrap();
+        fs::write(config_dir.path().join("inix/mine/shell.nix"), "{ }").unwrap();
+        let out_dir = tempdir().unwrap();
+
+        for (shell, file_name) in [("bash", "inix"), ("zsh", "_inix"), ("fish", "inix.fish")] {
+            let completions_dir = out_dir.path().join(shell);
+            let cli = Cli::try_parse_from([
+                "inix",
+                "--completions-dynamic",
+                shell,
+                "--completions-dir",
+                completions_dir.to_str().unwrap(),
+            ])
+            .unwrap();
+            let mut out = vec![];
+            run_to(cli, &dirs, &mut out).unwrap();
+
+            let path = completions_dir.join(file_name);
+            let script = fs::read_to_string(&path).unwrap();
+            assert!(script.contains("inix --list-template-names"), "{script}");
+            assert!(String::from_utf8(out)
+                .unwrap()
+                .contains(&path.display().to_string()));
+        }
+        let zsh = fs::read_to_string(out_dir.path().join("zsh/_inix")).unwrap();
+        assert!(zsh.starts_with("#compdef inix\n"), "{zsh}");
+        assert!(zsh.contains(":_inix_template_names'"), "{zsh}");
+
+        // without a directory, they go where the shell looks for them
+        let cli = Cli::try_parse_from(["inix", "--completions-dynamic", "fish"]).unwrap();
+        run_to(cli, &dirs, &mut vec![]).unwrap();
+        assert!(config_dir
+            .path()
+            .join("home/.config/fish/completions/inix.fish")
+            .is_file());
+
+        assert!(install_dynamic_completions(
+            clap_complete::Shell::PowerShell,
+            Some(out_dir.path()),
+            None
+        )
+        .is_err());
+        assert!(Cli::try_parse_from(["inix", "--completions-dir", "x"]).is_err());
 
-        let paths = InixPaths {
-            base_dir: &target_dir,
-            inix_dir: &target_dir.join("inix"),
-            shell_nix: &target_dir.join("shell.nix"),
-            envrc: &target_dir.join("shell.nix"),
+        let mut out = vec![];
+        run_to(
+            Cli {
+                list_template_names: true,
+                ..Default::default()
+            },
+            &dirs,
+            &mut out,
+        )
+        .unwrap();
+        let names = String::from_utf8(out).unwrap();
+        assert!(names.lines().any(|name| name == "mine"), "{names}");
+        assert!(names.lines().any(|name| name == "rust"), "{names}");
+        assert!(!names.lines().any(|name| name == "base"), "{names}");
+    }
+
+    // - a project's shell.nix and .envrc can be saved as a template
+    //   and used again
+    #[test]
+    fn projects_can_be_saved_as_templates() {
+        let (config_dir, dirs) = isolated_dirs();
+        let project_dir = tempdir().unwrap();
+        let shell_nix = "{ pkgs ? import <nixpkgs> { } }: pkgs.mkShell { }";
+        fs::write(project_dir.path().join("shell.nix"), shell_nix).unwrap();
+        fs::write(project_dir.path().join(".envrc"), "export FOO=1").unwrap();
+
+        let save = |on_conflict| {
+            run(
+                Cli {
+                    save_as: Some("saved".into()),
+                    directory: Some(project_dir.path().into()),
+                    on_conflict,
+                    ..Default::default()
+                },
+                &dirs,
+            )
         };
 
-        let args_p = Cli {
-            directory: Some(target_dir.clone()),
-            ..args
+        save(None).unwrap();
+        assert!(config_dir.path().join("inix/saved/shell.nix").is_file());
+
+        let new_project = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["saved".into()],
+                directory: Some(new_project.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        let read = |file: &str| fs::read_to_string(new_project.path().join(file)).unwrap();
+        assert_eq!(read("inix/saved/shell.nix"), shell_nix);
+        assert_eq!(read("inix/saved/.envrc"), "export FOO=1");
+
+        // an existing template is only changed if asked to
+        fs::write(project_dir.path().join(".envrc"), "export FOO=2").unwrap();
+        save(None).expect_err("Saving over an existing template should fail.");
+        save(Some(ConflictBehavior::MergeKeep)).unwrap();
+        let saved_envrc =
+            || fs::read_to_string(config_dir.path().join("inix/saved/.envrc")).unwrap();
+        assert_eq!(saved_envrc(), "export FOO=1");
+        save(Some(ConflictBehavior::Overwrite)).unwrap();
+        assert_eq!(saved_envrc(), "export FOO=2");
+
+        let empty_project = tempdir().unwrap();
+        let error = run(
+            Cli {
+                save_as: Some("empty".into()),
+                directory: Some(empty_project.path().into()),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .expect_err("There's nothing to save.");
+        assert!(error.to_string().contains("nothing to save"), "{error}");
+        assert!(!config_dir.path().join("inix/empty").exists());
+    }
+
+    // - a --template-dir with a template's files directly in it is a
+    //   template named after the directory, used by default, but
+    //   other template directories never are
+    #[test]
+    fn template_dirs_can_be_templates() {
+        let (config_dir, dirs) = isolated_dirs();
+        let parent = tempdir().unwrap();
+        let template_dir = parent.path().join("my-rust-env");
+        create_dir_all(&template_dir).unwrap();
+        fs::write(template_dir.join("shell.nix"), "# directly in the dir").unwrap();
+
+        let instantiate = |templates: Vec<String>| {
+            let project_dir = tempdir().unwrap();
+            run(
+                Cli {
+                    templates,
+                    template_dirs: vec![template_dir.join(".")],
+                    directory: Some(project_dir.path().into()),
+                    ..Default::default()
+                },
+                &dirs,
+            )
+            .unwrap();
+            fs::read_to_string(project_dir.path().join("inix/my-rust-env/shell.nix")).unwrap()
         };
 
-        let setup_output = setup(&paths);
+        assert_eq!(instantiate(vec![]), "# directly in the dir");
+        assert_eq!(
+            instantiate(vec!["my-rust-env".into()]),
+            "# directly in the dir"
+        );
 
-        match run(args_p) {
-            Err(e) => assert!(
-                false,
-                r#"Running the inix program failed with an error: {e:?}"#
-            ),
-            Ok(_) => {
-                execute(&paths, setup_output);
-            }
+        let shared_dir = parent.path().join("shared");
+        create_dir_all(&shared_dir).unwrap();
+        fs::write(shared_dir.join("shell.nix"), "# shared").unwrap();
+        create_dir_all(config_dir.path().join("inix")).unwrap();
+        fs::write(config_dir.path().join("inix/shell.nix"), "# config").unwrap();
+        let dirs = Dirs {
+            templates: vec![shared_dir],
+            ..dirs
         };
+        let names = template_listing(Default::default(), &dirs).unwrap();
+        assert!(
+            !names
+                .iter()
+                .any(|line| line.starts_with("shared ") || line.starts_with("inix ")),
+            "{names:?}"
+        );
+        for name in ["shared", "inix"] {
+            let project_dir = tempdir().unwrap();
+            run(
+                Cli {
+                    templates: vec![name.into()],
+                    directory: Some(project_dir.path().into()),
+                    ..Default::default()
+                },
+                &dirs,
+            )
+            .expect_err("Only --template-dir can be a template itself.");
+        }
     }
 
-    fn test_inix<T>(args: Cli, execute: impl FnOnce(&InixPaths) -> T) {
-        test_inix_with_setup(args, |_| {}, |paths, _| execute(paths))
+    // - a template with a broken inix.toml is reported, but doesn't
+    //   get in the way of listing, completing, or merging others
+    #[test]
+    fn broken_templates_dont_break_the_others() {
+        let (config_dir, dirs) = isolated_dirs();
+        for (name, metadata) in [("fine", "description = \"Fine\""), ("broken", "nope = [")] {
+            let dir = config_dir.path().join("inix").join(name);
+            create_dir_all(&dir).unwrap();
+            fs::write(dir.join("shell.nix"), format!("# {name}")).unwrap();
+            fs::write(dir.join("inix.toml"), metadata).unwrap();
+        }
+
+        let listing = template_listing(Default::default(), &dirs).unwrap();
+        assert!(
+            listing
+                .iter()
+                .any(|line| line.starts_with("broken (broken: ")),
+            "{listing:#?}"
+        );
+        assert!(
+            listing.iter().any(|line| line.starts_with("fine (custom")),
+            "{listing:#?}"
+        );
+        assert_eq!(
+            complete_template_name("fi", Default::default(), &dirs).unwrap(),
+            Some("fine".into())
+        );
+
+        // one that's set up already is still sourced
+        let base_dir = tempdir().unwrap();
+        let inix_dir = base_dir.path().join("inix");
+        copy_dir_all(
+            &config_dir.path().join("inix/broken"),
+            &inix_dir.join("broken"),
+        )
+        .unwrap();
+        run(
+            Cli {
+                templates: vec!["fine".into()],
+                directory: Some(base_dir.path().into()),
+                on_conflict: Some(ConflictBehavior::MergeKeep),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        let shell_nix = fs::read_to_string(base_dir.path().join("shell.nix")).unwrap();
+        for name in ["broken", "fine"] {
+            assert!(
+                shell_nix.contains(&format!("./inix/{name}/shell.nix")),
+                "{shell_nix}"
+            );
+        }
+        let (_, warnings) = final_templates(&inix_dir, &[], ConflictBehavior::MergeKeep).unwrap();
+        assert!(
+            warnings[0].contains(r#"without its inix.toml"#),
+            "{warnings:?}"
+        );
     }
 
-    fn power_set<'a, T>(a: &[T]) -> impl Iterator<Item = &[T]> {
-        std::iter::once([].as_ref()).chain(
-            (0..=a.len())
-                .tuple_combinations()
-                .map(move |(start, end)| &a[start..end]),
+    // - a template's inix.toml describes it and can limit the files
+    //   that are copied, but isn't a template by itself
+    #[test]
+    fn templates_can_have_metadata() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = |name: &str| {
+            let dir = config_dir.path().join("inix").join(name);
+            create_dir_all(&dir).unwrap();
+            dir
+        };
+
+        let described = template_dir("described");
+        fs::write(described.join("shell.nix"), "{ }").unwrap();
+        fs::write(described.join("overlay.nix"), "self: super: { }").unwrap();
+        fs::write(described.join("notes.md"), "not part of the template").unwrap();
+        fs::write(
+            described.join("inix.toml"),
+            "description = \"A shell with an overlay\"\nfiles = [\"overlay.nix\"]\n",
+        )
+        .unwrap();
+        fs::write(template_dir("plain").join("shell.nix"), "{ }").unwrap();
+
+        let listing = template_listing(Default::default(), &dirs).unwrap();
+        assert!(
+            listing
+                .iter()
+                .any(|line| line.starts_with("described (custom")
+                    && line.ends_with(": A shell with an overlay")),
+            "The description isn't in the listing: {listing:#?}"
+        );
+        assert!(listing
+            .iter()
+            .any(|line| line.starts_with("plain (custom") && !line.contains(": ")));
+
+        let template = try_get_templates(&["described".into()], Default::default(), &dirs)
+            .unwrap()
+            .remove(0);
+        let files: Vec<_> = template.files().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(files, vec!["shell.nix", "overlay.nix"]);
+
+        fs::write(
+            template_dir("metadata-only").join("inix.toml"),
+            "description = \"Nothing here\"",
+        )
+        .unwrap();
+        let error = try_get_templates(&["metadata-only".into()], Default::default(), &dirs)
+            .expect_err("A template with only an inix.toml should fail.");
+        assert!(format!("{error:#}").contains("inix.toml"), "{error:#}");
+
+        // templates can say which variables they need and which version
+        // of inix they need, and both are shown along with them
+        remove_dir_all(template_dir("metadata-only")).unwrap();
+        let node = template_dir("node");
+        fs::write(node.join("shell.nix"), "# node {{node_version}}").unwrap();
+        fs::write(
+            node.join("inix.toml"),
+            "description = \"Node.js\"\nvariables = [\"node_version\"]\nmin_inix_version = \"0.1\"\n",
+        )
+        .unwrap();
+        let listing = template_listing(Default::default(), &dirs).unwrap();
+        assert!(
+            listing.iter().any(|line| line
+                .contains("; shell.nix; variables: node_version; needs inix 0.1): Node.js")),
+            "The variables aren't in the listing: {listing:#?}"
+        );
+        let template = try_get_templates(&["node".into()], Default::default(), &dirs)
+            .unwrap()
+            .remove(0);
+        assert!(
+            show_template(&template)
+                .contains("Node.js\nVariables: node_version\nNeeds inix 0.1 or later\n"),
+            "{}",
+            show_template(&template)
+        );
+
+        let project_dir = tempdir().unwrap();
+        // (without --non-interactive, it'd wait for an answer when the
+        // tests are run from a terminal)
+        let set_up = |variables| {
+            run(
+                Cli {
+                    templates: vec!["node".into()],
+                    variables,
+                    directory: Some(project_dir.path().into()),
+                    non_interactive: true,
+                    ..Default::default()
+                },
+                &dirs,
+            )
+        };
+        let error = set_up(vec![]).expect_err("node_version isn't set.");
+        assert!(
+            error
+                .to_string()
+                .contains(r#"- node_version (for the "node" template)"#),
+            "{error}"
+        );
+        assert!(
+            error
+                .to_string()
+                .contains("You have asked me not to prompt you (`--non-interactive`)"),
+            "{error}"
+        );
+        set_up(vec![("node_version".into(), "18".into())]).unwrap();
+        assert_eq!(
+            fs::read_to_string(project_dir.path().join("inix/node/shell.nix")).unwrap(),
+            "# node 18"
+        );
+
+        fs::write(template_dir("future").join("shell.nix"), "{ }").unwrap();
+        fs::write(
+            template_dir("future").join("inix.toml"),
+            "description = \"From the future\"\nmin_inix_version = \"999.0\"\n",
+        )
+        .unwrap();
+        let error = run(
+            Cli {
+                templates: vec!["future".into()],
+                directory: Some(project_dir.path().join("future")),
+                ..Default::default()
+            },
+            &dirs,
         )
+        .expect_err("The template needs a newer inix.");
+        assert!(
+            format!("{error:#}").contains("needs inix 999.0"),
+            "{error:#}"
+        );
+        assert!(!project_dir.path().join("future").exists());
+        // it can still be looked at, though
+        let listing = template_listing(Default::default(), &dirs).unwrap();
+        assert!(
+            listing.contains(&format!(
+                "future (custom, from {}; shell.nix; needs inix 999.0): From the future",
+                template_dir("future").display()
+            )),
+            "{listing:?}"
+        );
+        let future = try_get_templates(&["future".into()], Default::default(), &dirs).unwrap();
+        assert!(show_template(&future[0]).contains("Needs inix 999.0 or later\n"));
+        assert!(version_at_least("1.2", "1.2.0"));
+        assert!(version_at_least("1.10", "1.9.5"));
+        assert!(!version_at_least("0.1.0", "0.2"));
     }
 
-    // Test cases
-    //
-    // - it completes successfully without templates
+    // - the shell hooks from the templates' inix.toml all end up in
+    //   the top-level shell's shellHook, in order
     #[test]
-    fn it_works_without_provided_templates() {
-        test_inix(
+    fn templates_can_have_shell_hooks() {
+        let (config_dir, dirs) = isolated_dirs();
+        for (name, hook) in [
+            ("greeter", "echo 'Hello!'"),
+            ("exporter", "export APP_ENV=dev\nexport APP_PORT=8080\n"),
+        ] {
+            let dir = config_dir.path().join("inix").join(name);
+            create_dir_all(&dir).unwrap();
+            fs::write(
+                dir.join("shell.nix"),
+                "{ pkgs ? import <nixpkgs> { } }: { }",
+            )
+            .unwrap();
+            fs::write(
+                dir.join("inix.toml"),
+                format!("description = \"{name}\"\nshell_hook = {hook:?}\n"),
+            )
+            .unwrap();
+        }
+
+        let base_dir = tempdir().unwrap();
+        run(
             Cli {
-                templates: vec![],
+                templates: vec!["greeter".into(), "exporter".into(), "rust".into()],
+                directory: Some(base_dir.path().into()),
+                emit_flake: true,
                 ..Default::default()
             },
-            |paths| {
-                for expected_file in [paths.shell_nix, paths.envrc] {
-                    assert!(
-                        expected_file.is_file(),
-                        r#"The file "/{}" does not exist or is not a file."#,
-                        expected_file.display()
-                    );
-                }
-                assert_eq!(
-                    false,
-                    paths.inix_dir.exists(),
-                    r#"The /inix directory was created when it shouldn't have anything in it."#
-                );
+            &dirs,
+        )
+        .unwrap();
+
+        let shell_nix = fs::read_to_string(base_dir.path().join("shell.nix")).unwrap();
+        assert!(
+            shell_nix.contains(indoc::indoc! {"
+                  shellHook = ''
+                    # from the exporter template
+                    export APP_ENV=dev
+                    export APP_PORT=8080
+                    # from the greeter template
+                    echo 'Hello!'
+                  '';
+                }"}),
+            "{shell_nix}"
+        );
+        let flake_nix = fs::read_to_string(base_dir.path().join("flake.nix")).unwrap();
+        assert!(
+            flake_nix
+                .contains("            # from the greeter template\n            echo 'Hello!'\n"),
+            "{flake_nix}"
+        );
+
+        // templates without hooks don't get an empty one
+        let base_dir = tempdir().unwrap();
+        run(
+            Cli {
+                templates: vec!["rust".into()],
+                directory: Some(base_dir.path().into()),
+                ..Default::default()
             },
+            &dirs,
         )
+        .unwrap();
+        let shell_nix = fs::read_to_string(base_dir.path().join("shell.nix")).unwrap();
+        assert!(!shell_nix.contains("shellHook"), "{shell_nix}");
     }
 
-    // - creates shell.nix, .envrc, and inix/* files
-    // - creates any directories necessary if they don't exist
+    // - templates can say which other templates they can't be used
+    //   with, and asking for both is an error that names them
     #[test]
-    fn it_creates_files() {
-        let base_dir = tempdir().unwrap();
+    fn templates_can_conflict_with_each_other() {
+        let (config_dir, dirs) = isolated_dirs();
+        for (name, conflicts_with) in [
+            ("node-npm", r#"["node-yarn"]"#),
+            ("node-yarn", r#"["node-npm"]"#),
+            ("node-pnpm", r#"["node-npm"]"#),
+        ] {
+            let dir = config_dir.path().join("inix").join(name);
+            create_dir_all(&dir).unwrap();
+            fs::write(dir.join("shell.nix"), "{ }").unwrap();
+            fs::write(
+                dir.join("inix.toml"),
+                format!("description = \"{name}\"\nconflicts_with = {conflicts_with}\n"),
+            )
+            .unwrap();
+        }
+        let get = |names: &[&str]| {
+            let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+            try_get_templates(&names, Default::default(), &dirs)
+        };
 
-        let project_dir = base_dir.path().join("my/project");
+        let error =
+            get(&["node-npm", "rust", "node-yarn"]).expect_err("node-npm and node-yarn conflict.");
+        assert_eq!(
+            error.to_string(),
+            indoc::indoc! {r#"
+                These templates can't be used together:
+                - "node-npm" and "node-yarn" ("node-npm" says that it conflicts with "node-yarn")
 
-        for templates in power_set(&["rust", "node"]).filter(|set| set.len() > 0) {
-            let args = Cli {
-                templates: templates.iter().map(|s| s.deref().into()).collect(),
-                directory: Some(project_dir.clone()),
-                ..Default::default()
-            };
+                Please choose one template from each pair."#}
+        );
 
-            test_inix(args, |paths| {
-                for expected_file in [paths.shell_nix, paths.envrc] {
-                    assert!(
-                        expected_file.is_file(),
-                        r#"The file "/{}" does not exist or is not a file."#,
-                        expected_file.display()
-                    );
-                }
+        // it's enough for one of them to say so
+        let error =
+            get(&["node-npm", "node-pnpm"]).expect_err("node-pnpm conflicts with node-npm.");
+        assert!(
+            error
+                .to_string()
+                .contains(r#"- "node-pnpm" and "node-npm" ("node-pnpm" says"#),
+            "{error}"
+        );
 
-                for expected_file in ["node/shell.nix", "node/.envrc"] {
-                    assert!(
-                        paths.inix_dir.join(expected_file).is_file(),
-                        r#"The file "/{expected_file}" does not exist or is not a file."#
-                    );
-                }
-            })
-        }
+        assert_eq!(get(&["node-yarn", "node-pnpm", "rust"]).unwrap().len(), 3);
     }
 
-    //
-    // - the resulting .envrc and shell.nix files actually work
+    // - `inix init-config` creates the template directory with a
+    //   sample template, but won't touch an existing one without
+    //   --force
     #[test]
-    fn the_envrc_file_works() {
-        // todo: use proptest to generate this with and without
-        // subdirectories that it needs to source from?
-        todo!()
+    fn init_config_scaffolds_the_template_dir() {
+        let (config_dir, dirs) = isolated_dirs();
+        let template_dir = config_dir.path().join("inix");
+
+        let init = |args: &[&str]| {
+            // the options for inix itself come before the command
+            let args = ["inix"].iter().chain(args).chain(&["init-config"]);
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert_eq!(cli.action, Some(Action::InitConfig));
+            let mut out = vec![];
+            run_to(cli, &dirs, &mut out).map(|_| String::from_utf8(out).unwrap())
+        };
+
+        init(&["--dry-run"]).unwrap();
+        assert!(!template_dir.exists(), "A dry run created files.");
+
+        let out = init(&[]).unwrap();
+        for (file_name, contents) in SAMPLE_CONFIG_FILES {
+            let path = template_dir.join(file_name);
+            assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+            assert!(out.contains(&path.display().to_string()), "{out}");
+        }
+        assert!(SAMPLE_CONFIG_FILES[0].1.contains("`inix init-config`"));
+        assert!(
+            find_template("example", &[&template_dir], &[], Default::default(), None)
+                .unwrap()
+                .is_some_and(|template| template.template_type == TemplateType::Custom)
+        );
+
+        fs::write(template_dir.join("example/shell.nix"), "# mine").unwrap();
+        init(&[]).expect_err("An existing template directory should not be touched.");
+        assert_eq!(
+            fs::read_to_string(template_dir.join("example/shell.nix")).unwrap(),
+            "# mine"
+        );
+
+        init(&["--force"]).unwrap();
+        assert_eq!(
+            fs::read_to_string(template_dir.join("example/shell.nix")).unwrap(),
+            SAMPLE_CONFIG_FILES[0].1
+        );
     }
 
+    // - merge-keep: backups go in the generation after the newest
+    //   one, even if there are gaps
     #[test]
-    fn the_nix_file_works() {
-        // todo: use proptest to generate this with and without
-        // subdirectories that it needs to source from?
-        todo!()
+    fn merge_keep_backs_up_to_the_next_generation() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        for (file, content) in [
+            ("shell.nix", "# current"),
+            ("shell.nix.1", "# first"),
+            ("shell.nix.7", "# seventh"),
+        ] {
+            fs::write(base_dir.path().join(file), content).unwrap();
+        }
+
+        run(
+            Cli {
+                templates: vec!["rust".into()],
+                directory: Some(base_dir.path().into()),
+                on_conflict: Some(ConflictBehavior::MergeKeep),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+
+        let read = |file: &str| fs::read_to_string(base_dir.path().join(file)).unwrap();
+        assert_eq!(read("shell.nix.8"), "# current");
+        assert_eq!(read("shell.nix.1"), "# first");
+        assert_eq!(read("shell.nix.7"), "# seventh");
+        assert_ne!(read("shell.nix"), "# current");
+        assert!(!base_dir.path().join("shell.nix.2").exists());
+
+        // running it again backs up shell.nix again, but not the
+        // .envrc, which only has its managed block updated
+        run(
+            Cli {
+                templates: vec!["node".into()],
+                directory: Some(base_dir.path().into()),
+                on_conflict: Some(ConflictBehavior::MergeKeep),
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .unwrap();
+        let generations = |file: &str| {
+            fs::read_dir(base_dir.path())
+                .unwrap()
+                .filter(|entry| {
+                    let name = entry.as_ref().unwrap().file_name();
+                    let name = name.to_string_lossy();
+                    name.strip_prefix(file)
+                        .and_then(|rest| rest.strip_prefix('.'))
+                        .is_some_and(|generation| generation.parse::<u32>().is_ok())
+                })
+                .count()
+        };
+        assert_eq!(generations("shell.nix"), 4);
+        assert_eq!(generations(".envrc"), 0);
     }
 
-    // - the base .envrc and shell.nix files contain links to all the
-    // templates mentioned
+    // - overwrite: if something can't be written, the existing inix
+    //   directory is left as it was
     #[test]
-    fn all_templates_are_linked() {
-        // in short:
-        // generate a set of templates. Ensure that each one is linked in both .envrc and shell.nix
-        todo!()
+    fn overwrite_is_rolled_back_when_a_write_fails() {
+        let (_config_dir, dirs) = isolated_dirs();
+        let base_dir = tempdir().unwrap();
+        let inix_dir = base_dir.path().join("inix");
+        create_dir_all(inix_dir.join("rust")).unwrap();
+        fs::write(inix_dir.join("rust").join("shell.nix"), "# mine").unwrap();
+        // a directory where shell.nix should go can't be replaced by
+        // a file, not even by root (unlike a read-only file)
+        create_dir_all(base_dir.path().join("shell.nix").join("nested")).unwrap();
+
+        run(
+            Cli {
+                templates: vec!["node".into()],
+                directory: Some(base_dir.path().into()),
+                on_conflict: Some(ConflictBehavior::Overwrite),
+                force: true,
+                ..Default::default()
+            },
+            &dirs,
+        )
+        .expect_err("Writing shell.nix over a directory should fail.");
+
+        assert_eq!(
+            fs::read_to_string(inix_dir.join("rust").join("shell.nix")).unwrap(),
+            "# mine"
+        );
+        assert!(!inix_dir.join("node").exists());
+        assert!(base_dir.path().join("shell.nix").join("nested").is_dir());
+        assert!(!base_dir.path().join(".envrc").exists());
+        let leftovers: Vec<_> = fs::read_dir(base_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name.to_string_lossy().starts_with(".inix-"))
+            .collect();
+        assert!(leftovers.is_empty(), "{leftovers:?}");
     }
 
     // - merge-replace: overwrites conflicting files
@@ -0,0 +1,10 @@
+# This is a sample template, created by `inix init-config`. Use it
+# as a starting point for your own templates.
+{ pkgs ? import <nixpkgs> { } }:
+
+pkgs.mkShell {
+  packages = [
+    # add the packages that your projects need here, e.g.
+    # pkgs.hello
+  ];
+}